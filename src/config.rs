@@ -0,0 +1,361 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/**
+ * Number of previous versions of a configuration file that are kept next to
+ * it as `<file>.1` (newest) up to `<file>.BACKUP_COUNT` (oldest).
+ */
+pub const BACKUP_COUNT: usize = 3;
+
+/**
+ * Version of the configuration schema written by this binary. Files without a
+ * `version` field predate versioning and are treated as version 0.
+ */
+pub const CURRENT_VERSION: u64 = 1;
+
+/**
+ * Migration steps, `MIGRATIONS[n]` upgrades a version `n` document to version
+ * `n + 1`.
+ */
+const MIGRATIONS: [fn(Value) -> Result<Value, String>; CURRENT_VERSION as usize] = [migrate_v0];
+
+#[derive(Debug)]
+pub enum LoadError {
+    /**
+     * The file was written by a newer version of this program.
+     */
+    UnsupportedVersion(u64),
+    Invalid(String),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "configuration version {} is newer than the supported version {}",
+                version, CURRENT_VERSION
+            ),
+            LoadError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/**
+ * Version 0 is the plain serialization of `Player` without a version field.
+ */
+fn migrate_v0(mut value: Value) -> Result<Value, String> {
+    match value.as_object_mut() {
+        Some(obj) => {
+            obj.insert("version".to_string(), json!(1));
+            Ok(value)
+        }
+        None => Err("configuration is not a JSON object".to_string()),
+    }
+}
+
+/**
+ * Upgrades a configuration document step by step to `CURRENT_VERSION`.
+ */
+pub fn migrate(mut value: Value) -> Result<Value, LoadError> {
+    let mut version = match value.get("version") {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .ok_or_else(|| LoadError::Invalid(format!("invalid version {}", v)))?,
+    };
+
+    if version > CURRENT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    while version < CURRENT_VERSION {
+        value = MIGRATIONS[version as usize](value).map_err(LoadError::Invalid)?;
+        version += 1;
+    }
+    Ok(value)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn backup_path(path: &Path, n: usize) -> PathBuf {
+    with_suffix(path, &format!(".{}", n))
+}
+
+fn rotate_backups(path: &Path, count: usize) -> io::Result<()> {
+    if count == 0 || !path.exists() {
+        return Ok(());
+    }
+
+    for n in (1..count).rev() {
+        let from = backup_path(path, n);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, n + 1))?;
+        }
+    }
+    fs::copy(path, backup_path(path, 1))?;
+    Ok(())
+}
+
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/**
+ * Stores `value` as pretty printed JSON at `path`, stamped with the
+ * `CURRENT_VERSION` of radio.json.
+ *
+ * The data is written to a temporary file which is flushed to disk and then
+ * renamed over the original, so a crash leaves either the old or the new file
+ * in place but never a truncated one. The previous file is kept as a backup.
+ */
+pub fn save<T: Serialize>(path: &Path, value: &T, backups: usize) -> io::Result<()> {
+    let mut doc = serde_json::to_value(value)?;
+    if let Some(obj) = doc.as_object_mut() {
+        obj.insert("version".to_string(), json!(CURRENT_VERSION));
+    }
+    write(path, &doc, backups)
+}
+
+/**
+ * Same as `save` for files that do not follow the radio.json schema, they are
+ * stored as they are without a version.
+ */
+pub fn save_unversioned<T: Serialize>(path: &Path, value: &T, backups: usize) -> io::Result<()> {
+    write(path, &serde_json::to_value(value)?, backups)
+}
+
+fn write(path: &Path, doc: &Value, backups: usize) -> io::Result<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    {
+        let mut f = File::create(&tmp_path)?;
+        serde_json::to_writer_pretty(&mut f, doc)?;
+        f.flush()?;
+        f.sync_all()?;
+    }
+
+    rotate_backups(path, backups)?;
+    fs::rename(&tmp_path, path)?;
+    sync_parent(path)
+}
+
+fn invalid<E: ToString>(err: E) -> LoadError {
+    LoadError::Invalid(err.to_string())
+}
+
+type Migrate = fn(Value) -> Result<Value, LoadError>;
+
+fn read<T: DeserializeOwned>(path: &Path, migrate: Migrate) -> Result<T, LoadError> {
+    let txt = fs::read_to_string(path).map_err(invalid)?;
+    let value = serde_json::from_str::<Value>(&txt).map_err(invalid)?;
+    serde_json::from_value::<T>(migrate(value)?).map_err(invalid)
+}
+
+/**
+ * Loads the configuration stored at `path`.
+ *
+ * If the file is missing or cannot be parsed, the backups are tried from
+ * newest to oldest. `Ok(None)` is returned when there is nothing to load at
+ * all, an error when files exist but none of them is valid. A file written by
+ * a newer version of the program is refused outright rather than replaced by
+ * an older backup.
+ */
+pub fn load<T: DeserializeOwned>(path: &Path, backups: usize) -> Result<Option<T>, LoadError> {
+    load_with(path, backups, migrate)
+}
+
+/**
+ * Loads a file stored with `save_unversioned`, falling back to its backups
+ * the same way as `load`.
+ */
+pub fn load_unversioned<T: DeserializeOwned>(
+    path: &Path,
+    backups: usize,
+) -> Result<Option<T>, LoadError> {
+    load_with(path, backups, Ok)
+}
+
+fn load_with<T: DeserializeOwned>(
+    path: &Path,
+    backups: usize,
+    migrate: Migrate,
+) -> Result<Option<T>, LoadError> {
+    let main_error = if path.exists() {
+        match read(path, migrate) {
+            Ok(value) => return Ok(Some(value)),
+            Err(err @ LoadError::UnsupportedVersion(_)) => return Err(err),
+            Err(err) => Some(err.to_string()),
+        }
+    } else {
+        None
+    };
+
+    let mut found_backup = false;
+    for n in 1..=backups {
+        let backup = backup_path(path, n);
+        if !backup.exists() {
+            continue;
+        }
+        found_backup = true;
+        match read(&backup, migrate) {
+            Ok(value) => {
+                eprintln!(
+                    "W: {} is unusable ({}), recovered configuration from {}",
+                    path.display(),
+                    main_error.as_ref().map_or("missing", |err| err.as_str()),
+                    backup.display()
+                );
+                return Ok(Some(value));
+            }
+            Err(err) => eprintln!("W: Ignoring backup {}: {}", backup.display(), err),
+        }
+    }
+
+    match main_error {
+        Some(err) => Err(LoadError::Invalid(format!("{}: {}", path.display(), err))),
+        None if found_backup => Err(LoadError::Invalid(format!(
+            "{} is missing and none of its backups is valid",
+            path.display()
+        ))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use serde::Deserialize;
+
+    use crate::testutil::test_dir;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Cfg {
+        n: usize,
+    }
+
+    #[test]
+    fn test_save_rotates_backups() {
+        let dir = test_dir("rotate");
+        let path = dir.join("radio.json");
+        for n in 0..5 {
+            save(&path, &Cfg { n }, 2).unwrap();
+        }
+
+        assert_eq!(read::<Cfg>(&path, migrate).unwrap(), Cfg { n: 4 });
+        assert_eq!(
+            read::<Cfg>(&backup_path(&path, 1), migrate).unwrap(),
+            Cfg { n: 3 }
+        );
+        assert_eq!(
+            read::<Cfg>(&backup_path(&path, 2), migrate).unwrap(),
+            Cfg { n: 2 }
+        );
+        assert!(!backup_path(&path, 3).exists());
+        assert!(!with_suffix(&path, ".tmp").exists());
+    }
+
+    #[test]
+    fn test_load_recovers_from_backup() {
+        let dir = test_dir("recover");
+        let path = dir.join("radio.json");
+        save(&path, &Cfg { n: 1 }, 2).unwrap();
+        save(&path, &Cfg { n: 2 }, 2).unwrap();
+        fs::write(&path, "{\"n\": ").unwrap();
+
+        assert_eq!(load::<Cfg>(&path, 2).unwrap(), Some(Cfg { n: 1 }));
+    }
+
+    #[test]
+    fn test_load_missing_and_corrupt() {
+        let dir = test_dir("missing");
+        let path = dir.join("radio.json");
+        assert_eq!(load::<Cfg>(&path, 2).unwrap(), None);
+
+        fs::write(&path, "garbage").unwrap();
+        assert!(load::<Cfg>(&path, 2).is_err());
+    }
+
+    const V0: &str = r#"{
+        "cfg": {
+            "streams": [
+                { "name": "Arrow", "url": "http://stream.gal.io/arrow", "id": 1 },
+                { "name": "Jazz", "url": "https://example.org/jazz", "id": 4 }
+            ],
+            "current": 4
+        }
+    }"#;
+
+    #[test]
+    fn test_migrate_v0() {
+        let value = migrate(serde_json::from_str(V0).unwrap()).unwrap();
+        assert_eq!(value["version"], json!(CURRENT_VERSION));
+
+        let player = serde_json::from_value::<crate::player::Player>(value).unwrap();
+        assert_eq!(player.cfg.streams.len(), 2);
+        assert_eq!(player.cfg.streams[1].id, 4);
+        assert_eq!(player.cfg.current, 4);
+    }
+
+    #[test]
+    fn test_migrate_current_version() {
+        let doc = json!({ "version": CURRENT_VERSION, "cfg": { "streams": [], "current": 0 } });
+        assert_eq!(migrate(doc.clone()).unwrap(), doc);
+    }
+
+    #[test]
+    fn test_refuse_newer_version() {
+        let doc = json!({ "version": CURRENT_VERSION + 1, "cfg": {} });
+        match migrate(doc) {
+            Err(LoadError::UnsupportedVersion(v)) => assert_eq!(v, CURRENT_VERSION + 1),
+            _ => panic!("Newer configuration version was accepted"),
+        }
+
+        let dir = test_dir("newer");
+        let path = dir.join("radio.json");
+        save(&path, &Cfg { n: 1 }, 2).unwrap();
+        save(&path, &Cfg { n: 2 }, 2).unwrap();
+        fs::write(
+            &path,
+            format!("{{\"version\": {}, \"n\": 3}}", CURRENT_VERSION + 1),
+        )
+        .unwrap();
+        assert!(match load::<Cfg>(&path, 2) {
+            Err(LoadError::UnsupportedVersion(_)) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_unversioned() {
+        let dir = test_dir("unversioned");
+        let path = dir.join("queue.json");
+        save_unversioned(&path, &json!({ "version": CURRENT_VERSION + 1, "n": 1 }), 0).unwrap();
+        let value = load_unversioned::<Value>(&path, 0).unwrap().unwrap();
+        assert_eq!(value["version"], json!(CURRENT_VERSION + 1));
+
+        save_unversioned(&path, &Cfg { n: 2 }, 0).unwrap();
+        let value = load_unversioned::<Value>(&path, 0).unwrap().unwrap();
+        assert_eq!(value, json!({ "n": 2 }));
+        assert!(!backup_path(&path, 1).exists());
+    }
+
+    #[test]
+    fn test_reject_malformed_version() {
+        assert!(migrate(json!({ "version": "one" })).is_err());
+        assert!(migrate(json!([1, 2, 3])).is_err());
+    }
+}
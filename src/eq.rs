@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+/**
+ * Upper limit of bands in a preset, every band is a filter in the chain.
+ */
+pub const MAX_BANDS: usize = 31;
+
+/**
+ * A peaking filter, `gain` is in dB and `q` sets the width of the band.
+ */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Band {
+    pub frequency: f64,
+    pub gain: f64,
+    #[serde(default = "default_q")]
+    pub q: f64,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct PresetFile {
+    presets: BTreeMap<String, Vec<Band>>,
+}
+
+/**
+ * Named equalizer presets, stored in `eq_presets.json` next to the player
+ * configuration.
+ */
+#[derive(Default)]
+pub struct Presets {
+    path: PathBuf,
+    presets: BTreeMap<String, Vec<Band>>,
+}
+
+fn default_q() -> f64 {
+    1.0
+}
+
+pub fn validate(bands: &[Band]) -> Result<(), String> {
+    if bands.len() > MAX_BANDS {
+        return Err(format!("at most {} bands are supported", MAX_BANDS));
+    }
+    for band in bands {
+        if !band.frequency.is_finite() || band.frequency <= 0.0 || band.frequency >= 24000.0 {
+            return Err(format!("invalid frequency {}", band.frequency));
+        }
+        if !band.q.is_finite() || band.q <= 0.0 {
+            return Err(format!("invalid q {}", band.q));
+        }
+        if !band.gain.is_finite() {
+            return Err(format!("invalid gain {}", band.gain));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * The bands as an MPV `af` filter, one ffmpeg `equalizer` per band.
+ */
+pub fn filter(bands: &[Band]) -> Option<String> {
+    if bands.is_empty() {
+        return None;
+    }
+    let chain: Vec<String> = bands
+        .iter()
+        .map(|band| {
+            format!(
+                "equalizer=f={}:t=q:w={}:g={}",
+                band.frequency, band.q, band.gain
+            )
+        })
+        .collect();
+    Some(format!("lavfi=[{}]", chain.join(",")))
+}
+
+impl Presets {
+    /**
+     * Loads the presets from `path`, a missing file means there are none yet.
+     */
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = config::load_unversioned::<PresetFile>(path, config::BACKUP_COUNT)
+            .map_err(|err| err.to_string())?
+            .unwrap_or_default();
+        Ok(Presets {
+            path: path.to_path_buf(),
+            presets: file.presets,
+        })
+    }
+
+    /**
+     * An empty set of presets that will be saved to `path`.
+     */
+    pub fn empty(path: &Path) -> Self {
+        Presets {
+            path: path.to_path_buf(),
+            presets: BTreeMap::new(),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let file = PresetFile {
+            presets: self.presets.clone(),
+        };
+        config::save_unversioned(&self.path, &file, config::BACKUP_COUNT)
+    }
+
+    pub fn all(&self) -> &BTreeMap<String, Vec<Band>> {
+        &self.presets
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[Band]> {
+        self.presets.get(name).map(Vec::as_slice)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.presets.contains_key(name)
+    }
+
+    /**
+     * Sets or, for `None`, removes a preset without saving, so the change can
+     * be undone. Returns the previous bands.
+     */
+    pub fn replace(&mut self, name: &str, bands: Option<Vec<Band>>) -> Option<Vec<Band>> {
+        match bands {
+            Some(bands) => self.presets.insert(name.to_string(), bands),
+            None => self.presets.remove(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::fs;
+
+    use crate::testutil::test_dir;
+
+    #[test]
+    fn test_filter() {
+        let bands = vec![
+            Band {
+                frequency: 100.0,
+                gain: 4.5,
+                q: 0.7,
+            },
+            Band {
+                frequency: 3000.0,
+                gain: -2.0,
+                q: 1.0,
+            },
+        ];
+        assert!(validate(&bands).is_ok());
+        assert_eq!(
+            filter(&bands).unwrap(),
+            "lavfi=[equalizer=f=100:t=q:w=0.7:g=4.5,equalizer=f=3000:t=q:w=1:g=-2]"
+        );
+        assert_eq!(filter(&[]), None);
+
+        let invalid = vec![Band {
+            frequency: 0.0,
+            gain: 0.0,
+            q: 1.0,
+        }];
+        assert!(validate(&invalid).is_err());
+
+        let invalid = vec![Band {
+            frequency: 100.0,
+            gain: 0.0,
+            q: f64::INFINITY,
+        }];
+        assert!(validate(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = test_dir("eq");
+        let path = dir.join("eq_presets.json");
+
+        let mut presets = Presets::load(&path).unwrap();
+        assert!(presets.all().is_empty());
+        let bands = vec![Band {
+            frequency: 80.0,
+            gain: 6.0,
+            q: 1.0,
+        }];
+        assert_eq!(presets.replace("bass", Some(bands.clone())), None);
+        presets.save().unwrap();
+
+        let loaded = Presets::load(&path).unwrap();
+        assert_eq!(loaded.get("bass"), Some(&bands[..]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
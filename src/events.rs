@@ -0,0 +1,43 @@
+use std::sync::{Arc, Mutex};
+
+use crate::player::{lock, PlaybackState, Stream};
+
+/**
+ * Changes of the player state that other parts of the program can listen to.
+ */
+#[derive(Clone, Debug)]
+pub enum PlayerEvent {
+    TitleChanged(String),
+    StreamChanged(Option<Stream>),
+    PlaybackChanged(PlaybackState),
+    VolumeChanged(u8),
+    PlaylistChanged,
+    /**
+     * Playback position and duration of the file in seconds, emitted at most
+     * once per second of playback.
+     */
+    PositionChanged(f64, Option<f64>),
+    /**
+     * MPV began playing the file or URL, positions that follow are within it.
+     */
+    FileStarted(String),
+}
+
+/**
+ * A listener returns `false` once it is no longer interested in events, e.g.
+ * because the receiving end of its channel is gone, after which it is removed.
+ */
+pub type Listener = Box<dyn Fn(&PlayerEvent) -> bool + Send>;
+
+#[derive(Clone, Default)]
+pub struct Listeners(Arc<Mutex<Vec<Listener>>>);
+
+impl Listeners {
+    pub fn add<F: 'static + Fn(&PlayerEvent) -> bool + Send>(&self, listener: F) {
+        lock(&self.0).push(Box::new(listener));
+    }
+
+    pub fn emit(&self, event: PlayerEvent) {
+        lock(&self.0).retain(|listener| listener(&event));
+    }
+}
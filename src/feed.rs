@@ -0,0 +1,460 @@
+use std::fs;
+use std::path::Path;
+
+/**
+ * A podcast feed, either RSS 2.0 or Atom.
+ */
+#[derive(Debug, PartialEq)]
+pub struct Feed {
+    pub title: String,
+    pub items: Vec<Item>,
+}
+
+/**
+ * An episode with its audio enclosure, entries without one are skipped.
+ */
+#[derive(Debug, PartialEq)]
+pub struct Item {
+    pub guid: String,
+    pub title: String,
+    pub url: String,
+    /**
+     * Seconds since the epoch.
+     */
+    pub published: Option<u64>,
+    /**
+     * Length in seconds, from `itunes:duration`.
+     */
+    pub duration: Option<f64>,
+}
+
+/**
+ * Just enough XML for feeds: elements, attributes, text and CDATA. Namespace
+ * prefixes are kept as part of the name.
+ */
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+impl Element {
+    /**
+     * Name without the namespace prefix.
+     */
+    fn local_name(&self) -> &str {
+        self.name.rsplit(':').next().unwrap_or("")
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.children
+            .iter()
+            .filter(move |child| child.local_name() == name)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children
+            .iter()
+            .find(|child| child.local_name() == name)
+    }
+
+    fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name)
+            .map(|child| child.text.trim())
+            .filter(|text| !text.is_empty())
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end < 12 => end,
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16)
+                    .ok()
+                    .and_then(std::char::from_u32)
+            }
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn parse_attrs(txt: &str) -> Result<Vec<(String, String)>, String> {
+    let mut attrs = Vec::new();
+    let mut rest = txt.trim();
+    while !rest.is_empty() {
+        let eq = rest
+            .find('=')
+            .ok_or_else(|| format!("Invalid attribute {}", rest))?;
+        let name = rest[..eq].trim().to_string();
+        rest = rest[eq + 1..].trim_start();
+        let quote = rest
+            .chars()
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+            .ok_or_else(|| format!("Unquoted attribute {}", name))?;
+        let end = rest[1..]
+            .find(quote)
+            .ok_or_else(|| format!("Unterminated attribute {}", name))?;
+        attrs.push((name, unescape(&rest[1..end + 1])));
+        rest = rest[end + 2..].trim_start();
+    }
+    Ok(attrs)
+}
+
+/**
+ * Parses a document into its root element. Closing tags that don't match are
+ * tolerated by closing the elements opened in between.
+ */
+fn parse_xml(xml: &str) -> Result<Element, String> {
+    let mut stack = vec![Element::default()];
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        stack.last_mut().unwrap().text.push_str(&unescape(text));
+        rest = &rest[start..];
+
+        if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").ok_or("Unterminated CDATA section")?;
+            stack.last_mut().unwrap().text.push_str(&rest[9..end]);
+            rest = &rest[end + 3..];
+        } else if rest.starts_with("<!--") {
+            let end = rest.find("-->").ok_or("Unterminated comment")?;
+            rest = &rest[end + 3..];
+        } else if rest.starts_with("<?") || rest.starts_with("<!") {
+            let end = rest.find('>').ok_or("Unterminated declaration")?;
+            rest = &rest[end + 1..];
+        } else if rest.starts_with("</") {
+            let end = rest.find('>').ok_or("Unterminated tag")?;
+            let name = rest[2..end].trim();
+            rest = &rest[end + 1..];
+            if stack[1..].iter().any(|element| element.name == name) {
+                loop {
+                    let element = stack.pop().unwrap();
+                    let done = element.name == name;
+                    stack.last_mut().unwrap().children.push(element);
+                    if done {
+                        break;
+                    }
+                }
+            }
+        } else {
+            let end = rest.find('>').ok_or("Unterminated tag")?;
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            let (tag, empty) = match tag.strip_suffix('/') {
+                Some(tag) => (tag, true),
+                None => (tag, false),
+            };
+            let split = tag.find(char::is_whitespace).unwrap_or(tag.len());
+            let element = Element {
+                name: tag[..split].to_string(),
+                attrs: parse_attrs(&tag[split..])?,
+                ..Default::default()
+            };
+            if empty {
+                stack.last_mut().unwrap().children.push(element);
+            } else {
+                stack.push(element);
+            }
+        }
+    }
+
+    while stack.len() > 1 {
+        let element = stack.pop().unwrap();
+        stack.last_mut().unwrap().children.push(element);
+    }
+    stack
+        .pop()
+        .unwrap()
+        .children
+        .into_iter()
+        .next()
+        .ok_or_else(|| "Not an XML document".to_string())
+}
+
+/**
+ * Days since the epoch, `None` if the year is too far out to be represented.
+ */
+fn days_from_civil(year: i64, month: u32, day: u32) -> Option<i64> {
+    let year = if month <= 2 {
+        year.checked_sub(1)?
+    } else {
+        year
+    };
+    let era = if year >= 0 {
+        year
+    } else {
+        year.checked_sub(399)?
+    } / 400;
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era.checked_mul(146_097)?.checked_add(doe - 719_468)
+}
+
+fn timestamp(date: (i64, u32, u32), time: &str, offset_minutes: i64) -> Option<u64> {
+    let mut parts = time.split(':');
+    let hour: i64 = parts.next()?.parse().ok()?;
+    let minute: i64 = parts.next()?.parse().ok()?;
+    let second: f64 = parts.next().unwrap_or("0").parse().ok()?;
+    let secs = days_from_civil(date.0, date.1, date.2)?
+        .checked_mul(86_400)?
+        .checked_add(hour.checked_mul(3600)?)?
+        .checked_add(minute.checked_mul(60)?)?
+        .checked_add(second as i64)?
+        .checked_sub(offset_minutes * 60)?;
+    if secs < 0 {
+        None
+    } else {
+        Some(secs as u64)
+    }
+}
+
+/**
+ * Offset of a time zone such as `+0200`, `-05:00`, `Z` or `GMT` in minutes.
+ */
+fn zone_offset(zone: &str) -> Option<i64> {
+    match zone {
+        "" | "Z" | "UT" | "UTC" | "GMT" => return Some(0),
+        "EST" => return Some(-5 * 60),
+        "EDT" => return Some(-4 * 60),
+        "CST" => return Some(-6 * 60),
+        "CDT" => return Some(-5 * 60),
+        "MST" => return Some(-7 * 60),
+        "MDT" => return Some(-6 * 60),
+        "PST" => return Some(-8 * 60),
+        "PDT" => return Some(-7 * 60),
+        _ => (),
+    }
+    let sign = match zone.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits: String = zone[1..].chars().filter(|c| *c != ':').collect();
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 60 + minutes))
+}
+
+/**
+ * Parses RFC 2822 dates as used by RSS, e.g. `Wed, 02 Oct 2002 13:00:00 GMT`.
+ */
+pub fn parse_rfc2822(date: &str) -> Option<u64> {
+    let date = date.split_once(',').map_or(date, |(_, date)| date);
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    if parts.len() < 4 {
+        return None;
+    }
+    let day: u32 = parts[0].parse().ok()?;
+    let month_name = parts[1].to_ascii_lowercase();
+    let month = MONTHS
+        .iter()
+        .position(|name| month_name.starts_with(name))? as u32
+        + 1;
+    let mut year: i64 = parts[2].parse().ok()?;
+    if year < 100 {
+        year += if year < 50 { 2000 } else { 1900 };
+    }
+    let offset = zone_offset(parts.get(4).copied().unwrap_or(""))?;
+    timestamp((year, month, day), parts[3], offset)
+}
+
+/**
+ * Parses RFC 3339 dates as used by Atom, e.g. `2003-12-13T18:30:02+01:00`.
+ */
+pub fn parse_rfc3339(date: &str) -> Option<u64> {
+    let (day, time) = date.trim().split_once(&['T', 't', ' '][..])?;
+    let mut fields = day.split('-');
+    let year: i64 = fields.next()?.parse().ok()?;
+    let month: u32 = fields.next()?.parse().ok()?;
+    let day: u32 = fields.next()?.parse().ok()?;
+    let zone_start = time.find(&['Z', 'z', '+', '-'][..]).unwrap_or(time.len());
+    let offset = zone_offset(&time[zone_start..].to_ascii_uppercase())?;
+    timestamp((year, month, day), &time[..zone_start], offset)
+}
+
+/**
+ * Parses `itunes:duration`, which is either seconds or `[HH:]MM:SS`.
+ */
+pub fn parse_duration(duration: &str) -> Option<f64> {
+    duration.trim().split(':').try_fold(0.0, |total, part| {
+        Some(total * 60.0 + part.parse::<f64>().ok()?)
+    })
+}
+
+fn parse_rss(channel: &Element) -> Feed {
+    let items = channel
+        .children("item")
+        .filter_map(|item| {
+            let url = item.child("enclosure")?.attr("url")?.to_string();
+            Some(Item {
+                guid: item.child_text("guid").unwrap_or(&url).to_string(),
+                title: item.child_text("title").unwrap_or("").to_string(),
+                published: item.child_text("pubDate").and_then(parse_rfc2822),
+                duration: item.child_text("duration").and_then(parse_duration),
+                url,
+            })
+        })
+        .collect();
+    Feed {
+        title: channel.child_text("title").unwrap_or("").to_string(),
+        items,
+    }
+}
+
+fn parse_atom(feed: &Element) -> Feed {
+    let items = feed
+        .children("entry")
+        .filter_map(|entry| {
+            let url = entry
+                .children("link")
+                .find(|link| link.attr("rel") == Some("enclosure"))?
+                .attr("href")?
+                .to_string();
+            Some(Item {
+                guid: entry.child_text("id").unwrap_or(&url).to_string(),
+                title: entry.child_text("title").unwrap_or("").to_string(),
+                published: entry
+                    .child_text("published")
+                    .or_else(|| entry.child_text("updated"))
+                    .and_then(parse_rfc3339),
+                duration: entry.child_text("duration").and_then(parse_duration),
+                url,
+            })
+        })
+        .collect();
+    Feed {
+        title: feed.child_text("title").unwrap_or("").to_string(),
+        items,
+    }
+}
+
+pub fn parse(xml: &str) -> Result<Feed, String> {
+    let root = parse_xml(xml)?;
+    match root.local_name() {
+        "rss" => root
+            .child("channel")
+            .map(parse_rss)
+            .ok_or_else(|| "RSS feed without a channel".to_string()),
+        "feed" => Ok(parse_atom(&root)),
+        name => Err(format!("Not a feed: <{}>", name)),
+    }
+}
+
+pub fn parse_file(path: &Path) -> Result<Feed, String> {
+    let xml = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    parse(&xml)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::testutil::feed_fixture;
+
+    #[test]
+    fn test_parse_rss() {
+        let feed = feed_fixture("podcast.rss");
+        assert_eq!(feed.title, "Radio & Co");
+        assert_eq!(feed.items.len(), 2);
+        assert_eq!(
+            feed.items[0],
+            Item {
+                guid: "episode-2".to_string(),
+                title: "Episode <2>".to_string(),
+                url: "https://example.com/episode2.mp3".to_string(),
+                published: Some(1_033_563_600),
+                duration: Some(3723.0),
+            }
+        );
+        assert_eq!(feed.items[1].guid, "https://example.com/episode1.mp3");
+        assert_eq!(feed.items[1].duration, Some(1800.0));
+    }
+
+    #[test]
+    fn test_parse_atom() {
+        let feed = feed_fixture("podcast.atom");
+        assert_eq!(feed.title, "Atom Radio");
+        assert_eq!(feed.items.len(), 1);
+        assert_eq!(feed.items[0].guid, "urn:uuid:1225c695");
+        assert_eq!(feed.items[0].url, "https://example.com/show.ogg");
+        assert_eq!(feed.items[0].published, Some(1_071_336_602));
+        assert!(parse("<html></html>").is_err());
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(parse_rfc2822("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(
+            parse_rfc2822("02 Oct 2002 15:00:00 +0200"),
+            Some(1_033_563_600)
+        );
+        assert_eq!(parse_rfc3339("1970-01-01T01:00:00+01:00"), Some(0));
+        assert_eq!(
+            parse_rfc3339("2003-12-13T17:30:02.25Z"),
+            Some(1_071_336_602)
+        );
+        assert_eq!(parse_rfc3339("yesterday"), None);
+        assert_eq!(parse_rfc2822("02 Oct 2002 15:00:00 +1\u{e9}2"), None);
+        assert_eq!(parse_rfc3339("2002-10-02T15:00:00-1\u{e9}2"), None);
+        assert_eq!(
+            parse_rfc2822("02 Oct 9223372036854775807 15:00:00 GMT"),
+            None
+        );
+        assert_eq!(parse_rfc3339("2002-10-02T9223372036854775807:00:00Z"), None);
+        assert_eq!(parse_duration("1:02:03"), Some(3723.0));
+        assert_eq!(parse_duration("90"), Some(90.0));
+        assert_eq!(parse_duration("soon"), None);
+    }
+}
@@ -0,0 +1,161 @@
+use std::env;
+use std::ffi::CString;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::player::{lock, Player};
+
+/**
+ * How long the playback position may stand still while playing before the
+ * audio pipeline is considered stalled.
+ */
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(15);
+
+/**
+ * Interval of the health checks when systemd did not ask for watchdog pings.
+ */
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub mpv: bool,
+    pub event_thread: bool,
+    pub config_writable: bool,
+    pub audio_flowing: bool,
+}
+
+impl Health {
+    pub fn ok(&self) -> bool {
+        self.mpv && self.event_thread && self.config_writable && self.audio_flowing
+    }
+
+    fn failures(&self) -> Vec<&'static str> {
+        let mut failures = Vec::new();
+        for (ok, name) in &[
+            (self.mpv, "mpv"),
+            (self.event_thread, "event_thread"),
+            (self.config_writable, "config_writable"),
+            (self.audio_flowing, "audio_flowing"),
+        ] {
+            if !ok {
+                failures.push(*name);
+            }
+        }
+        failures
+    }
+}
+
+/**
+ * Whether `path` can be written, or created if it does not exist yet. Saving
+ * also needs the directory to be writable for the temporary file and backups.
+ */
+pub fn writable(path: &Path) -> bool {
+    let access = |path: &Path| match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    };
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return false,
+    };
+    access(dir) && (!path.exists() || access(path))
+}
+
+/**
+ * Sends `state` to the systemd notification socket at `socket`, names starting
+ * with `@` are abstract socket addresses.
+ */
+fn notify_socket(socket: &str, state: &str) -> io::Result<()> {
+    let addr = if let Some(name) = socket.strip_prefix('@') {
+        SocketAddr::from_abstract_name(name.as_bytes())?
+    } else {
+        SocketAddr::from_pathname(socket)?
+    };
+    let datagram = UnixDatagram::unbound()?;
+    datagram.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/**
+ * Reports readiness and watchdog pings to systemd if the service manager
+ * provided a notification socket. `READY=1` is sent once the first health
+ * check passes, `WATCHDOG=1` only while the checks keep passing so systemd
+ * restarts a radio that stopped playing.
+ */
+pub fn spawn_notifier(player: Arc<Mutex<Player>>) -> Option<JoinHandle<()>> {
+    let socket = env::var("NOTIFY_SOCKET").ok()?;
+    let watchdog = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(|usec| Duration::from_micros(usec) / 2);
+
+    Some(thread::spawn(move || {
+        let mut ready = false;
+        loop {
+            let health = lock(&player).health();
+            let state = if health.ok() {
+                let mut state = "STATUS=Healthy\n".to_string();
+                if !ready {
+                    state.push_str("READY=1\n");
+                }
+                if watchdog.is_some() {
+                    state.push_str("WATCHDOG=1\n");
+                }
+                ready = true;
+                state
+            } else {
+                format!("STATUS=Failing: {}\n", health.failures().join(", "))
+            };
+            if let Err(err) = notify_socket(&socket, &state) {
+                eprintln!("W: Failed to notify systemd: {}", err);
+            }
+            thread::sleep(watchdog.unwrap_or(CHECK_INTERVAL));
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::fs;
+
+    use crate::testutil::test_dir;
+
+    #[test]
+    fn test_writable() {
+        let dir = test_dir("health");
+        let file = dir.join("radio.json");
+        assert!(writable(&file));
+
+        fs::write(&file, "{}").unwrap();
+        assert!(writable(&file));
+        assert!(!writable(&dir.join("missing").join("radio.json")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_notify_socket() {
+        let dir = test_dir("notify");
+        let path = dir.join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(path.to_str().unwrap(), "READY=1\n").unwrap();
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,14 +1,98 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+use actix_files::NamedFile;
+
+use actix_web::error::{self, JsonPayloadError, PathError, ResponseError};
 use actix_web::http::uri::{Scheme, Uri};
-use actix_web::{web, HttpResponse, Responder, Result};
+use actix_web::http::StatusCode;
+use actix_web::{web, Error, HttpRequest, HttpResponse, Result};
 
+use futures::{Future, Stream};
 use serde::{Deserialize, Serialize};
 
-use crate::player::Player;
+use crate::eq::Band;
+use crate::library::Library;
+use crate::player::{lock, AudioDevice, Player, PlayerError};
+use crate::podcasts::{PodcastError, Podcasts};
+use crate::record::{self, RecordRequest, Recorder};
+use crate::restream::{self, Restream};
+use crate::schedule::{ScheduleError, ScheduledRecording, Scheduler};
+use crate::webhooks::Webhooks;
+use crate::zones::Zones;
 
 pub struct AppState {
     pub player: Arc<Mutex<Player>>,
+    pub webhooks: Webhooks,
+    pub recorder: Recorder,
+    pub scheduler: Scheduler,
+    pub zones: Zones,
+    pub restream: Restream,
+    pub library: Library,
+    pub podcasts: Podcasts,
+}
+
+impl AppState {
+    fn zone(&self, name: &str) -> Result<&Arc<Mutex<Player>>, ApiError> {
+        self.zones.get(name).ok_or_else(|| ApiError {
+            status: StatusCode::NOT_FOUND,
+            code: "unknown_zone",
+            message: format!("No zone {}", name),
+        })
+    }
+
+    /**
+     * The player of the zone in the request path, routes without a zone act
+     * on the default zone.
+     */
+    fn player(&self, req: &HttpRequest) -> Result<&Arc<Mutex<Player>>, ApiError> {
+        match req.match_info().get("zone") {
+            Some(name) => self.zone(name),
+            None => Ok(&self.player),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AudioDevices<'a> {
+    selected: Option<&'a str>,
+    devices: Vec<AudioDevice>,
+}
+
+#[derive(Serialize)]
+struct ScheduleInfo {
+    recordings: Vec<ScheduledRecording>,
+    quota_mb: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct BrowseQuery {
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    q: String,
+}
+
+#[derive(Deserialize)]
+pub struct LibraryPlay {
+    path: String,
+    #[serde(default)]
+    append: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PodcastRequest {
+    url: String,
+    #[serde(default)]
+    auto_download: bool,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -17,12 +101,143 @@ pub struct StreamInfo {
     pub url: String,
 }
 
+/**
+ * Error returned by every handler. It is rendered as a JSON object with a
+ * machine-readable `code` and a human-readable `message`.
+ */
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json2(&ErrorBody {
+            code: self.code,
+            message: &self.message,
+        })
+    }
+}
+
+impl From<PlayerError> for ApiError {
+    fn from(err: PlayerError) -> Self {
+        let status = match err {
+            PlayerError::NotFound(_) | PlayerError::UnknownStream(_) => StatusCode::NOT_FOUND,
+            PlayerError::InvalidUrl(_)
+            | PlayerError::InvalidGain(_)
+            | PlayerError::InvalidOption(_) => StatusCode::BAD_REQUEST,
+            PlayerError::Backend(_) => StatusCode::BAD_GATEWAY,
+            PlayerError::Persistence(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PlayerError::NotPlaying => StatusCode::CONFLICT,
+            PlayerError::UnknownPreset(_) => StatusCode::NOT_FOUND,
+            PlayerError::InvalidPreset(_) => StatusCode::BAD_REQUEST,
+            PlayerError::UnknownDevice(_) => StatusCode::NOT_FOUND,
+            PlayerError::Restream(_) => StatusCode::BAD_GATEWAY,
+        };
+        ApiError {
+            status,
+            code: err.code(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<ScheduleError> for ApiError {
+    fn from(err: ScheduleError) -> Self {
+        let status = match err {
+            ScheduleError::NotFound(_) => StatusCode::NOT_FOUND,
+            ScheduleError::UnknownStation(_) | ScheduleError::Invalid(_) => StatusCode::BAD_REQUEST,
+            ScheduleError::Conflict(_) => StatusCode::CONFLICT,
+            ScheduleError::Persistence(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError {
+            status,
+            code: err.code(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<PodcastError> for ApiError {
+    fn from(err: PodcastError) -> Self {
+        let status = match err {
+            PodcastError::Player(err) => return err.into(),
+            PodcastError::NotFound(_) | PodcastError::EpisodeNotFound(..) => StatusCode::NOT_FOUND,
+            PodcastError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+            PodcastError::Persistence(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError {
+            status,
+            code: err.code(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(err: io::Error) -> Self {
+        let (status, code) = match err.kind() {
+            io::ErrorKind::NotFound => (StatusCode::NOT_FOUND, "not_found"),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, "io_failure"),
+        };
+        ApiError {
+            status,
+            code,
+            message: err.to_string(),
+        }
+    }
+}
+
+/**
+ * Extractor configuration so malformed request bodies get the same JSON error
+ * format as the rest of the API.
+ */
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err: JsonPayloadError, _req: &HttpRequest| {
+        Error::from(ApiError {
+            status: StatusCode::BAD_REQUEST,
+            code: "invalid_request",
+            message: err.to_string(),
+        })
+    })
+}
+
+/**
+ * Same as `json_config` for malformed path segments such as `/stream/abc`.
+ */
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|err: PathError, _req: &HttpRequest| {
+        Error::from(ApiError {
+            status: StatusCode::NOT_FOUND,
+            code: "invalid_path",
+            message: err.to_string(),
+        })
+    })
+}
+
 pub fn get_playlist(data: web::Data<AppState>) -> Result<HttpResponse> {
-    let guard = data.player.lock().unwrap();
+    let guard = lock(&data.player);
     Ok(HttpResponse::Ok().json2(&guard.get_playlist()))
 }
 
-pub fn post_stream(info: web::Json<StreamInfo>, data: web::Data<AppState>) -> Result<HttpResponse> {
+pub fn post_stream(
+    info: web::Json<StreamInfo>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
     let new_stream = info.into_inner();
 
     match new_stream.url.parse::<Uri>() {
@@ -30,36 +245,501 @@ pub fn post_stream(info: web::Json<StreamInfo>, data: web::Data<AppState>) -> Re
             if uri.scheme_part() == Some(&Scheme::HTTP)
                 || uri.scheme_part() == Some(&Scheme::HTTPS) =>
         {
-            let mut guard = data.player.lock().unwrap();
-            Ok(HttpResponse::Ok().json2(guard.add(new_stream.name, new_stream.url)))
+            let mut guard = lock(&data.player);
+            Ok(HttpResponse::Ok().json2(guard.add(new_stream.name, new_stream.url)?))
         }
-        _ => Ok(HttpResponse::BadRequest().body("URL invalid or unsupported")),
+        _ => Err(PlayerError::InvalidUrl(new_stream.url).into()),
     }
 }
 
-pub fn get_stream(data: web::Data<AppState>) -> impl Responder {
-    let guard = data.player.lock().unwrap();
-    HttpResponse::Ok().json2(&guard.get_current())
+pub fn get_stream(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let guard = lock(data.player(&req)?);
+    Ok(HttpResponse::Ok().json2(&guard.get_current()))
 }
 
-pub fn delete_stream(info: web::Path<usize>, data: web::Data<AppState>) -> impl Responder {
-    let mut guard = data.player.lock().unwrap();
-    match guard.delete(info.into_inner()) {
-        Some(stream) => HttpResponse::Ok().json2(&stream),
-        None => HttpResponse::NotFound().body("No stream with the provided ID"),
-    }
+pub fn delete_stream(
+    info: web::Path<usize>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(&data.player);
+    let stream = guard.delete(info.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&stream))
+}
+
+fn play(player: &Mutex<Player>, id: usize) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(player);
+    let stream = guard.play(id)?;
+    Ok(HttpResponse::Ok().json2(&stream))
+}
+
+pub fn put_play(
+    info: web::Path<usize>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    play(&data.player, info.into_inner())
+}
+
+pub fn put_zone_play(
+    info: web::Path<(String, usize)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (zone, id) = info.into_inner();
+    play(data.zone(&zone)?, id)
+}
+
+pub fn put_stop(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(data.player(&req)?);
+    guard.stop()?;
+    Ok(HttpResponse::Ok().json2(&guard.get_state()))
+}
+
+pub fn get_volume(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let guard = lock(data.player(&req)?);
+    Ok(HttpResponse::Ok().json2(&guard.get_volume()))
 }
 
-pub fn put_play(info: web::Path<usize>, data: web::Data<AppState>) -> impl Responder {
-    let mut guard = data.player.lock().unwrap();
-    match guard.play(info.into_inner()) {
-        Ok(stream) => HttpResponse::Ok().json2(&stream),
-        Err(_) => HttpResponse::NotFound().body("No stream with the provided ID"),
+pub fn put_volume(
+    req: HttpRequest,
+    volume: web::Json<u8>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(data.player(&req)?);
+    guard.set_volume(volume.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&guard.get_volume()))
+}
+
+/**
+ * Streams the current station, with ICY metadata for clients asking for it.
+ */
+pub fn get_listen(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> impl Future<Item = HttpResponse, Error = ApiError> {
+    let icy = req
+        .headers()
+        .get("icy-metadata")
+        .map_or(false, |value| value.as_bytes() == b"1");
+
+    data.restream
+        .connect(icy)
+        .from_err()
+        .map(move |(content_type, audio)| {
+            let mut response = HttpResponse::Ok();
+            response
+                .content_type(content_type)
+                .header("cache-control", "no-cache");
+            if icy {
+                response.header("icy-metaint", restream::METAINT.to_string());
+            }
+            response.streaming(
+                audio
+                    .map(web::Bytes::from)
+                    .map_err(|()| error::ErrorInternalServerError("Re-streaming ended")),
+            )
+        })
+}
+
+pub fn get_zones(data: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json2(&data.zones.info()))
+}
+
+pub fn get_library(
+    query: web::Query<BrowseQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let listing = data.library.browse(&query.path).ok_or_else(|| ApiError {
+        status: StatusCode::NOT_FOUND,
+        code: "not_found",
+        message: format!("No directory {}", query.path),
+    })?;
+    Ok(HttpResponse::Ok().json2(&listing))
+}
+
+pub fn get_library_search(
+    query: web::Query<SearchQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json2(&data.library.search(&query.q)))
+}
+
+pub fn get_library_scan(data: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json2(&data.library.status()))
+}
+
+pub fn post_library_scan(data: web::Data<AppState>) -> Result<HttpResponse> {
+    data.library.scan();
+    Ok(HttpResponse::Accepted().json2(&data.library.status()))
+}
+
+/**
+ * Plays a file or a folder of the library, replacing the station or the
+ * files playing unless `append` is set.
+ */
+pub fn put_library_play(
+    req: HttpRequest,
+    info: web::Json<LibraryPlay>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let tracks = data.library.tracks(&info.path);
+    if tracks.is_empty() {
+        return Err(ApiError {
+            status: StatusCode::NOT_FOUND,
+            code: "not_found",
+            message: format!("No tracks in {}", info.path),
+        });
     }
+    let files = tracks
+        .iter()
+        .map(|track| Library::file(track).to_string_lossy().to_string())
+        .collect();
+    lock(data.player(&req)?).play_files(files, info.append, 0.0)?;
+    Ok(HttpResponse::Ok().json2(&tracks))
+}
+
+pub fn get_podcasts(data: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json2(&data.podcasts.list()))
+}
+
+/**
+ * Subscribes to a feed, its episodes are fetched in the background.
+ */
+pub fn post_podcast(
+    info: web::Json<PodcastRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let info = info.into_inner();
+    let feed = data.podcasts.subscribe(info.url, info.auto_download)?;
+    Ok(HttpResponse::Accepted().json2(&feed))
 }
 
-pub fn get_now_playing(data: web::Data<AppState>) -> impl Responder {
-    let guard = data.player.lock().unwrap();
-    HttpResponse::Ok().json2(&guard.get_now_playing())
+pub fn get_podcast(
+    info: web::Path<usize>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json2(&data.podcasts.get(info.into_inner())?))
 }
 
+pub fn delete_podcast(
+    info: web::Path<usize>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json2(&data.podcasts.unsubscribe(info.into_inner())?))
+}
+
+pub fn post_podcast_refresh(
+    info: web::Path<usize>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let id = info.into_inner();
+    data.podcasts.get(id)?;
+    data.podcasts.spawn_refresh(id);
+    Ok(HttpResponse::Accepted().finish())
+}
+
+pub fn put_podcast_download(
+    info: web::Path<usize>,
+    auto_download: web::Json<bool>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let feed = data
+        .podcasts
+        .set_auto_download(info.into_inner(), auto_download.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&feed))
+}
+
+pub fn put_episode_play(
+    info: web::Path<(usize, usize)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, episode) = info.into_inner();
+    Ok(HttpResponse::Ok().json2(&data.podcasts.play(id, episode)?))
+}
+
+pub fn put_episode_played(
+    info: web::Path<(usize, usize)>,
+    played: web::Json<bool>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, episode) = info.into_inner();
+    let episode = data.podcasts.set_played(id, episode, played.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&episode))
+}
+
+pub fn put_scrobble(
+    info: web::Path<usize>,
+    scrobble: web::Json<bool>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(&data.player);
+    let stream = guard.set_scrobble(info.into_inner(), scrobble.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&stream))
+}
+
+pub fn put_gain(
+    info: web::Path<usize>,
+    gain: web::Json<f64>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(&data.player);
+    let stream = guard.set_gain(info.into_inner(), gain.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&stream))
+}
+
+pub fn put_stream_options(
+    info: web::Path<usize>,
+    options: web::Json<BTreeMap<String, String>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(&data.player);
+    let stream = guard.set_stream_options(info.into_inner(), options.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&stream))
+}
+
+pub fn get_normalization(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let guard = lock(&data.player);
+    Ok(HttpResponse::Ok().json2(&guard.cfg.normalization))
+}
+
+pub fn put_normalization(
+    enabled: web::Json<bool>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(&data.player);
+    let normalization = guard.set_normalization(enabled.into_inner())?;
+    Ok(HttpResponse::Ok().json2(normalization))
+}
+
+pub fn get_eq(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let guard = lock(&data.player);
+    Ok(HttpResponse::Ok().json2(&guard.eq_status()))
+}
+
+pub fn put_eq(
+    preset: web::Json<Option<String>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(&data.player);
+    let status = guard.set_eq(preset.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&status))
+}
+
+pub fn put_stream_eq(
+    info: web::Path<usize>,
+    preset: web::Json<Option<String>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(&data.player);
+    let stream = guard.set_stream_eq(info.into_inner(), preset.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&stream))
+}
+
+pub fn get_eq_presets(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let guard = lock(&data.player);
+    Ok(HttpResponse::Ok().json2(guard.eq_presets()))
+}
+
+pub fn put_eq_preset(
+    info: web::Path<String>,
+    bands: web::Json<Vec<Band>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(&data.player);
+    guard.set_eq_preset(info.into_inner(), bands.into_inner())?;
+    Ok(HttpResponse::Ok().json2(guard.eq_presets()))
+}
+
+pub fn delete_eq_preset(
+    info: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(&data.player);
+    let bands = guard.delete_eq_preset(&info)?;
+    Ok(HttpResponse::Ok().json2(&bands))
+}
+
+pub fn get_audio_devices(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let guard = lock(data.player(&req)?);
+    Ok(HttpResponse::Ok().json2(&AudioDevices {
+        selected: guard.cfg.audio_device.as_ref().map(String::as_str),
+        devices: guard.audio_devices()?,
+    }))
+}
+
+pub fn put_audio_device(
+    req: HttpRequest,
+    name: web::Json<Option<String>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(data.player(&req)?);
+    guard.set_audio_device(name.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&guard.cfg.audio_device))
+}
+
+pub fn get_now_playing(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let guard = lock(data.player(&req)?);
+    Ok(HttpResponse::Ok().json2(&guard.get_now_playing()))
+}
+
+pub fn post_record(
+    request: web::Json<RecordRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let status = data.recorder.start(request.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&status))
+}
+
+pub fn get_record(data: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json2(&data.recorder.status()))
+}
+
+pub fn delete_record(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let status = data.recorder.stop()?;
+    Ok(HttpResponse::Ok().json2(&status))
+}
+
+pub fn get_recordings(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let files = record::list(data.recorder.dir())?;
+    Ok(HttpResponse::Ok().json2(&files))
+}
+
+fn recording_path(data: &AppState, name: &str) -> Result<PathBuf, ApiError> {
+    record::recording_path(data.recorder.dir(), name).ok_or_else(|| ApiError {
+        status: StatusCode::BAD_REQUEST,
+        code: "invalid_name",
+        message: format!("Invalid recording name: {}", name),
+    })
+}
+
+pub fn get_recording(
+    info: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<NamedFile, ApiError> {
+    let path = recording_path(&data, &info)?;
+    Ok(NamedFile::open(path)?.use_last_modified(true))
+}
+
+pub fn delete_recording(
+    info: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let path = recording_path(&data, &info)?;
+    fs::remove_file(path)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub fn get_schedule(data: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json2(&ScheduleInfo {
+        recordings: data.scheduler.list(),
+        quota_mb: data.scheduler.quota_mb(),
+    }))
+}
+
+pub fn post_schedule(
+    recording: web::Json<ScheduledRecording>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let recording = data.scheduler.add(recording.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&recording))
+}
+
+pub fn put_schedule(
+    info: web::Path<usize>,
+    recording: web::Json<ScheduledRecording>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let recording = data
+        .scheduler
+        .update(info.into_inner(), recording.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&recording))
+}
+
+pub fn delete_schedule(
+    info: web::Path<usize>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let recording = data.scheduler.delete(info.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&recording))
+}
+
+pub fn put_quota(
+    quota_mb: web::Json<Option<u64>>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    data.scheduler.set_quota_mb(quota_mb.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&data.scheduler.quota_mb()))
+}
+
+pub fn put_pause(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(data.player(&req)?);
+    guard.pause(true)?;
+    Ok(HttpResponse::Ok().json2(&guard.timeshift_status()))
+}
+
+pub fn put_resume(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(data.player(&req)?);
+    guard.pause(false)?;
+    Ok(HttpResponse::Ok().json2(&guard.timeshift_status()))
+}
+
+pub fn put_seek(
+    req: HttpRequest,
+    offset: web::Json<f64>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(data.player(&req)?);
+    guard.seek(offset.into_inner())?;
+    Ok(HttpResponse::Ok().json2(&guard.timeshift_status()))
+}
+
+pub fn put_live(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let mut guard = lock(data.player(&req)?);
+    guard.go_live()?;
+    Ok(HttpResponse::Ok().json2(&guard.timeshift_status()))
+}
+
+pub fn get_timeshift(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let guard = lock(data.player(&req)?);
+    Ok(HttpResponse::Ok().json2(&guard.timeshift_status()))
+}
+
+pub fn get_health(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let health = lock(&data.player).health();
+    let mut response = if health.ok() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    Ok(response.json2(&health))
+}
+
+pub fn get_metrics(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let guard = lock(&data.player);
+    let txt = guard
+        .metrics()
+        .render(guard.get_state(), guard.get_current(), guard.get_playlist());
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(txt))
+}
+
+pub fn post_webhook_test(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let count = lock(&data.player).cfg.webhooks.len();
+    if data.webhooks.send_test() {
+        Ok(HttpResponse::Accepted().json2(&count))
+    } else {
+        Err(ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            code: "webhooks_unavailable",
+            message: "The webhook worker is not running".to_string(),
+        })
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::mpv_simple::{MpvCtx, MpvError, MpvEvent};
+use crate::player::lock;
+
+const AUDIO_EXTENSIONS: [&str; 12] = [
+    "aac", "aiff", "ape", "flac", "m4a", "mka", "mp3", "oga", "ogg", "opus", "wav", "wma",
+];
+
+/**
+ * How long reading the tags of a single file may take.
+ */
+const TAG_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SEARCH_LIMIT: usize = 200;
+
+/**
+ * Contents of `library.json`, directories are relative to the data directory.
+ */
+#[derive(Deserialize)]
+pub struct LibraryCfg {
+    #[serde(default = "default_dirs")]
+    pub dirs: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Track {
+    /**
+     * Path relative to the data directory, separated by `/`.
+     */
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<String>,
+    pub duration: Option<f64>,
+
+    #[serde(skip)]
+    file: PathBuf,
+    #[serde(skip)]
+    modified: u64,
+}
+
+/**
+ * Contents of a directory of the library.
+ */
+#[derive(Serialize)]
+pub struct Listing {
+    pub dirs: Vec<String>,
+    pub tracks: Vec<Track>,
+}
+
+#[derive(Serialize)]
+pub struct LibraryStatus {
+    pub scanning: bool,
+    pub tracks: usize,
+}
+
+/**
+ * Index of the audio files in the configured directories. Tags are read
+ * with MPV in the background, the index is kept in memory and rebuilt on
+ * every scan.
+ */
+#[derive(Clone)]
+pub struct Library {
+    root: PathBuf,
+    dirs: Vec<String>,
+    tracks: Arc<Mutex<BTreeMap<String, Track>>>,
+    scanning: Arc<AtomicBool>,
+}
+
+/**
+ * Reads tags by loading files paused into a context without outputs.
+ */
+struct TagReader {
+    ctx: MpvCtx,
+}
+
+fn default_dirs() -> Vec<String> {
+    vec!["music".to_string()]
+}
+
+impl Default for LibraryCfg {
+    fn default() -> Self {
+        LibraryCfg {
+            dirs: default_dirs(),
+        }
+    }
+}
+
+pub fn load_config(path: &Path) -> Result<LibraryCfg, String> {
+    match fs::read_to_string(path) {
+        Ok(txt) => serde_json::from_str(&txt).map_err(|err| format!("{}: {}", path.display(), err)),
+        Err(_) => Ok(LibraryCfg::default()),
+    }
+}
+
+fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| {
+            AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+        })
+}
+
+/**
+ * Tag names differ in case between formats, e.g. ID3 and Vorbis comments.
+ */
+fn tag(metadata: &Map<String, Value>, name: &str) -> Option<String> {
+    metadata
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| value.as_str())
+        .map(str::to_string)
+}
+
+fn modified(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_secs())
+}
+
+/**
+ * Collects the audio files below `dir`. Symlinked files are followed,
+ * symlinked directories are not, so links cannot form a loop.
+ */
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            eprintln!("W: Cannot read {}: {}", dir.display(), err);
+            return;
+        }
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            walk(&path, files);
+        } else if (file_type.is_file() || path.is_file()) && is_audio(&path) {
+            files.push(path);
+        }
+    }
+}
+
+impl TagReader {
+    fn new() -> Result<Self, MpvError> {
+        let mut ctx = MpvCtx::create()?;
+        ctx.set_option("ao", "null")?;
+        ctx.set_option("vid", "no")?;
+        ctx.set_option("pause", "yes")?;
+        ctx.init()?;
+        Ok(TagReader { ctx })
+    }
+
+    fn read(&mut self, file: &Path) -> Option<(Map<String, Value>, Option<f64>)> {
+        self.ctx
+            .command(&["loadfile", &file.to_string_lossy()])
+            .ok()?;
+        let deadline = Instant::now() + TAG_TIMEOUT;
+        // Replacing a file ends the previous one first.
+        let mut started = false;
+        loop {
+            match self.ctx.wait_event(0.5) {
+                Ok(MpvEvent::StartFile) => started = true,
+                Ok(MpvEvent::FileLoaded) if started => break,
+                Ok(MpvEvent::EndFile { .. }) if started => return None,
+                Ok(MpvEvent::Shutdown) => return None,
+                _ => (),
+            }
+            if Instant::now() > deadline {
+                return None;
+            }
+        }
+
+        let metadata = match self.ctx.get_property_node("metadata") {
+            Ok(Value::Object(metadata)) => metadata,
+            _ => Map::new(),
+        };
+        let duration = self
+            .ctx
+            .get_property_node("duration")
+            .ok()
+            .and_then(|duration| duration.as_f64());
+        Some((metadata, duration))
+    }
+}
+
+impl Track {
+    fn new(path: String, file: PathBuf, tags: Option<(Map<String, Value>, Option<f64>)>) -> Self {
+        let (metadata, duration) = tags.unwrap_or_default();
+        Track {
+            path,
+            title: tag(&metadata, "title"),
+            artist: tag(&metadata, "artist"),
+            album: tag(&metadata, "album"),
+            track: tag(&metadata, "track"),
+            duration,
+            modified: modified(&file),
+            file,
+        }
+    }
+
+    fn matches(&self, words: &[String]) -> bool {
+        let text = [&self.title, &self.artist, &self.album]
+            .iter()
+            .filter_map(|tag| tag.as_ref())
+            .fold(self.path.to_lowercase(), |text, tag| {
+                text + "\n" + &tag.to_lowercase()
+            });
+        words.iter().all(|word| text.contains(word.as_str()))
+    }
+}
+
+impl Library {
+    pub fn new(root: &Path, cfg: LibraryCfg) -> Self {
+        Library {
+            root: root.to_path_buf(),
+            dirs: cfg
+                .dirs
+                .into_iter()
+                .map(|dir| dir.trim_matches('/').to_string())
+                .filter(|dir| !dir.is_empty())
+                .collect(),
+            tracks: Arc::default(),
+            scanning: Arc::default(),
+        }
+    }
+
+    /**
+     * Path of `file` relative to the data directory.
+     */
+    fn relative(&self, file: &Path) -> Option<String> {
+        let relative = file.strip_prefix(&self.root).ok()?;
+        let parts: Vec<String> = relative
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy().to_string())
+            .collect();
+        Some(parts.join("/"))
+    }
+
+    /**
+     * Rebuilds the index in the background, unchanged files keep their tags.
+     * Returns `false` if a scan is already running.
+     */
+    pub fn scan(&self) -> bool {
+        if self.scanning.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        let library = self.clone();
+        thread::spawn(move || {
+            library.rebuild();
+            library.scanning.store(false, Ordering::SeqCst);
+        });
+        true
+    }
+
+    fn rebuild(&self) {
+        let mut files = Vec::new();
+        for dir in &self.dirs {
+            let dir = self.root.join(dir);
+            if dir.is_dir() {
+                walk(&dir, &mut files);
+            }
+        }
+
+        let old = lock(&self.tracks).clone();
+        let mut reader = match TagReader::new() {
+            Ok(reader) => Some(reader),
+            Err(err) => {
+                eprintln!("W: Cannot read tags: {:?}", err);
+                None
+            }
+        };
+
+        let mut tracks = BTreeMap::new();
+        for file in files {
+            let path = match self.relative(&file) {
+                Some(path) => path,
+                None => continue,
+            };
+            let track = match old.get(&path) {
+                Some(track) if track.modified == modified(&file) => track.clone(),
+                _ => {
+                    let tags = reader.as_mut().and_then(|reader| reader.read(&file));
+                    Track::new(path.clone(), file, tags)
+                }
+            };
+            tracks.insert(path, track);
+        }
+        *lock(&self.tracks) = tracks;
+    }
+
+    pub fn status(&self) -> LibraryStatus {
+        LibraryStatus {
+            scanning: self.scanning.load(Ordering::SeqCst),
+            tracks: lock(&self.tracks).len(),
+        }
+    }
+
+    /**
+     * Lists a directory, the empty path lists the configured directories.
+     */
+    pub fn browse(&self, dir: &str) -> Option<Listing> {
+        let dir = dir.trim_matches('/');
+        if dir.is_empty() {
+            return Some(Listing {
+                dirs: self.dirs.clone(),
+                tracks: Vec::new(),
+            });
+        }
+
+        let prefix = format!("{}/", dir);
+        let mut dirs = BTreeSet::new();
+        let mut tracks = Vec::new();
+        for (path, track) in lock(&self.tracks).range(prefix.clone()..) {
+            let rest = match path.strip_prefix(&prefix) {
+                Some(rest) => rest,
+                None => break,
+            };
+            match rest.find('/') {
+                Some(end) => {
+                    dirs.insert(format!("{}{}", prefix, &rest[..end]));
+                }
+                None => tracks.push(track.clone()),
+            }
+        }
+
+        if dirs.is_empty() && tracks.is_empty() && !self.dirs.iter().any(|root| root == dir) {
+            return None;
+        }
+        Some(Listing {
+            dirs: dirs.into_iter().collect(),
+            tracks,
+        })
+    }
+
+    /**
+     * Tracks containing every word of `query` in their path or tags.
+     */
+    pub fn search(&self, query: &str) -> Vec<Track> {
+        let words: Vec<String> = query
+            .to_lowercase()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        if words.is_empty() {
+            return Vec::new();
+        }
+        lock(&self.tracks)
+            .values()
+            .filter(|track| track.matches(&words))
+            .take(SEARCH_LIMIT)
+            .cloned()
+            .collect()
+    }
+
+    /**
+     * The track at `path` or all tracks below the directory `path`, in order
+     * of their paths.
+     */
+    pub fn tracks(&self, path: &str) -> Vec<Track> {
+        let path = path.trim_matches('/');
+        let tracks = lock(&self.tracks);
+        if let Some(track) = tracks.get(path) {
+            return vec![track.clone()];
+        }
+        let prefix = format!("{}/", path);
+        tracks
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, track)| track.clone())
+            .collect()
+    }
+
+    /**
+     * File to hand to MPV for a track.
+     */
+    pub fn file(track: &Track) -> &Path {
+        &track.file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn library(paths: &[&str]) -> Library {
+        let library = Library::new(Path::new("/data"), LibraryCfg::default());
+        {
+            let mut tracks = lock(&library.tracks);
+            for path in paths {
+                let mut metadata = Map::new();
+                metadata.insert("TITLE".to_string(), Value::from(path.to_uppercase()));
+                let file = library.root.join(path);
+                let track = Track::new(path.to_string(), file, Some((metadata, Some(1.0))));
+                tracks.insert(path.to_string(), track);
+            }
+        }
+        library
+    }
+
+    #[test]
+    fn test_browse() {
+        let library = library(&[
+            "music/a/1.mp3",
+            "music/a/2.mp3",
+            "music/b/c/3.flac",
+            "music/4.ogg",
+            "music2/5.mp3",
+        ]);
+
+        let root = library.browse("").unwrap();
+        assert_eq!(root.dirs, vec!["music"]);
+
+        let music = library.browse("/music/").unwrap();
+        assert_eq!(music.dirs, vec!["music/a", "music/b"]);
+        assert_eq!(music.tracks.len(), 1);
+        assert_eq!(music.tracks[0].title.as_deref(), Some("MUSIC/4.OGG"));
+        assert!(library.browse("music/x").is_none());
+
+        let paths: Vec<String> = library
+            .tracks("music/a")
+            .into_iter()
+            .map(|track| track.path)
+            .collect();
+        assert_eq!(paths, vec!["music/a/1.mp3", "music/a/2.mp3"]);
+        assert_eq!(library.tracks("music/4.ogg").len(), 1);
+        assert!(library.tracks("music/b/c/3").is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let library = library(&["music/Jazz/one.mp3", "music/Rock/two.mp3"]);
+        assert_eq!(library.search("jazz ONE").len(), 1);
+        assert_eq!(library.search("music").len(), 2);
+        assert!(library.search("jazz two").is_empty());
+        assert!(library.search(" ").is_empty());
+        assert!(is_audio(Path::new("a/b.FLAC")));
+        assert!(!is_audio(Path::new("a/cover.jpg")));
+    }
+}
@@ -1,12 +1,38 @@
+mod config;
+mod eq;
+mod events;
+mod feed;
+mod health;
 mod http;
+mod library;
+mod metrics;
+mod mpd;
+#[cfg(feature = "mpris")]
+mod mpris;
 mod mpv_simple;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod multiroom;
 mod player;
+mod podcasts;
+mod record;
+mod reload;
+mod restream;
+mod schedule;
+mod scrobble;
+#[cfg(test)]
+mod testutil;
+mod webhooks;
+mod zones;
 
 use std::env;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use actix_web::dev::Service;
 use actix_web::{web, App, HttpServer};
+use futures::Future;
 use getopts::Options;
 
 fn print_usage(program: &str, opts: Options) {
@@ -19,6 +45,20 @@ fn main() {
     let program = args[0].clone();
     let mut opts = Options::new();
     opts.reqopt("o", "output", "Directory to store the user data", "DIR");
+    opts.optflag("w", "watch", "reload radio.json when it is changed on disk");
+    opts.optopt(
+        "m",
+        "mpd",
+        "Address of the MPD protocol server (default 127.0.0.1:6600)",
+        "ADDR",
+    );
+    opts.optflag("", "no-mpd", "do not start the MPD protocol server");
+    opts.optopt(
+        "l",
+        "listen",
+        "Address of the HTTP server (default 0.0.0.0:8080)",
+        "ADDR",
+    );
     opts.optflag("h", "help", "print this help menu");
 
     let matches = match opts.parse(&args[1..]) {
@@ -43,29 +83,371 @@ fn main() {
         std::process::exit(1);
     }
 
-    let mut mpv_ctx = mpv_simple::MpvCtx::create().expect("Failed to create MPV context");
-    mpv_ctx.init().expect("Failed to initialize MPV context");
+    let cfg_file = cfg_path.join("radio.json");
+    let player = match player::Player::from_file(&cfg_file) {
+        Ok(player) => Arc::new(Mutex::new(player)),
+        Err(err) => {
+            eprintln!("E: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    player::spawn_watchdog(player.clone(), Duration::from_secs(5));
+    reload::spawn(player.clone(), cfg_file, matches.opt_present("w"));
+
+    if !matches.opt_present("no-mpd") {
+        let mpd_addr = matches
+            .opt_str("m")
+            .unwrap_or_else(|| "127.0.0.1:6600".to_string());
+        if let Err(err) = mpd::spawn(player.clone(), mpd_addr.as_str()) {
+            eprintln!("W: Failed to start the MPD server on {}: {}", mpd_addr, err);
+        }
+    }
+
+    #[cfg(feature = "mpris")]
+    let _mpris = match mpris::spawn(player.clone(), None) {
+        Ok(mpris) => Some(mpris),
+        Err(err) => {
+            eprintln!("W: Failed to register on the session bus: {}", err);
+            None
+        }
+    };
+
+    #[cfg(feature = "mqtt")]
+    match mqtt::load_config(&cfg_path.join("mqtt.json")) {
+        Ok(Some(mqtt_cfg)) => {
+            mqtt::spawn(player.clone(), mqtt_cfg);
+        }
+        Ok(None) => (),
+        Err(err) => eprintln!("W: MQTT disabled: {}", err),
+    }
+
+    match scrobble::load_config(&cfg_path.join("scrobble.json")) {
+        Ok(Some(scrobble_cfg)) => {
+            scrobble::spawn(
+                player.clone(),
+                scrobble_cfg,
+                cfg_path.join("scrobble_queue.json"),
+            );
+        }
+        Ok(None) => (),
+        Err(err) => eprintln!("W: Scrobbling disabled: {}", err),
+    }
+
+    match multiroom::load_config(&cfg_path.join("multiroom.json")) {
+        Ok(Some(multiroom_cfg)) => {
+            let fifo = cfg_path.join("multiroom.pcm");
+            if let Err(err) = multiroom::spawn(player.clone(), multiroom_cfg, fifo) {
+                eprintln!("W: Multi-room playback disabled: {}", err);
+            }
+        }
+        Ok(None) => (),
+        Err(err) => eprintln!("W: Multi-room playback disabled: {}", err),
+    }
+
+    let restream_cfg =
+        restream::load_config(&cfg_path.join("restream.json")).unwrap_or_else(|err| {
+            eprintln!("W: Passing the station through for /listen: {}", err);
+            restream::RestreamCfg::default()
+        });
+    let restream =
+        restream::Restream::spawn(player.clone(), restream_cfg, cfg_path.join("restream.fifo"));
+
+    let library_cfg = library::load_config(&cfg_path.join("library.json")).unwrap_or_else(|err| {
+        eprintln!("W: Scanning the default library directory: {}", err);
+        library::LibraryCfg::default()
+    });
+    let library = library::Library::new(cfg_path, library_cfg);
+    library.scan();
+
+    let podcasts = match podcasts::Podcasts::spawn(
+        player.clone(),
+        cfg_path.join("podcasts.json"),
+        cfg_path.join("podcasts"),
+    ) {
+        Ok(podcasts) => podcasts,
+        Err(err) => {
+            eprintln!("E: {}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let player = Arc::new(Mutex::new(player::Player::from_file(
-        &cfg_path.join("radio.json"),
-        mpv_ctx,
-    )));
+    let webhooks = webhooks::Webhooks::spawn(player.clone());
+    let recorder = record::Recorder::spawn(player.clone(), cfg_path.join("recordings"));
+    let scheduler = match schedule::Scheduler::load(player.clone(), cfg_path.join("schedule.json"))
+    {
+        Ok(scheduler) => scheduler,
+        Err(err) => {
+            eprintln!("E: {}", err);
+            std::process::exit(1);
+        }
+    };
+    scheduler.spawn(cfg_path.join("recordings"));
+    let zones = match zones::Zones::load(player.clone(), cfg_path) {
+        Ok(zones) => zones,
+        Err(err) => {
+            eprintln!("E: {}", err);
+            std::process::exit(1);
+        }
+    };
+    zones.spawn_sync();
+    health::spawn_notifier(player.clone());
+    let metrics = player::lock(&player).metrics().clone();
+    let http_addr = matches
+        .opt_str("l")
+        .unwrap_or_else(|| "0.0.0.0:8080".to_string());
 
     HttpServer::new(move || {
+        let metrics = metrics.clone();
         App::new()
             .data(http::AppState {
                 player: player.clone(),
+                webhooks: webhooks.clone(),
+                recorder: recorder.clone(),
+                scheduler: scheduler.clone(),
+                zones: zones.clone(),
+                restream: restream.clone(),
+                library: library.clone(),
+                podcasts: podcasts.clone(),
+            })
+            .wrap_fn(move |req, srv| {
+                let metrics = metrics.clone();
+                let route = metrics::route_label(req.path());
+                let method = req.method().to_string();
+                let started = Instant::now();
+                srv.call(req).map(move |res| {
+                    metrics.request(route, &method, res.status().as_u16(), started.elapsed());
+                    res
+                })
             })
             .route("/playlist", web::get().to(http::get_playlist))
-            .route("/stream", web::post().to(http::post_stream))
+            .route(
+                "/stream",
+                web::post().data(http::json_config()).to(http::post_stream),
+            )
             .route("/stream", web::get().to(http::get_stream))
-            .route("/stream/{id}", web::delete().to(http::delete_stream))
-            .route("/stream/{id}", web::put().to(http::put_play))
+            .route(
+                "/stream/{id}",
+                web::delete()
+                    .data(http::path_config())
+                    .to(http::delete_stream),
+            )
+            .route(
+                "/stream/{id}",
+                web::put().data(http::path_config()).to(http::put_play),
+            )
+            .route(
+                "/stream/{id}/scrobble",
+                web::put()
+                    .data(http::path_config())
+                    .data(http::json_config())
+                    .to(http::put_scrobble),
+            )
+            .route(
+                "/stream/{id}/gain",
+                web::put()
+                    .data(http::path_config())
+                    .data(http::json_config())
+                    .to(http::put_gain),
+            )
+            .route(
+                "/stream/{id}/options",
+                web::put()
+                    .data(http::path_config())
+                    .data(http::json_config())
+                    .to(http::put_stream_options),
+            )
+            .route(
+                "/audio/normalization",
+                web::get().to(http::get_normalization),
+            )
+            .route(
+                "/audio/normalization",
+                web::put()
+                    .data(http::json_config())
+                    .to(http::put_normalization),
+            )
+            .route(
+                "/stream/{id}/eq",
+                web::put()
+                    .data(http::path_config())
+                    .data(http::json_config())
+                    .to(http::put_stream_eq),
+            )
+            .route("/audio/eq", web::get().to(http::get_eq))
+            .route(
+                "/audio/eq",
+                web::put().data(http::json_config()).to(http::put_eq),
+            )
+            .route("/audio/eq/presets", web::get().to(http::get_eq_presets))
+            .route(
+                "/audio/eq/presets/{name}",
+                web::put().data(http::json_config()).to(http::put_eq_preset),
+            )
+            .route(
+                "/audio/eq/presets/{name}",
+                web::delete().to(http::delete_eq_preset),
+            )
+            .route("/audio/devices", web::get().to(http::get_audio_devices))
+            .route(
+                "/audio/device",
+                web::put()
+                    .data(http::json_config())
+                    .to(http::put_audio_device),
+            )
             .route("/now_playing", web::get().to(http::get_now_playing))
+            .route("/pause", web::put().to(http::put_pause))
+            .route("/resume", web::put().to(http::put_resume))
+            .route(
+                "/seek",
+                web::put().data(http::json_config()).to(http::put_seek),
+            )
+            .route("/live", web::put().to(http::put_live))
+            .route("/timeshift", web::get().to(http::get_timeshift))
+            .route("/stop", web::put().to(http::put_stop))
+            .route("/volume", web::get().to(http::get_volume))
+            .route(
+                "/volume",
+                web::put().data(http::json_config()).to(http::put_volume),
+            )
+            .route("/listen", web::get().to_async(http::get_listen))
+            .route("/library", web::get().to(http::get_library))
+            .route("/library/search", web::get().to(http::get_library_search))
+            .route("/library/scan", web::get().to(http::get_library_scan))
+            .route("/library/scan", web::post().to(http::post_library_scan))
+            .route(
+                "/library/play",
+                web::put()
+                    .data(http::json_config())
+                    .to(http::put_library_play),
+            )
+            .route("/podcasts", web::get().to(http::get_podcasts))
+            .route(
+                "/podcasts",
+                web::post().data(http::json_config()).to(http::post_podcast),
+            )
+            .route(
+                "/podcasts/{id}",
+                web::get().data(http::path_config()).to(http::get_podcast),
+            )
+            .route(
+                "/podcasts/{id}",
+                web::delete()
+                    .data(http::path_config())
+                    .to(http::delete_podcast),
+            )
+            .route(
+                "/podcasts/{id}/refresh",
+                web::post()
+                    .data(http::path_config())
+                    .to(http::post_podcast_refresh),
+            )
+            .route(
+                "/podcasts/{id}/download",
+                web::put()
+                    .data(http::path_config())
+                    .data(http::json_config())
+                    .to(http::put_podcast_download),
+            )
+            .route(
+                "/podcasts/{id}/episodes/{episode}",
+                web::put()
+                    .data(http::path_config())
+                    .to(http::put_episode_play),
+            )
+            .route(
+                "/podcasts/{id}/episodes/{episode}/played",
+                web::put()
+                    .data(http::path_config())
+                    .data(http::json_config())
+                    .to(http::put_episode_played),
+            )
+            .route("/zones", web::get().to(http::get_zones))
+            .route("/zones/{zone}/stream", web::get().to(http::get_stream))
+            .route(
+                "/zones/{zone}/stream/{id}",
+                web::put().data(http::path_config()).to(http::put_zone_play),
+            )
+            .route(
+                "/zones/{zone}/now_playing",
+                web::get().to(http::get_now_playing),
+            )
+            .route(
+                "/zones/{zone}/library/play",
+                web::put()
+                    .data(http::json_config())
+                    .to(http::put_library_play),
+            )
+            .route("/zones/{zone}/stop", web::put().to(http::put_stop))
+            .route("/zones/{zone}/pause", web::put().to(http::put_pause))
+            .route("/zones/{zone}/resume", web::put().to(http::put_resume))
+            .route(
+                "/zones/{zone}/seek",
+                web::put().data(http::json_config()).to(http::put_seek),
+            )
+            .route("/zones/{zone}/live", web::put().to(http::put_live))
+            .route(
+                "/zones/{zone}/timeshift",
+                web::get().to(http::get_timeshift),
+            )
+            .route("/zones/{zone}/volume", web::get().to(http::get_volume))
+            .route(
+                "/zones/{zone}/volume",
+                web::put().data(http::json_config()).to(http::put_volume),
+            )
+            .route(
+                "/zones/{zone}/audio/devices",
+                web::get().to(http::get_audio_devices),
+            )
+            .route(
+                "/zones/{zone}/audio/device",
+                web::put()
+                    .data(http::json_config())
+                    .to(http::put_audio_device),
+            )
+            .route("/webhooks/test", web::post().to(http::post_webhook_test))
+            .route("/metrics", web::get().to(http::get_metrics))
+            .route("/health", web::get().to(http::get_health))
+            .route(
+                "/record",
+                web::post().data(http::json_config()).to(http::post_record),
+            )
+            .route("/record", web::get().to(http::get_record))
+            .route("/record", web::delete().to(http::delete_record))
+            .route("/recordings", web::get().to(http::get_recordings))
+            .route("/schedule", web::get().to(http::get_schedule))
+            .route(
+                "/schedule",
+                web::post()
+                    .data(http::json_config())
+                    .to(http::post_schedule),
+            )
+            .route(
+                "/schedule/quota",
+                web::put().data(http::json_config()).to(http::put_quota),
+            )
+            .route(
+                "/schedule/{id}",
+                web::put()
+                    .data(http::path_config())
+                    .data(http::json_config())
+                    .to(http::put_schedule),
+            )
+            .route(
+                "/schedule/{id}",
+                web::delete()
+                    .data(http::path_config())
+                    .to(http::delete_schedule),
+            )
+            .route("/recordings/{name}", web::get().to(http::get_recording))
+            .route(
+                "/recordings/{name}",
+                web::delete().to(http::delete_recording),
+            )
             .service(actix_files::Files::new("/", "web").index_file("index.html"))
     })
     .workers(1)
-    .bind("0.0.0.0:8080")
+    .bind(http_addr.as_str())
     .unwrap()
     .run()
     .unwrap();
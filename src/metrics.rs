@@ -0,0 +1,533 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::mpv_simple::MpvError;
+use crate::player::{lock, PlaybackState, Stream};
+
+/**
+ * Upper bounds in seconds of the HTTP latency histogram buckets.
+ */
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/**
+ * Patterns of the API routes as registered in main.rs, in the same order as
+ * the router tries them. Everything else is served by the static files
+ * service.
+ */
+const ROUTES: [&str; 55] = [
+    "/playlist",
+    "/stream",
+    "/stream/{id}",
+    "/stream/{id}/scrobble",
+    "/stream/{id}/gain",
+    "/stream/{id}/options",
+    "/audio/normalization",
+    "/stream/{id}/eq",
+    "/audio/eq",
+    "/audio/eq/presets",
+    "/audio/eq/presets/{name}",
+    "/audio/devices",
+    "/audio/device",
+    "/now_playing",
+    "/pause",
+    "/resume",
+    "/seek",
+    "/live",
+    "/timeshift",
+    "/stop",
+    "/volume",
+    "/listen",
+    "/library",
+    "/library/search",
+    "/library/scan",
+    "/library/play",
+    "/podcasts",
+    "/podcasts/{id}",
+    "/podcasts/{id}/refresh",
+    "/podcasts/{id}/download",
+    "/podcasts/{id}/episodes/{episode}",
+    "/podcasts/{id}/episodes/{episode}/played",
+    "/zones",
+    "/zones/{zone}/stream",
+    "/zones/{zone}/stream/{id}",
+    "/zones/{zone}/now_playing",
+    "/zones/{zone}/library/play",
+    "/zones/{zone}/stop",
+    "/zones/{zone}/pause",
+    "/zones/{zone}/resume",
+    "/zones/{zone}/seek",
+    "/zones/{zone}/live",
+    "/zones/{zone}/timeshift",
+    "/zones/{zone}/volume",
+    "/zones/{zone}/audio/devices",
+    "/zones/{zone}/audio/device",
+    "/webhooks/test",
+    "/metrics",
+    "/health",
+    "/record",
+    "/recordings",
+    "/schedule",
+    "/schedule/quota",
+    "/schedule/{id}",
+    "/recordings/{name}",
+];
+
+#[derive(Default)]
+struct RequestStats {
+    statuses: BTreeMap<u16, u64>,
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+struct Listening {
+    current: Option<(usize, Instant)>,
+    seconds: BTreeMap<usize, f64>,
+}
+
+/**
+ * Counters shared by the player, its event thread and the HTTP server,
+ * rendered in the Prometheus text format by `Metrics::render`.
+ */
+pub struct Metrics {
+    started: Instant,
+    backend_restarts: AtomicU64,
+    reconnects: AtomicU64,
+    title_changes: AtomicU64,
+    /**
+     * Bits of the `f64` number of seconds buffered by the demuxer.
+     */
+    buffered: AtomicU64,
+    /**
+     * Bits of the `f64` stream times of the playback position and of the end
+     * of the demuxer cache.
+     */
+    playback_time: AtomicU64,
+    cache_time: AtomicU64,
+    /**
+     * Last time the playback position moved, or playback was (re)started.
+     */
+    progressed: Mutex<Instant>,
+    errors: Mutex<BTreeMap<String, u64>>,
+    requests: Mutex<BTreeMap<(String, String), RequestStats>>,
+    listening: Mutex<Listening>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            started: Instant::now(),
+            backend_restarts: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            title_changes: AtomicU64::new(0),
+            buffered: AtomicU64::new(0),
+            playback_time: AtomicU64::new(0),
+            cache_time: AtomicU64::new(0),
+            progressed: Mutex::new(Instant::now()),
+            errors: Mutex::new(BTreeMap::new()),
+            requests: Mutex::new(BTreeMap::new()),
+            listening: Mutex::new(Listening::default()),
+        }
+    }
+}
+
+fn matches(pattern: &str, segments: &[&str]) -> bool {
+    let parts: Vec<&str> = pattern.trim_start_matches('/').split('/').collect();
+    parts.len() == segments.len()
+        && parts.iter().zip(segments).all(|(part, segment)| {
+            part == segment || (part.starts_with('{') && !segment.is_empty())
+        })
+}
+
+/**
+ * Maps a request path onto the route it was served by, so IDs and static file
+ * names don't each get a time series of their own. Paths below an API route
+ * that match none of them are all counted as `unknown`.
+ */
+pub fn route_label(path: &str) -> String {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    if let Some(route) = ROUTES.iter().find(|route| matches(route, &segments)) {
+        return route.to_string();
+    }
+    let api = ROUTES
+        .iter()
+        .any(|route| route[1..].split('/').next() == Some(segments[0]));
+    if api {
+        "unknown".to_string()
+    } else {
+        "static".to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Metrics {
+    pub fn backend_restarted(&self) {
+        self.backend_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stream_reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn title_changed(&self) {
+        self.title_changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stream_error(&self, err: MpvError) {
+        *lock(&self.errors).entry(format!("{:?}", err)).or_insert(0) += 1;
+    }
+
+    pub fn set_buffered(&self, seconds: f64) {
+        self.buffered.store(seconds.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn buffered(&self) -> f64 {
+        f64::from_bits(self.buffered.load(Ordering::Relaxed))
+    }
+
+    pub fn set_playback_time(&self, seconds: f64) {
+        self.playback_time
+            .store(seconds.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_cache_time(&self, seconds: f64) {
+        self.cache_time.store(seconds.to_bits(), Ordering::Relaxed);
+    }
+
+    /**
+     * Seconds between the playback position and the newest data received,
+     * which is how far playback lags behind the live stream.
+     */
+    pub fn behind_live(&self) -> f64 {
+        let playback = f64::from_bits(self.playback_time.load(Ordering::Relaxed));
+        let cache = f64::from_bits(self.cache_time.load(Ordering::Relaxed));
+        (cache - playback).max(0.0)
+    }
+
+    pub fn audio_progressed(&self) {
+        *lock(&self.progressed) = Instant::now();
+    }
+
+    pub fn since_progress(&self) -> Duration {
+        lock(&self.progressed).elapsed()
+    }
+
+    /**
+     * Attributes the time since the last call to the station that was playing
+     * then, `station` is the one playing from now on.
+     */
+    pub fn set_listening(&self, station: Option<usize>) {
+        let mut listening = lock(&self.listening);
+        if let Some((id, since)) = listening.current.take() {
+            *listening.seconds.entry(id).or_insert(0.0) += since.elapsed().as_secs_f64();
+        }
+        listening.current = station.map(|id| (id, Instant::now()));
+    }
+
+    fn listening_seconds(&self) -> BTreeMap<usize, f64> {
+        let listening = lock(&self.listening);
+        let mut seconds = listening.seconds.clone();
+        if let Some((id, since)) = listening.current {
+            *seconds.entry(id).or_insert(0.0) += since.elapsed().as_secs_f64();
+        }
+        seconds
+    }
+
+    pub fn request(&self, route: String, method: &str, status: u16, latency: Duration) {
+        let latency = latency.as_secs_f64();
+        let mut requests = lock(&self.requests);
+        let stats = requests.entry((route, method.to_string())).or_default();
+        *stats.statuses.entry(status).or_insert(0) += 1;
+        for (bucket, bound) in stats.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if latency <= *bound {
+                *bucket += 1;
+            }
+        }
+        stats.count += 1;
+        stats.sum += latency;
+    }
+
+    /**
+     * Renders all metrics in the Prometheus text exposition format. The state
+     * that is owned by the player is passed in by the caller.
+     */
+    pub fn render(
+        &self,
+        state: PlaybackState,
+        current: Option<&Stream>,
+        streams: &[Stream],
+    ) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "radio_playback_state",
+            "gauge",
+            "Current playback state.",
+        );
+        for (name, value) in &[
+            ("playing", PlaybackState::Playing),
+            ("paused", PlaybackState::Paused),
+            ("stopped", PlaybackState::Stopped),
+        ] {
+            let _ = writeln!(
+                out,
+                "radio_playback_state{{state=\"{}\"}} {}",
+                name,
+                (state == *value) as u8
+            );
+        }
+
+        header(
+            &mut out,
+            "radio_current_station",
+            "gauge",
+            "ID of the selected station.",
+        );
+        if let Some(stream) = current {
+            let _ = writeln!(out, "radio_current_station {}", stream.id);
+        }
+
+        header(
+            &mut out,
+            "radio_uptime_seconds",
+            "gauge",
+            "Seconds since the player started.",
+        );
+        let _ = writeln!(
+            out,
+            "radio_uptime_seconds {}",
+            self.started.elapsed().as_secs_f64()
+        );
+
+        header(
+            &mut out,
+            "radio_backend_restarts_total",
+            "counter",
+            "Number of times the MPV context was recreated.",
+        );
+        let _ = writeln!(
+            out,
+            "radio_backend_restarts_total {}",
+            self.backend_restarts.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "radio_reconnects_total",
+            "counter",
+            "Number of times the current stream was reloaded after it ended.",
+        );
+        let _ = writeln!(
+            out,
+            "radio_reconnects_total {}",
+            self.reconnects.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "radio_stream_errors_total",
+            "counter",
+            "MPV errors by kind.",
+        );
+        for (kind, count) in lock(&self.errors).iter() {
+            let _ = writeln!(
+                out,
+                "radio_stream_errors_total{{kind=\"{}\"}} {}",
+                kind, count
+            );
+        }
+
+        header(
+            &mut out,
+            "radio_buffered_seconds",
+            "gauge",
+            "Seconds of audio buffered ahead.",
+        );
+        let _ = writeln!(out, "radio_buffered_seconds {}", self.buffered());
+
+        header(
+            &mut out,
+            "radio_behind_live_seconds",
+            "gauge",
+            "Seconds playback lags behind the live stream.",
+        );
+        let _ = writeln!(out, "radio_behind_live_seconds {}", self.behind_live());
+
+        header(
+            &mut out,
+            "radio_title_changes_total",
+            "counter",
+            "Number of stream title changes.",
+        );
+        let _ = writeln!(
+            out,
+            "radio_title_changes_total {}",
+            self.title_changes.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "radio_listening_seconds_total",
+            "counter",
+            "Seconds spent playing each station.",
+        );
+        for (id, seconds) in self.listening_seconds() {
+            let name = streams
+                .iter()
+                .find(|stream| stream.id == id)
+                .map_or("", |stream| stream.name.as_str());
+            let _ = writeln!(
+                out,
+                "radio_listening_seconds_total{{station=\"{}\",name=\"{}\"}} {}",
+                id,
+                escape(name),
+                seconds
+            );
+        }
+
+        let requests = lock(&self.requests);
+        header(
+            &mut out,
+            "radio_http_requests_total",
+            "counter",
+            "HTTP requests by route, method and status.",
+        );
+        for ((route, method), stats) in requests.iter() {
+            for (status, count) in &stats.statuses {
+                let _ = writeln!(
+                    out,
+                    "radio_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                    escape(route),
+                    method,
+                    status,
+                    count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "radio_http_request_duration_seconds",
+            "histogram",
+            "HTTP request latencies by route and method.",
+        );
+        for ((route, method), stats) in requests.iter() {
+            let labels = format!("route=\"{}\",method=\"{}\"", escape(route), method);
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.buckets.iter()) {
+                let _ = writeln!(
+                    out,
+                    "radio_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "radio_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, stats.count
+            );
+            let _ = writeln!(
+                out,
+                "radio_http_request_duration_seconds_sum{{{}}} {}",
+                labels, stats.sum
+            );
+            let _ = writeln!(
+                out,
+                "radio_http_request_duration_seconds_count{{{}}} {}",
+                labels, stats.count
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_route_label() {
+        assert_eq!(route_label("/stream/12"), "/stream/{id}");
+        assert_eq!(route_label("/stream/3/scrobble"), "/stream/{id}/scrobble");
+        assert_eq!(route_label("/playlist"), "/playlist");
+        assert_eq!(route_label("/recordings/show.mka"), "/recordings/{name}");
+        assert_eq!(route_label("/zones"), "/zones");
+        assert_eq!(
+            route_label("/zones/kitchen/stream/4"),
+            "/zones/{zone}/stream/{id}"
+        );
+        assert_eq!(route_label("/library/search"), "/library/search");
+        assert_eq!(
+            route_label("/zones/kitchen/library/play"),
+            "/zones/{zone}/library/play"
+        );
+        assert_eq!(
+            route_label("/podcasts/2/episodes/31/played"),
+            "/podcasts/{id}/episodes/{episode}/played"
+        );
+        assert_eq!(route_label("/stream/3/unknown"), "unknown");
+        assert_eq!(route_label("/zones/kitchen/anything"), "unknown");
+        assert_eq!(route_label("/playlist/xyz"), "unknown");
+        assert_eq!(route_label("/schedule/quota"), "/schedule/quota");
+        assert_eq!(route_label("/index.html"), "static");
+        assert_eq!(route_label("/"), "static");
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.stream_error(MpvError::LoadingFailed);
+        metrics.stream_error(MpvError::LoadingFailed);
+        metrics.title_changed();
+        metrics.backend_restarted();
+        metrics.stream_reconnected();
+        metrics.set_buffered(4.5);
+        metrics.set_playback_time(10.0);
+        metrics.set_cache_time(40.0);
+        metrics.set_listening(Some(2));
+        metrics.set_listening(None);
+        metrics.request("/stream".to_string(), "GET", 200, Duration::from_millis(20));
+
+        let streams = vec![Stream::new(2, "Jazz \"FM\"".to_string(), String::new())];
+        let txt = metrics.render(PlaybackState::Playing, Some(&streams[0]), &streams);
+
+        assert!(txt.contains("radio_playback_state{state=\"playing\"} 1\n"));
+        assert!(txt.contains("radio_playback_state{state=\"stopped\"} 0\n"));
+        assert!(txt.contains("radio_current_station 2\n"));
+        assert!(txt.contains("radio_stream_errors_total{kind=\"LoadingFailed\"} 2\n"));
+        assert!(txt.contains("radio_title_changes_total 1\n"));
+        assert!(txt.contains("radio_backend_restarts_total 1\n"));
+        assert!(txt.contains("radio_reconnects_total 1\n"));
+        assert!(txt.contains("radio_buffered_seconds 4.5\n"));
+        assert!(txt.contains("radio_behind_live_seconds 30\n"));
+        assert!(
+            txt.contains("radio_listening_seconds_total{station=\"2\",name=\"Jazz \\\"FM\\\"\"}")
+        );
+        assert!(txt.contains(
+            "radio_http_requests_total{route=\"/stream\",method=\"GET\",status=\"200\"} 1\n"
+        ));
+        assert!(txt.contains(
+            "radio_http_request_duration_seconds_bucket{route=\"/stream\",method=\"GET\",le=\"0.01\"} 0\n"
+        ));
+        assert!(txt.contains(
+            "radio_http_request_duration_seconds_bucket{route=\"/stream\",method=\"GET\",le=\"0.025\"} 1\n"
+        ));
+    }
+}
@@ -0,0 +1,565 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::events::PlayerEvent;
+use crate::player::{lock, PlaybackState, Player, PlayerError, Stream};
+
+const GREETING: &str = "OK MPD 0.21.0\n";
+
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+const ACK_ERROR_SYSTEM: u32 = 52;
+
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "currentsong",
+    "delete",
+    "deleteid",
+    "idle",
+    "listplaylists",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistinfo",
+    "previous",
+    "setvol",
+    "status",
+    "stop",
+    "tagtypes",
+    "urlhandlers",
+];
+
+/**
+ * Incremented whenever the station list changes, reported as `playlist` in
+ * the status so clients know when to refresh the queue.
+ */
+static PLAYLIST_VERSION: AtomicUsize = AtomicUsize::new(1);
+
+enum Input {
+    Line(String),
+    Event(PlayerEvent),
+    Closed,
+}
+
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+impl From<PlayerError> for Ack {
+    fn from(err: PlayerError) -> Self {
+        let code = match err {
+            PlayerError::NotFound(_) | PlayerError::UnknownStream(_) => ACK_ERROR_NO_EXIST,
+            PlayerError::InvalidUrl(_) | PlayerError::InvalidGain(_) => ACK_ERROR_ARG,
+            _ => ACK_ERROR_SYSTEM,
+        };
+        Ack {
+            code,
+            message: err.to_string(),
+        }
+    }
+}
+
+fn ack(code: u32, message: &str) -> Ack {
+    Ack {
+        code,
+        message: message.to_string(),
+    }
+}
+
+fn subsystem(event: &PlayerEvent) -> Option<&'static str> {
+    match event {
+        PlayerEvent::TitleChanged(_)
+        | PlayerEvent::StreamChanged(_)
+        | PlayerEvent::PlaybackChanged(_) => Some("player"),
+        PlayerEvent::VolumeChanged(_) => Some("mixer"),
+        PlayerEvent::PlaylistChanged => Some("playlist"),
+        // Clients ask for the elapsed time themselves.
+        PlayerEvent::PositionChanged(..) | PlayerEvent::FileStarted(_) => None,
+    }
+}
+
+/**
+ * Splits a command line into the command and its arguments. Arguments may be
+ * enclosed in double quotes, in which case backslash escapes are honored.
+ */
+pub fn parse_command(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line
+        .trim_end_matches(|c| c == '\r' || c == '\n')
+        .chars()
+        .peekable();
+
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        let mut arg = String::new();
+        match chars.peek() {
+            None => break,
+            Some('"') => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => arg.push(c),
+                            None => return Err("Unterminated escape".to_string()),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err("Missing closing quote".to_string()),
+                    }
+                }
+                if chars.peek().map_or(false, |c| !c.is_whitespace()) {
+                    return Err("Space expected after closing quote".to_string());
+                }
+            }
+            Some(_) => {
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    arg.push(*c);
+                    chars.next();
+                }
+            }
+        }
+        args.push(arg);
+    }
+
+    Ok(args)
+}
+
+fn parse_arg<T: std::str::FromStr>(args: &[String], n: usize) -> Result<Option<T>, Ack> {
+    match args.get(n) {
+        None => Ok(None),
+        Some(arg) => arg
+            .parse::<T>()
+            .map(Some)
+            .map_err(|_| ack(ACK_ERROR_ARG, &format!("Invalid argument: {}", arg))),
+    }
+}
+
+fn required_arg<T: std::str::FromStr>(args: &[String], n: usize) -> Result<T, Ack> {
+    parse_arg(args, n)?.ok_or_else(|| ack(ACK_ERROR_ARG, "Missing argument"))
+}
+
+fn write_song(out: &mut String, pos: usize, stream: &Stream, title: Option<&str>) {
+    out.push_str(&format!("file: {}\n", stream.url));
+    out.push_str(&format!("Name: {}\n", stream.name));
+    if let Some(title) = title {
+        if !title.is_empty() {
+            out.push_str(&format!("Title: {}\n", title));
+        }
+    }
+    out.push_str(&format!("Pos: {}\n", pos));
+    out.push_str(&format!("Id: {}\n", stream.id));
+}
+
+fn station_at(player: &Player, pos: usize) -> Result<usize, Ack> {
+    player
+        .get_playlist()
+        .get(pos)
+        .map(|stream| stream.id)
+        .ok_or_else(|| ack(ACK_ERROR_ARG, "Bad song index"))
+}
+
+/**
+ * Executes a single command, not counting `idle`, `noidle` and command lists
+ * which are handled by the connection itself.
+ */
+fn execute(player: &Mutex<Player>, args: &[String]) -> Result<String, Ack> {
+    let mut out = String::new();
+    let mut player = lock(player);
+
+    match args[0].as_str() {
+        "ping" => (),
+        "status" => {
+            let state = match player.get_state() {
+                PlaybackState::Playing => "play",
+                PlaybackState::Paused => "pause",
+                PlaybackState::Stopped => "stop",
+            };
+            out.push_str(&format!("volume: {}\n", player.get_volume()));
+            out.push_str("repeat: 0\nrandom: 0\nsingle: 0\nconsume: 0\n");
+            out.push_str(&format!(
+                "playlist: {}\n",
+                PLAYLIST_VERSION.load(Ordering::SeqCst)
+            ));
+            out.push_str(&format!(
+                "playlistlength: {}\n",
+                player.get_playlist().len()
+            ));
+            out.push_str(&format!("state: {}\n", state));
+            if let Some(current) = player.get_current() {
+                if let Some(pos) = player
+                    .get_playlist()
+                    .iter()
+                    .position(|stream| stream.id == current.id)
+                {
+                    out.push_str(&format!("song: {}\nsongid: {}\n", pos, current.id));
+                }
+            }
+        }
+        "currentsong" => {
+            if player.get_state() != PlaybackState::Stopped {
+                let title = player.get_now_playing();
+                if let Some(current) = player.get_current() {
+                    let pos = player
+                        .get_playlist()
+                        .iter()
+                        .position(|stream| stream.id == current.id)
+                        .unwrap_or(0);
+                    write_song(&mut out, pos, current, Some(&title));
+                }
+            }
+        }
+        "playlistinfo" => {
+            let only = parse_arg::<usize>(args, 1)?;
+            for (pos, stream) in player.get_playlist().iter().enumerate() {
+                if only.map_or(true, |only| only == pos) {
+                    write_song(&mut out, pos, stream, None);
+                }
+            }
+        }
+        "play" => match parse_arg::<usize>(args, 1)? {
+            Some(pos) => {
+                let id = station_at(&player, pos)?;
+                player.play(id)?;
+            }
+            None if player.get_state() == PlaybackState::Paused => player.pause(false)?,
+            None => {
+                let current = player.cfg.current;
+                player.play(current)?;
+            }
+        },
+        "playid" => match parse_arg::<usize>(args, 1)? {
+            Some(id) => {
+                player.play(id)?;
+            }
+            None => {
+                let current = player.cfg.current;
+                player.play(current)?;
+            }
+        },
+        "stop" => player.stop()?,
+        "pause" => {
+            let pause = match parse_arg::<u8>(args, 1)? {
+                Some(flag) => flag != 0,
+                None => player.get_state() == PlaybackState::Playing,
+            };
+            player.pause(pause)?;
+        }
+        "setvol" => {
+            let volume = required_arg::<i32>(args, 1)?;
+            if volume < 0 || volume > 100 {
+                return Err(ack(ACK_ERROR_ARG, "Invalid volume value"));
+            }
+            player.set_volume(volume as u8)?;
+        }
+        "next" => {
+            player.next()?;
+        }
+        "previous" => {
+            player.previous()?;
+        }
+        "add" | "addid" => {
+            let url = required_arg::<String>(args, 1)?;
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(PlayerError::InvalidUrl(url).into());
+            }
+            let stream = player.add(url.clone(), url)?;
+            if args[0] == "addid" {
+                out.push_str(&format!("Id: {}\n", stream.id));
+            }
+        }
+        "delete" => {
+            let pos = required_arg::<usize>(args, 1)?;
+            let id = station_at(&player, pos)?;
+            player.delete(id)?;
+        }
+        "deleteid" => {
+            let id = required_arg::<usize>(args, 1)?;
+            player.delete(id)?;
+        }
+        "outputs" => {
+            out.push_str("outputid: 0\noutputname: mpv\nplugin: mpv\noutputenabled: 1\n");
+        }
+        "listplaylists" | "tagtypes" => (),
+        "urlhandlers" => out.push_str("handler: http://\nhandler: https://\n"),
+        "commands" => {
+            for command in COMMANDS {
+                out.push_str(&format!("command: {}\n", command));
+            }
+        }
+        "notcommands" => (),
+        command => {
+            return Err(ack(
+                ACK_ERROR_UNKNOWN,
+                &format!("unknown command \"{}\"", command),
+            ))
+        }
+    }
+
+    Ok(out)
+}
+
+struct Connection {
+    player: Arc<Mutex<Player>>,
+    writer: TcpStream,
+    inputs: Receiver<Input>,
+    pending: BTreeSet<&'static str>,
+}
+
+impl Connection {
+    fn next_line(&mut self) -> Option<String> {
+        loop {
+            match self.inputs.recv() {
+                Ok(Input::Line(line)) => return Some(line),
+                Ok(Input::Event(event)) => {
+                    self.pending.extend(subsystem(&event));
+                }
+                Ok(Input::Closed) | Err(_) => return None,
+            }
+        }
+    }
+
+    /**
+     * Waits until one of the requested subsystems changed or the client sends
+     * `noidle`. Returns `false` if the connection should be closed.
+     */
+    fn idle(&mut self, filter: &[String]) -> io::Result<bool> {
+        loop {
+            let changed: Vec<&'static str> = self
+                .pending
+                .iter()
+                .cloned()
+                .filter(|name| filter.is_empty() || filter.iter().any(|f| f == name))
+                .collect();
+            if !changed.is_empty() {
+                let mut out = String::new();
+                for name in changed {
+                    self.pending.remove(name);
+                    out.push_str(&format!("changed: {}\n", name));
+                }
+                out.push_str("OK\n");
+                self.writer.write_all(out.as_bytes())?;
+                return Ok(true);
+            }
+
+            match self.inputs.recv() {
+                Ok(Input::Event(event)) => {
+                    self.pending.extend(subsystem(&event));
+                }
+                Ok(Input::Line(ref line)) if line.trim() == "noidle" => {
+                    self.writer.write_all(b"OK\n")?;
+                    return Ok(true);
+                }
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    fn respond(
+        &mut self,
+        result: Result<String, Ack>,
+        index: usize,
+        command: &str,
+    ) -> io::Result<bool> {
+        match result {
+            Ok(out) => {
+                self.writer.write_all(out.as_bytes())?;
+                Ok(true)
+            }
+            Err(err) => {
+                let line = format!(
+                    "ACK [{}@{}] {{{}}} {}\n",
+                    err.code, index, command, err.message
+                );
+                self.writer.write_all(line.as_bytes())?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn run_list(&mut self, list_ok: bool) -> io::Result<bool> {
+        let mut commands = Vec::new();
+        loop {
+            let line = match self.next_line() {
+                Some(line) => line,
+                None => return Ok(false),
+            };
+            if line.trim() == "command_list_end" {
+                break;
+            }
+            commands.push(line);
+        }
+
+        for (index, line) in commands.iter().enumerate() {
+            let args = match parse_command(line) {
+                Ok(ref args) if args.is_empty() => continue,
+                Ok(args) => args,
+                Err(err) => {
+                    self.respond(Err(ack(ACK_ERROR_ARG, &err)), index, "")?;
+                    return Ok(true);
+                }
+            };
+            let result = execute(&self.player, &args);
+            if !self.respond(result, index, &args[0])? {
+                return Ok(true);
+            }
+            if list_ok {
+                self.writer.write_all(b"list_OK\n")?;
+            }
+        }
+        self.writer.write_all(b"OK\n")?;
+        Ok(true)
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.writer.write_all(GREETING.as_bytes())?;
+
+        while let Some(line) = self.next_line() {
+            let args = match parse_command(&line) {
+                Ok(ref args) if args.is_empty() => continue,
+                Ok(args) => args,
+                Err(err) => {
+                    self.respond(Err(ack(ACK_ERROR_ARG, &err)), 0, "")?;
+                    continue;
+                }
+            };
+
+            let keep_going = match args[0].as_str() {
+                "close" => false,
+                "idle" => self.idle(&args[1..])?,
+                "noidle" => true,
+                "command_list_begin" => self.run_list(false)?,
+                "command_list_ok_begin" => self.run_list(true)?,
+                command => {
+                    let command = command.to_string();
+                    let result = execute(&self.player, &args).map(|mut out| {
+                        out.push_str("OK\n");
+                        out
+                    });
+                    self.respond(result, 0, &command)?;
+                    true
+                }
+            };
+            if !keep_going {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn handle_client(player: Arc<Mutex<Player>>, stream: TcpStream) -> io::Result<()> {
+    let (tx, rx) = channel();
+
+    let event_tx = tx.clone();
+    lock(&player)
+        .listeners()
+        .add(move |event| event_tx.send(Input::Event(event.clone())).is_ok());
+
+    let reader = BufReader::new(stream.try_clone()?);
+    thread::spawn(move || {
+        for line in reader.lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(Input::Line(line)).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        let _ = tx.send(Input::Closed);
+    });
+
+    let mut connection = Connection {
+        player,
+        writer: stream,
+        inputs: rx,
+        pending: BTreeSet::new(),
+    };
+    connection.run()
+}
+
+/**
+ * Starts a server speaking the subset of the MPD protocol needed by common
+ * clients on `addr`, each client is served on its own thread. Stations are
+ * exposed as the MPD queue: the position of a song is the position of the
+ * station in the playlist and its song ID is the station ID.
+ */
+pub fn spawn<A: ToSocketAddrs>(player: Arc<Mutex<Player>>, addr: A) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+
+    lock(&player).listeners().add(|event| {
+        if let PlayerEvent::PlaylistChanged = event {
+            PLAYLIST_VERSION.fetch_add(1, Ordering::SeqCst);
+        }
+        true
+    });
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let player = player.clone();
+                    thread::spawn(move || {
+                        let _ = handle_client(player, stream);
+                    });
+                }
+                Err(err) => eprintln!("W: MPD connection failed: {}", err),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("status\n").unwrap(), vec!["status"]);
+        assert_eq!(
+            parse_command("  setvol   50 ").unwrap(),
+            vec!["setvol", "50"]
+        );
+        assert_eq!(
+            parse_command("add \"http://example.org/a b\"").unwrap(),
+            vec!["add", "http://example.org/a b"]
+        );
+        assert_eq!(
+            parse_command(r#"add "quote \" and \\ backslash""#).unwrap(),
+            vec!["add", r#"quote " and \ backslash"#]
+        );
+        assert!(parse_command("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_command_errors() {
+        assert!(parse_command("add \"unterminated").is_err());
+        assert!(parse_command("add \"a\"b").is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+use zbus::{dbus_interface, fdo};
+
+use crate::events::PlayerEvent;
+use crate::player::{lock, PlaybackState, Player, PlayerError};
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.radio";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+fn to_fdo(err: PlayerError) -> fdo::Error {
+    fdo::Error::Failed(err.to_string())
+}
+
+/**
+ * The `org.mpris.MediaPlayer2` interface. The radio has no window to raise and
+ * is not supposed to be quit by desktop widgets.
+ */
+struct Root;
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[dbus_interface(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn identity(&self) -> &str {
+        "Radio"
+    }
+
+    #[dbus_interface(property)]
+    fn supported_uri_schemes(&self) -> Vec<&str> {
+        vec!["http", "https"]
+    }
+
+    #[dbus_interface(property)]
+    fn supported_mime_types(&self) -> Vec<&str> {
+        Vec::new()
+    }
+}
+
+/**
+ * The `org.mpris.MediaPlayer2.Player` interface.
+ */
+struct MprisPlayer {
+    player: Arc<Mutex<Player>>,
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MprisPlayer {
+    fn next(&self) -> fdo::Result<()> {
+        lock(&self.player).next().map(|_| ()).map_err(to_fdo)
+    }
+
+    fn previous(&self) -> fdo::Result<()> {
+        lock(&self.player).previous().map(|_| ()).map_err(to_fdo)
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        lock(&self.player).pause(true).map_err(to_fdo)
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        let mut player = lock(&self.player);
+        match player.get_state() {
+            PlaybackState::Playing => player.pause(true),
+            PlaybackState::Paused => player.pause(false),
+            PlaybackState::Stopped => {
+                let current = player.cfg.current;
+                player.play(current).map(|_| ())
+            }
+        }
+        .map_err(to_fdo)
+    }
+
+    fn stop(&self) -> fdo::Result<()> {
+        lock(&self.player).stop().map_err(to_fdo)
+    }
+
+    fn play(&self) -> fdo::Result<()> {
+        let mut player = lock(&self.player);
+        match player.get_state() {
+            PlaybackState::Playing => Ok(()),
+            PlaybackState::Paused => player.pause(false),
+            PlaybackState::Stopped => {
+                let current = player.cfg.current;
+                player.play(current).map(|_| ())
+            }
+        }
+        .map_err(to_fdo)
+    }
+
+    fn seek(&self, _offset: i64) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "Streams cannot be seeked".to_string(),
+        ))
+    }
+
+    fn set_position(&self, _track_id: ObjectPath<'_>, _position: i64) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "Streams cannot be seeked".to_string(),
+        ))
+    }
+
+    fn open_uri(&self, _uri: &str) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "Add streams through the web interface".to_string(),
+        ))
+    }
+
+    #[dbus_interface(property)]
+    fn playback_status(&self) -> &str {
+        match lock(&self.player).get_state() {
+            PlaybackState::Playing => "Playing",
+            PlaybackState::Paused => "Paused",
+            PlaybackState::Stopped => "Stopped",
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let player = lock(&self.player);
+        let mut metadata = HashMap::new();
+        if let Some(stream) = player.get_current() {
+            let track_id = format!("{}/station/{}", OBJECT_PATH, stream.id);
+            if let Ok(path) = ObjectPath::try_from(track_id) {
+                metadata.insert("mpris:trackid".to_string(), Value::from(path).into());
+            }
+
+            let title = player.get_now_playing();
+            let title = if title.is_empty() {
+                stream.name.to_string()
+            } else {
+                title
+            };
+            metadata.insert("xesam:title".to_string(), Value::from(title).into());
+            metadata.insert(
+                "xesam:album".to_string(),
+                Value::from(stream.name.to_string()).into(),
+            );
+            metadata.insert(
+                "xesam:url".to_string(),
+                Value::from(stream.url.to_string()).into(),
+            );
+        }
+        metadata
+    }
+
+    #[dbus_interface(property)]
+    fn volume(&self) -> f64 {
+        f64::from(lock(&self.player).get_volume()) / 100.0
+    }
+
+    #[dbus_interface(property)]
+    fn set_volume(&mut self, volume: f64) {
+        let volume = (volume.max(0.0).min(1.0) * 100.0).round() as u8;
+        if let Err(err) = lock(&self.player).set_volume(volume) {
+            eprintln!("W: MPRIS volume change failed: {}", err);
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn position(&self) -> i64 {
+        0
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_next(&self) -> bool {
+        !lock(&self.player).get_playlist().is_empty()
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_previous(&self) -> bool {
+        !lock(&self.player).get_playlist().is_empty()
+    }
+
+    #[dbus_interface(property)]
+    fn can_play(&self) -> bool {
+        lock(&self.player).get_current().is_some()
+    }
+
+    #[dbus_interface(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/**
+ * Emits `PropertiesChanged` for the properties affected by `event`.
+ */
+fn notify(connection: &Connection, event: &PlayerEvent) -> zbus::Result<()> {
+    let iface_ref = connection
+        .object_server()
+        .interface::<_, MprisPlayer>(OBJECT_PATH)?;
+    let iface = iface_ref.get();
+    let ctxt = iface_ref.signal_context();
+
+    zbus::block_on(async {
+        match event {
+            PlayerEvent::TitleChanged(_) => iface.metadata_changed(ctxt).await,
+            PlayerEvent::StreamChanged(_) => {
+                iface.metadata_changed(ctxt).await?;
+                iface.can_play_changed(ctxt).await
+            }
+            PlayerEvent::PlaybackChanged(_) => iface.playback_status_changed(ctxt).await,
+            PlayerEvent::VolumeChanged(_) => iface.volume_changed(ctxt).await,
+            PlayerEvent::PlaylistChanged => {
+                iface.can_go_next_changed(ctxt).await?;
+                iface.can_go_previous_changed(ctxt).await
+            }
+            // `Position` is not signalled, clients poll it.
+            PlayerEvent::PositionChanged(..) | PlayerEvent::FileStarted(_) => Ok(()),
+        }
+    })
+}
+
+/**
+ * Registers the player on the session bus, or on the bus at `address` if it
+ * is given. Player events are forwarded to a separate thread which emits the
+ * D-Bus signals, the property getters need the player lock which is held
+ * while most events are emitted.
+ */
+pub fn spawn(
+    player: Arc<Mutex<Player>>,
+    address: Option<&str>,
+) -> zbus::Result<(Connection, JoinHandle<()>)> {
+    let builder = match address {
+        Some(address) => ConnectionBuilder::address(address)?,
+        None => ConnectionBuilder::session()?,
+    };
+    let connection = builder
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, Root)?
+        .serve_at(
+            OBJECT_PATH,
+            MprisPlayer {
+                player: player.clone(),
+            },
+        )?
+        .build()?;
+
+    let (tx, rx) = channel();
+    lock(&player)
+        .listeners()
+        .add(move |event| tx.send(event.clone()).is_ok());
+
+    let thread_connection = connection.clone();
+    let signal_thread = thread::spawn(move || {
+        for event in rx {
+            if let Err(err) = notify(&thread_connection, &event) {
+                eprintln!("W: Failed to emit MPRIS signal: {}", err);
+            }
+        }
+    });
+
+    Ok((connection, signal_thread))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+
+    use zbus::blocking::Proxy;
+
+    #[test]
+    fn test_private_bus() {
+        let mut daemon = match Command::new("dbus-daemon")
+            .args(&["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(_) => {
+                println!("dbus-daemon not available, skipping");
+                return;
+            }
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+
+        let player = Arc::new(Mutex::new(Player::default()));
+        let (_server, _thread) = spawn(player.clone(), Some(address.trim())).unwrap();
+
+        let client = ConnectionBuilder::address(address.trim())
+            .unwrap()
+            .build()
+            .unwrap();
+        let proxy = Proxy::new(
+            &client,
+            BUS_NAME,
+            OBJECT_PATH,
+            "org.mpris.MediaPlayer2.Player",
+        )
+        .unwrap();
+
+        assert_eq!(
+            proxy.get_property::<String>("PlaybackStatus").unwrap(),
+            "Stopped"
+        );
+        assert_eq!(proxy.get_property::<f64>("Volume").unwrap(), 1.0);
+        assert_eq!(proxy.get_property::<bool>("CanGoNext").unwrap(), false);
+        assert!(proxy.call_method("Seek", &(10i64)).is_err());
+
+        let _ = daemon.kill();
+    }
+}
@@ -1,8 +1,9 @@
 use std::ffi::{c_void, CStr, CString};
 
 use libc::{c_char, c_double, c_int};
+use serde_json::{Map, Number, Value};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(C)]
 #[allow(dead_code)]
 pub enum MpvError {
@@ -90,6 +91,14 @@ struct CMpvEvent {
     data: *mut c_void,
 }
 
+#[repr(C)]
+struct CMpvEventEndFile {
+    reason: c_int,
+    error: c_int,
+}
+
+const END_FILE_REASON_ERROR: c_int = 4;
+
 #[repr(C)]
 struct CMpvEventProperty {
     name: *const c_char,
@@ -107,7 +116,13 @@ pub enum MpvEvent {
     SetPropertyReply,
     CommandReply,
     StartFile,
-    EndFile,
+    /**
+     * Playback of a file ended, `error` is set if it ended because of a
+     * failure rather than at its end or on request.
+     */
+    EndFile {
+        error: Option<MpvError>,
+    },
     FileLoaded,
     TracksChanged,
     TrackSwitched,
@@ -142,7 +157,15 @@ impl From<CMpvEvent> for MpvEvent {
             CMpvEventId::SetPropertyReply => MpvEvent::SetPropertyReply,
             CMpvEventId::CommandReply => MpvEvent::CommandReply,
             CMpvEventId::StartFile => MpvEvent::StartFile,
-            CMpvEventId::EndFile => MpvEvent::EndFile,
+            CMpvEventId::EndFile => unsafe {
+                let end = event.data as *const CMpvEventEndFile;
+                let error = if !end.is_null() && (*end).reason == END_FILE_REASON_ERROR {
+                    Some(std::mem::transmute::<c_int, MpvError>((*end).error))
+                } else {
+                    None
+                };
+                MpvEvent::EndFile { error }
+            },
             CMpvEventId::FileLoaded => MpvEvent::FileLoaded,
             CMpvEventId::TracksChanged => MpvEvent::TracksChanged,
             CMpvEventId::TrackSwitched => MpvEvent::TrackSwitched,
@@ -179,6 +202,76 @@ impl From<CMpvEvent> for MpvEvent {
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+union CMpvNodeValue {
+    string: *mut c_char,
+    flag: c_int,
+    int64: i64,
+    double: c_double,
+    list: *mut CMpvNodeList,
+    ba: *mut c_void,
+}
+
+#[repr(C)]
+struct CMpvNode {
+    u: CMpvNodeValue,
+    format: MpvFormat,
+}
+
+#[repr(C)]
+struct CMpvNodeList {
+    num: c_int,
+    values: *mut CMpvNode,
+    keys: *mut *mut c_char,
+}
+
+/**
+ * Converts an `mpv_node` into JSON, byte arrays are not supported and become
+ * `null`.
+ */
+unsafe fn node_to_value(node: &CMpvNode) -> Value {
+    match node.format {
+        MpvFormat::String | MpvFormat::OsdString => {
+            Value::String(CStr::from_ptr(node.u.string).to_string_lossy().to_string())
+        }
+        MpvFormat::Flag => Value::Bool(node.u.flag != 0),
+        MpvFormat::Int64 => Value::Number(node.u.int64.into()),
+        MpvFormat::Double => Number::from_f64(node.u.double).map_or(Value::Null, Value::Number),
+        MpvFormat::NodeArray => {
+            let list = &*node.u.list;
+            let values = (0..list.num as isize)
+                .map(|i| node_to_value(&*list.values.offset(i)))
+                .collect();
+            Value::Array(values)
+        }
+        MpvFormat::NodeMap => {
+            let list = &*node.u.list;
+            let mut map = Map::new();
+            for i in 0..list.num as isize {
+                let key = CStr::from_ptr(*list.keys.offset(i))
+                    .to_string_lossy()
+                    .to_string();
+                map.insert(key, node_to_value(&*list.values.offset(i)));
+            }
+            Value::Object(map)
+        }
+        _ => Value::Null,
+    }
+}
+
+/**
+ * Formats options as an MPV key-value list. Values are length-prefixed so
+ * they may contain commas and other separators.
+ */
+pub fn key_value_list(options: &[(&str, &str)]) -> String {
+    options
+        .iter()
+        .map(|(key, value)| format!("{}=%{}%{}", key, value.len(), value))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
 type WakeUpCallback = extern "C" fn(d: *mut c_void);
 
 extern "C" {
@@ -191,6 +284,29 @@ extern "C" {
 
     fn mpv_command(ctx: *mut c_void, args: *const *const c_char) -> MpvError;
 
+    fn mpv_command_node(ctx: *mut c_void, args: *mut CMpvNode, result: *mut CMpvNode) -> MpvError;
+
+    fn mpv_set_option_string(
+        ctx: *mut c_void,
+        name: *const c_char,
+        data: *const c_char,
+    ) -> MpvError;
+
+    fn mpv_set_property_string(
+        ctx: *mut c_void,
+        name: *const c_char,
+        data: *const c_char,
+    ) -> MpvError;
+
+    fn mpv_get_property(
+        ctx: *mut c_void,
+        name: *const c_char,
+        format: MpvFormat,
+        data: *mut c_void,
+    ) -> MpvError;
+
+    fn mpv_free_node_contents(node: *mut CMpvNode);
+
     fn mpv_wait_event(ctx: *mut c_void, timeout: c_double) -> *const CMpvEvent;
 
     fn mpv_observe_property(
@@ -204,6 +320,13 @@ extern "C" {
 
 }
 
+/**
+ * Converts an argument for MPV, strings with a NUL byte cannot be passed.
+ */
+fn c_string(x: &str) -> Result<CString, MpvError> {
+    CString::new(x).map_err(|_| MpvError::InvalidParameter)
+}
+
 pub struct MpvCtx {
     ctx: *mut c_void,
     wakeup_callback: Option<Box<dyn FnMut()>>,
@@ -218,10 +341,10 @@ extern "C" fn wakeup_callback(d: *mut c_void) {
 }
 
 impl<'a> MpvCtx {
-    pub fn create() -> Result<MpvCtx, (&'static str)> {
+    pub fn create() -> Result<MpvCtx, MpvError> {
         let ctx = unsafe { mpv_create() };
         if ctx.is_null() {
-            Err("Intialization of context failed")
+            Err(MpvError::NoMem)
         } else {
             Ok(MpvCtx {
                 ctx,
@@ -242,8 +365,8 @@ impl<'a> MpvCtx {
     pub fn command(&mut self, args: &[&'a str]) -> Result<(), MpvError> {
         let c_args = args
             .iter()
-            .map(|&x| CString::new(x).expect("Failed to convert string slice to C string"))
-            .collect::<Vec<CString>>();
+            .map(|&x| c_string(x))
+            .collect::<Result<Vec<CString>, MpvError>>()?;
 
         let mut c_args_ptrs = c_args
             .iter()
@@ -259,6 +382,109 @@ impl<'a> MpvCtx {
         }
     }
 
+    /**
+     * Runs the command `name` with named arguments, which unlike positional
+     * arguments stay the same across MPV versions.
+     */
+    pub fn command_named(&mut self, name: &str, args: &[(&str, &str)]) -> Result<(), MpvError> {
+        let keys = std::iter::once("name")
+            .chain(args.iter().map(|(key, _)| *key))
+            .map(c_string)
+            .collect::<Result<Vec<CString>, MpvError>>()?;
+        let values = std::iter::once(name)
+            .chain(args.iter().map(|(_, value)| *value))
+            .map(c_string)
+            .collect::<Result<Vec<CString>, MpvError>>()?;
+
+        let mut key_ptrs: Vec<*mut c_char> =
+            keys.iter().map(|x| x.as_ptr() as *mut c_char).collect();
+        let mut nodes: Vec<CMpvNode> = values
+            .iter()
+            .map(|x| CMpvNode {
+                u: CMpvNodeValue {
+                    string: x.as_ptr() as *mut c_char,
+                },
+                format: MpvFormat::String,
+            })
+            .collect();
+        let mut list = CMpvNodeList {
+            num: nodes.len() as c_int,
+            values: nodes.as_mut_ptr(),
+            keys: key_ptrs.as_mut_ptr(),
+        };
+        let mut node = CMpvNode {
+            u: CMpvNodeValue { list: &mut list },
+            format: MpvFormat::NodeMap,
+        };
+        let mut reply = CMpvNode {
+            u: CMpvNodeValue { int64: 0 },
+            format: MpvFormat::None,
+        };
+
+        let result = unsafe { mpv_command_node(self.ctx, &mut node, &mut reply) };
+        if result == MpvError::Success {
+            unsafe { mpv_free_node_contents(&mut reply) };
+            Ok(())
+        } else {
+            Err(result)
+        }
+    }
+
+    /**
+     * Sets an option, most options can only be set before `init` is called.
+     */
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), MpvError> {
+        let name = c_string(name)?;
+        let value = c_string(value)?;
+        let result = unsafe { mpv_set_option_string(self.ctx, name.as_ptr(), value.as_ptr()) };
+        if result == MpvError::Success {
+            Ok(())
+        } else {
+            Err(result)
+        }
+    }
+
+    /**
+     * Sets a property at runtime, the value is parsed like on the command
+     * line.
+     */
+    pub fn set_property(&mut self, name: &str, value: &str) -> Result<(), MpvError> {
+        let name = c_string(name)?;
+        let value = c_string(value)?;
+        let result = unsafe { mpv_set_property_string(self.ctx, name.as_ptr(), value.as_ptr()) };
+        if result == MpvError::Success {
+            Ok(())
+        } else {
+            Err(result)
+        }
+    }
+
+    /**
+     * Reads a property in the node format, which represents structured values
+     * such as lists and maps.
+     */
+    pub fn get_property_node(&mut self, name: &str) -> Result<Value, MpvError> {
+        let name = c_string(name)?;
+        let mut node = CMpvNode {
+            u: CMpvNodeValue { int64: 0 },
+            format: MpvFormat::None,
+        };
+        let result = unsafe {
+            mpv_get_property(
+                self.ctx,
+                name.as_ptr(),
+                MpvFormat::Node,
+                &mut node as *mut CMpvNode as *mut c_void,
+            )
+        };
+        if result != MpvError::Success {
+            return Err(result);
+        }
+        let value = unsafe { node_to_value(&node) };
+        unsafe { mpv_free_node_contents(&mut node) };
+        Ok(value)
+    }
+
     pub fn wait_event(&mut self, timeout: f64) -> Result<MpvEvent, MpvError> {
         let event = unsafe { mpv_wait_event(self.ctx, timeout) };
         if event.is_null() {
@@ -279,7 +505,7 @@ impl<'a> MpvCtx {
         name: &str,
         format: MpvFormat,
     ) -> Result<(), MpvError> {
-        let name = CString::new(name).expect("Failed to convert string slice to C string");
+        let name = c_string(name)?;
         let result =
             unsafe { mpv_observe_property(self.ctx, reply_userdata, name.as_ptr(), format) };
         if result == MpvError::Success {
@@ -307,6 +533,57 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_key_value_list() {
+        assert_eq!(
+            key_value_list(&[
+                ("user-agent", "Radio/1.0"),
+                ("http-header-fields", "A: 1,B: 2")
+            ]),
+            "user-agent=%9%Radio/1.0,http-header-fields=%9%A: 1,B: 2"
+        );
+    }
+
+    #[test]
+    fn test_node_to_value() {
+        let name = CString::new("pulse").unwrap();
+        let key = CString::new("name").unwrap();
+        let mut values = [CMpvNode {
+            u: CMpvNodeValue {
+                string: name.as_ptr() as *mut c_char,
+            },
+            format: MpvFormat::String,
+        }];
+        let mut keys = [key.as_ptr() as *mut c_char];
+        let mut map = CMpvNodeList {
+            num: 1,
+            values: values.as_mut_ptr(),
+            keys: keys.as_mut_ptr(),
+        };
+        let mut entries = [CMpvNode {
+            u: CMpvNodeValue { list: &mut map },
+            format: MpvFormat::NodeMap,
+        }];
+        let mut array = CMpvNodeList {
+            num: 1,
+            values: entries.as_mut_ptr(),
+            keys: std::ptr::null_mut(),
+        };
+        let node = CMpvNode {
+            u: CMpvNodeValue { list: &mut array },
+            format: MpvFormat::NodeArray,
+        };
+
+        let value = unsafe { node_to_value(&node) };
+        assert_eq!(value, serde_json::json!([{ "name": "pulse" }]));
+    }
+
+    #[test]
+    fn test_nul_argument() {
+        assert_eq!(c_string("af").unwrap().as_bytes(), b"af");
+        assert_eq!(c_string("a\0f").unwrap_err(), MpvError::InvalidParameter);
+    }
+
     #[test]
     fn test_wait_event() {
         let mut ctx = MpvCtx::create().expect("Creating context failed");
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::events::PlayerEvent;
+use crate::player::{lock, PlaybackState, Player, PlayerError};
+
+/**
+ * Connection settings, read from `mqtt.json` in the data directory.
+ */
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct MqttCfg {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: String,
+    /**
+     * Prefix of all state and command topics.
+     */
+    pub base_topic: String,
+    /**
+     * Prefix Home Assistant listens on for discovery messages, discovery is
+     * disabled when this is empty.
+     */
+    pub discovery_prefix: String,
+    pub name: String,
+}
+
+impl Default for MqttCfg {
+    fn default() -> Self {
+        MqttCfg {
+            host: "localhost".to_string(),
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: "radio".to_string(),
+            base_topic: "radio".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            name: "Radio".to_string(),
+        }
+    }
+}
+
+/**
+ * Commands accepted on `<base_topic>/cmd/<command>`.
+ */
+#[derive(Debug, PartialEq)]
+enum Command {
+    /**
+     * Plays the station with the ID, or resumes without one.
+     */
+    Play(Option<usize>),
+    /**
+     * Plays the station with the name, as sent by the Home Assistant select.
+     */
+    Station(String),
+    Power(bool),
+    Stop,
+    Pause,
+    Next,
+    Previous,
+    Volume(u8),
+}
+
+enum Outgoing {
+    Connected,
+    Event(PlayerEvent),
+}
+
+pub fn load_config(path: &Path) -> Result<Option<MqttCfg>, String> {
+    match fs::read_to_string(path) {
+        Ok(txt) => serde_json::from_str(&txt)
+            .map(Some)
+            .map_err(|err| format!("{}: {}", path.display(), err)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_command(base_topic: &str, topic: &str, payload: &[u8]) -> Option<Command> {
+    let name = topic.strip_prefix(base_topic)?.strip_prefix("/cmd/")?;
+    let payload = String::from_utf8_lossy(payload).trim().to_string();
+
+    match name {
+        "play" if payload.is_empty() => Some(Command::Play(None)),
+        "play" => payload.parse().ok().map(|id| Command::Play(Some(id))),
+        "station" if !payload.is_empty() => Some(Command::Station(payload)),
+        "power" => match payload.as_str() {
+            "ON" => Some(Command::Power(true)),
+            "OFF" => Some(Command::Power(false)),
+            _ => None,
+        },
+        "stop" => Some(Command::Stop),
+        "pause" => Some(Command::Pause),
+        "next" => Some(Command::Next),
+        "previous" => Some(Command::Previous),
+        "volume" => payload
+            .parse::<f64>()
+            .ok()
+            .map(|volume| Command::Volume(volume.max(0.0).min(100.0).round() as u8)),
+        _ => None,
+    }
+}
+
+fn execute(player: &Mutex<Player>, command: Command) -> Result<(), PlayerError> {
+    let mut player = lock(player);
+    match command {
+        Command::Play(None) | Command::Power(true) => match player.get_state() {
+            PlaybackState::Paused => player.pause(false),
+            _ => {
+                let current = player.cfg.current;
+                player.play(current).map(|_| ())
+            }
+        },
+        Command::Play(Some(id)) => player.play(id).map(|_| ()),
+        Command::Station(station) => {
+            let id = player
+                .get_playlist()
+                .iter()
+                .find(|stream| stream.name == station)
+                .map(|stream| stream.id)
+                .ok_or(PlayerError::UnknownStream(station))?;
+            player.play(id).map(|_| ())
+        }
+        Command::Power(false) | Command::Stop => player.stop(),
+        Command::Pause => player.pause(true),
+        Command::Next => player.next().map(|_| ()),
+        Command::Previous => player.previous().map(|_| ()),
+        Command::Volume(volume) => player.set_volume(volume),
+    }
+}
+
+fn state_topic(cfg: &MqttCfg, name: &str) -> String {
+    format!("{}/state/{}", cfg.base_topic, name)
+}
+
+fn cmd_topic(cfg: &MqttCfg, name: &str) -> String {
+    format!("{}/cmd/{}", cfg.base_topic, name)
+}
+
+fn availability_topic(cfg: &MqttCfg) -> String {
+    format!("{}/available", cfg.base_topic)
+}
+
+/**
+ * Home Assistant has no MQTT media player, so the radio is announced as a
+ * device with a power switch, station select, volume slider, title sensor
+ * and next/previous buttons.
+ */
+fn discovery_messages(cfg: &MqttCfg, stations: &[String]) -> Vec<(String, String)> {
+    if cfg.discovery_prefix.is_empty() {
+        return Vec::new();
+    }
+
+    let device = json!({
+        "identifiers": [format!("radio_{}", cfg.client_id)],
+        "name": cfg.name,
+        "model": "Radio",
+    });
+    let entity = |component: &str, object_id: &str, name: &str, extra: serde_json::Value| {
+        let mut config = json!({
+            "name": format!("{} {}", cfg.name, name),
+            "unique_id": format!("{}_{}", cfg.client_id, object_id),
+            "availability_topic": availability_topic(cfg),
+            "device": device,
+        });
+        if let (Some(config), Some(extra)) = (config.as_object_mut(), extra.as_object()) {
+            for (key, value) in extra {
+                config.insert(key.to_string(), value.clone());
+            }
+        }
+        (
+            format!(
+                "{}/{}/{}/{}/config",
+                cfg.discovery_prefix, component, cfg.client_id, object_id
+            ),
+            config.to_string(),
+        )
+    };
+
+    vec![
+        entity(
+            "switch",
+            "power",
+            "Power",
+            json!({
+                "state_topic": state_topic(cfg, "playing"),
+                "command_topic": cmd_topic(cfg, "power"),
+            }),
+        ),
+        entity(
+            "select",
+            "station",
+            "Station",
+            json!({
+                "state_topic": state_topic(cfg, "station"),
+                "command_topic": cmd_topic(cfg, "station"),
+                "options": stations,
+            }),
+        ),
+        entity(
+            "number",
+            "volume",
+            "Volume",
+            json!({
+                "state_topic": state_topic(cfg, "volume"),
+                "command_topic": cmd_topic(cfg, "volume"),
+                "min": 0,
+                "max": 100,
+                "step": 1,
+            }),
+        ),
+        entity(
+            "sensor",
+            "title",
+            "Title",
+            json!({ "state_topic": state_topic(cfg, "title") }),
+        ),
+        entity(
+            "button",
+            "next",
+            "Next",
+            json!({ "command_topic": cmd_topic(cfg, "next") }),
+        ),
+        entity(
+            "button",
+            "previous",
+            "Previous",
+            json!({ "command_topic": cmd_topic(cfg, "previous") }),
+        ),
+    ]
+}
+
+fn state_messages(cfg: &MqttCfg, player: &Player) -> Vec<(String, String)> {
+    let station = player
+        .get_current()
+        .map_or(String::new(), |stream| stream.name.to_string());
+    let playback = match player.get_state() {
+        PlaybackState::Playing => "playing",
+        PlaybackState::Paused => "paused",
+        PlaybackState::Stopped => "stopped",
+    };
+    let playing = if player.get_state() == PlaybackState::Playing {
+        "ON"
+    } else {
+        "OFF"
+    };
+
+    vec![
+        (state_topic(cfg, "station"), station),
+        (state_topic(cfg, "title"), player.get_now_playing()),
+        (state_topic(cfg, "volume"), player.get_volume().to_string()),
+        (state_topic(cfg, "playing"), playing.to_string()),
+        (state_topic(cfg, "playback"), playback.to_string()),
+    ]
+}
+
+fn publish_loop(
+    cfg: Arc<MqttCfg>,
+    player: Arc<Mutex<Player>>,
+    mut client: Client,
+    rx: Receiver<Outgoing>,
+) {
+    // Retained payloads as last published, unchanged ones are not sent again.
+    let mut published: HashMap<String, String> = HashMap::new();
+    for outgoing in rx {
+        let mut messages = Vec::new();
+        {
+            let player = lock(&player);
+            if let Outgoing::Connected = outgoing {
+                published.clear();
+                messages.push((availability_topic(&cfg), "online".to_string()));
+            }
+            match outgoing {
+                Outgoing::Connected | Outgoing::Event(PlayerEvent::PlaylistChanged) => {
+                    let stations: Vec<String> = player
+                        .get_playlist()
+                        .iter()
+                        .map(|stream| stream.name.to_string())
+                        .collect();
+                    messages.extend(discovery_messages(&cfg, &stations));
+                }
+                _ => (),
+            }
+            messages.extend(state_messages(&cfg, &player));
+        }
+
+        for (topic, payload) in messages {
+            if published.get(&topic) == Some(&payload) {
+                continue;
+            }
+            match client.publish(topic.as_str(), QoS::AtLeastOnce, true, payload.as_str()) {
+                Ok(()) => {
+                    published.insert(topic, payload);
+                }
+                Err(err) => eprintln!("W: MQTT publish failed: {}", err),
+            }
+        }
+    }
+}
+
+/**
+ * Connects to the broker and keeps the connection alive, reconnecting when it
+ * drops. State is published to retained topics whenever the player changes.
+ */
+pub fn spawn(player: Arc<Mutex<Player>>, cfg: MqttCfg) -> JoinHandle<()> {
+    let mut options = MqttOptions::new(cfg.client_id.to_string(), cfg.host.to_string(), cfg.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        availability_topic(&cfg),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(ref username) = cfg.username {
+        options.set_credentials(
+            username.to_string(),
+            cfg.password.clone().unwrap_or_default(),
+        );
+    }
+
+    let (client, mut connection) = Client::new(options, 16);
+    let cfg = Arc::new(cfg);
+
+    let (tx, rx) = channel();
+    let event_tx = tx.clone();
+    lock(&player).listeners().add(move |event| match event {
+        // None of the published state depends on the playback position.
+        PlayerEvent::PositionChanged(..) | PlayerEvent::FileStarted(_) => true,
+        event => event_tx.send(Outgoing::Event(event.clone())).is_ok(),
+    });
+
+    let publish_cfg = cfg.clone();
+    let publish_player = player.clone();
+    let publish_client = client.clone();
+    thread::spawn(move || publish_loop(publish_cfg, publish_player, publish_client, rx));
+
+    thread::spawn(move || {
+        let mut client = client;
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    let topic = format!("{}/cmd/+", cfg.base_topic);
+                    if let Err(err) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                        eprintln!("W: MQTT subscribe failed: {}", err);
+                    }
+                    let _ = tx.send(Outgoing::Connected);
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    match parse_command(&cfg.base_topic, &publish.topic, &publish.payload) {
+                        Some(command) => {
+                            if let Err(err) = execute(&player, command) {
+                                eprintln!("W: MQTT command on {} failed: {}", publish.topic, err);
+                            }
+                        }
+                        None => eprintln!("W: Ignoring MQTT message on {}", publish.topic),
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!("W: MQTT connection error: {}", err);
+                    thread::sleep(Duration::from_secs(5));
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command("radio", "radio/cmd/play", b"3"),
+            Some(Command::Play(Some(3)))
+        );
+        assert_eq!(parse_command("radio", "radio/cmd/play", b"Jazz"), None);
+        assert_eq!(
+            parse_command("radio", "radio/cmd/station", b"104"),
+            Some(Command::Station("104".to_string()))
+        );
+        assert_eq!(
+            parse_command("radio", "radio/cmd/play", b""),
+            Some(Command::Play(None))
+        );
+        assert_eq!(
+            parse_command("radio", "radio/cmd/volume", b"42.4"),
+            Some(Command::Volume(42))
+        );
+        assert_eq!(
+            parse_command("radio", "radio/cmd/volume", b"250"),
+            Some(Command::Volume(100))
+        );
+        assert_eq!(
+            parse_command("radio", "radio/cmd/power", b"OFF"),
+            Some(Command::Power(false))
+        );
+        assert_eq!(parse_command("radio", "other/cmd/stop", b""), None);
+        assert_eq!(parse_command("radio", "radio/cmd/unknown", b""), None);
+    }
+
+    #[test]
+    fn test_discovery_messages() {
+        let cfg = MqttCfg::default();
+        let messages = discovery_messages(&cfg, &["Arrow".to_string()]);
+        let (topic, payload) = messages
+            .iter()
+            .find(|(topic, _)| topic.contains("/select/"))
+            .unwrap();
+        assert_eq!(topic, "homeassistant/select/radio/station/config");
+
+        let config: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(config["command_topic"], "radio/cmd/station");
+        assert_eq!(config["options"], json!(["Arrow"]));
+        assert_eq!(config["availability_topic"], "radio/available");
+
+        let cfg = MqttCfg {
+            discovery_prefix: String::new(),
+            ..Default::default()
+        };
+        assert!(discovery_messages(&cfg, &[]).is_empty());
+    }
+
+    /**
+     * Needs a broker on localhost:1883, e.g. `mosquitto -v`.
+     */
+    #[test]
+    #[ignore]
+    fn test_local_broker() {
+        let player = Arc::new(Mutex::new(Player::default()));
+        spawn(player, MqttCfg::default());
+
+        let mut options = MqttOptions::new("radio-test", "localhost", 1883);
+        options.set_keep_alive(Duration::from_secs(5));
+        let (mut client, mut connection) = Client::new(options, 16);
+        client
+            .subscribe("radio/state/volume", QoS::AtLeastOnce)
+            .unwrap();
+
+        for notification in connection.iter() {
+            if let Ok(Event::Incoming(Packet::Publish(publish))) = notification {
+                assert_eq!(&publish.payload[..], b"100");
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,781 @@
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libc::c_int;
+use serde::Deserialize;
+
+use crate::mpv_simple::MpvCtx;
+use crate::player::{lock, Player};
+
+/**
+ * PCM is exchanged as signed 16 bit little endian stereo at this rate.
+ */
+const SAMPLE_RATE: i64 = 48_000;
+const BYTES_PER_FRAME: usize = 4;
+
+/**
+ * Audio is sent in chunks of 20 ms.
+ */
+const CHUNK_FRAMES: usize = 960;
+const CHUNK_BYTES: usize = CHUNK_FRAMES * BYTES_PER_FRAME;
+
+/**
+ * Followers drop audio that is more late than this instead of playing it, so
+ * they catch up after a stall. Smaller deviations are corrected through the
+ * playback speed.
+ */
+const MAX_LATE_MICROS: i64 = 250_000;
+
+/**
+ * Delay of an output until MPV reports how much audio it has buffered.
+ */
+const DEFAULT_OUTPUT_LATENCY: i64 = 100_000;
+
+/**
+ * How often the position of an output is compared to the leader timeline.
+ */
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+ * Playback runs at most this much faster or slower to catch up with the
+ * leader, pitch correction keeps that inaudible. A deviation is corrected
+ * over about `CORRECTION_MICROS`.
+ */
+const MAX_SPEED_CORRECTION: f64 = 0.005;
+const CORRECTION_MICROS: f64 = 10_000_000.0;
+
+/**
+ * Number of written chunks kept to look up the due time of the audible one,
+ * several seconds worth, more than MPV buffers.
+ */
+const SYNC_CHUNKS: usize = 500;
+
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/**
+ * Chunks queued for a follower, half a second. A follower that falls further
+ * behind is dropped.
+ */
+const FOLLOWER_BACKLOG: usize = 25;
+
+/**
+ * Number of recent ping round trips the clock offset is estimated from.
+ */
+const CLOCK_SAMPLES: usize = 8;
+
+const MSG_AUDIO: u8 = 1;
+const MSG_PING: u8 = 2;
+const MSG_PONG: u8 = 3;
+
+fn default_latency_ms() -> u64 {
+    500
+}
+
+/**
+ * Where an instance plays the synchronised audio.
+ */
+#[derive(Deserialize, Default)]
+pub struct OutputCfg {
+    /**
+     * MPV audio output driver, `null` plays nothing but keeps the timing.
+     */
+    #[serde(default)]
+    pub ao: Option<String>,
+    #[serde(default)]
+    pub audio_device: Option<String>,
+    /**
+     * Delays this output in ms, to even out outputs with different latencies.
+     */
+    #[serde(default)]
+    pub offset_ms: i64,
+}
+
+/**
+ * Contents of `multiroom.json`. The leader decodes the current stream once
+ * and sends it to its followers, including one inside its own process for
+ * the local output.
+ */
+#[derive(Deserialize)]
+#[serde(tag = "role", rename_all = "lowercase")]
+pub enum MultiroomCfg {
+    Leader {
+        listen: String,
+        /**
+         * How far ahead of its presentation time audio is sent, in ms.
+         */
+        #[serde(default = "default_latency_ms")]
+        latency_ms: u64,
+        #[serde(default)]
+        output: OutputCfg,
+    },
+    Follower {
+        leader: String,
+        #[serde(default)]
+        output: OutputCfg,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    /**
+     * PCM to be played at `pts`, in µs since the epoch on the leader clock.
+     */
+    Audio {
+        pts: i64,
+        data: Vec<u8>,
+    },
+    Ping {
+        sent: i64,
+    },
+    Pong {
+        sent: i64,
+        leader: i64,
+    },
+}
+
+/**
+ * Estimates the offset of the leader clock from the local one. The sample
+ * with the shortest round trip is trusted most, as it was delayed least by
+ * queued audio.
+ */
+#[derive(Default)]
+struct Clock {
+    samples: VecDeque<(i64, i64)>,
+}
+
+/**
+ * Assigns presentation times to consecutive chunks. The timeline starts over
+ * `latency` ahead whenever audio arrives too late to be played in time, e.g.
+ * after a pause or a network stall.
+ */
+struct Timeline {
+    latency: i64,
+    next: Option<i64>,
+}
+
+/**
+ * A local MPV context playing the raw PCM written into a pipe.
+ */
+struct Output {
+    pipe: File,
+    ctx: MpvCtx,
+}
+
+/**
+ * Keeps an output on the leader timeline. MPV reports the audible position,
+ * which tells how much audio is buffered between the pipe and the speaker
+ * and how far playback drifted from the due time of the audible frame.
+ */
+struct Sync {
+    /**
+     * Frames written into the output.
+     */
+    written: i64,
+    /**
+     * First frame and local due time of the recently written chunks.
+     */
+    chunks: VecDeque<(i64, i64)>,
+    /**
+     * Time from writing audio until it is heard, in µs.
+     */
+    latency: i64,
+    speed: f64,
+}
+
+/**
+ * A connected follower. Its audio is written by a thread of its own, so a
+ * slow follower does not hold up the others.
+ */
+struct Client {
+    audio: SyncSender<Arc<Vec<u8>>>,
+    stream: TcpStream,
+}
+
+pub fn load_config(path: &Path) -> Result<Option<MultiroomCfg>, String> {
+    match fs::read_to_string(path) {
+        Ok(txt) => serde_json::from_str(&txt)
+            .map(Some)
+            .map_err(|err| format!("{}: {}", path.display(), err)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn now_micros() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_micros() as i64)
+        .unwrap_or(0)
+}
+
+fn read_i64<R: Read>(r: &mut R) -> io::Result<i64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(i64::from_be_bytes(buf))
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            Message::Audio { pts, data } => {
+                buf.push(MSG_AUDIO);
+                buf.extend_from_slice(&pts.to_be_bytes());
+                buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Message::Ping { sent } => {
+                buf.push(MSG_PING);
+                buf.extend_from_slice(&sent.to_be_bytes());
+            }
+            Message::Pong { sent, leader } => {
+                buf.push(MSG_PONG);
+                buf.extend_from_slice(&sent.to_be_bytes());
+                buf.extend_from_slice(&leader.to_be_bytes());
+            }
+        }
+        buf
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Message> {
+        let mut kind = [0; 1];
+        r.read_exact(&mut kind)?;
+        match kind[0] {
+            MSG_AUDIO => {
+                let pts = read_i64(r)?;
+                let mut len = [0; 4];
+                r.read_exact(&mut len)?;
+                let len = u32::from_be_bytes(len) as usize;
+                if len > CHUNK_BYTES {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("chunk of {} bytes", len),
+                    ));
+                }
+                let mut data = vec![0; len];
+                r.read_exact(&mut data)?;
+                Ok(Message::Audio { pts, data })
+            }
+            MSG_PING => Ok(Message::Ping { sent: read_i64(r)? }),
+            MSG_PONG => Ok(Message::Pong {
+                sent: read_i64(r)?,
+                leader: read_i64(r)?,
+            }),
+            kind => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown message {}", kind),
+            )),
+        }
+    }
+}
+
+impl Clock {
+    /**
+     * Adds a ping sent at `sent` local time, answered at `leader` leader time
+     * and received back at `received` local time.
+     */
+    fn add(&mut self, sent: i64, leader: i64, received: i64) {
+        let round_trip = received - sent;
+        let offset = leader - (sent + received) / 2;
+        self.samples.push_back((round_trip, offset));
+        if self.samples.len() > CLOCK_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /**
+     * Leader time minus local time, unknown until the first ping returned.
+     */
+    fn offset(&self) -> Option<i64> {
+        self.samples
+            .iter()
+            .min_by_key(|(round_trip, _)| *round_trip)
+            .map(|(_, offset)| *offset)
+    }
+}
+
+impl Timeline {
+    fn new(latency: i64) -> Self {
+        Timeline {
+            latency,
+            next: None,
+        }
+    }
+
+    fn pts(&mut self, now: i64, frames: usize) -> i64 {
+        let pts = match self.next {
+            Some(next) if next >= now + self.latency / 2 => next,
+            _ => now + self.latency,
+        };
+        self.next = Some(pts + frames as i64 * 1_000_000 / SAMPLE_RATE);
+        pts
+    }
+}
+
+impl Sync {
+    fn new() -> Self {
+        Sync {
+            written: 0,
+            chunks: VecDeque::new(),
+            latency: DEFAULT_OUTPUT_LATENCY,
+            speed: 1.0,
+        }
+    }
+
+    fn wrote(&mut self, due: i64, frames: usize) {
+        self.chunks.push_back((self.written, due));
+        if self.chunks.len() > SYNC_CHUNKS {
+            self.chunks.pop_front();
+        }
+        self.written += frames as i64;
+    }
+
+    fn due(&self, frame: i64) -> Option<i64> {
+        self.chunks
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= frame)
+            .map(|(first, due)| due + (frame - first) * 1_000_000 / SAMPLE_RATE)
+    }
+
+    /**
+     * Takes the audible position in seconds at local time `now` into account
+     * and returns the speed that brings the output back in time.
+     */
+    fn update(&mut self, position: f64, now: i64) -> f64 {
+        let frame = (position * SAMPLE_RATE as f64) as i64;
+        self.latency = (self.written - frame) * 1_000_000 / SAMPLE_RATE;
+        if let Some(due) = self.due(frame) {
+            let late = (now - due) as f64 / CORRECTION_MICROS;
+            self.speed = 1.0 + late.max(-MAX_SPEED_CORRECTION).min(MAX_SPEED_CORRECTION);
+        }
+        self.speed
+    }
+}
+
+impl Output {
+    fn open(cfg: &OutputCfg) -> Result<Output, String> {
+        let mut fds: [c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error().to_string());
+        }
+        let read_end = unsafe { File::from_raw_fd(fds[0]) };
+        let pipe = unsafe { File::from_raw_fd(fds[1]) };
+
+        let mut options = vec![
+            ("vid", "no"),
+            ("cache", "no"),
+            ("demuxer", "rawaudio"),
+            ("demuxer-rawaudio-format", "s16le"),
+            ("demuxer-rawaudio-rate", "48000"),
+            ("demuxer-rawaudio-channels", "stereo"),
+        ];
+        if let Some(ao) = &cfg.ao {
+            options.push(("ao", ao));
+        }
+        if let Some(device) = &cfg.audio_device {
+            options.push(("audio-device", device));
+        }
+
+        let mut ctx = MpvCtx::create().map_err(|err| format!("{:?}", err))?;
+        for (name, value) in options {
+            ctx.set_option(name, value)
+                .map_err(|err| format!("{}={}: {:?}", name, value, err))?;
+        }
+        ctx.init().map_err(|err| format!("{:?}", err))?;
+        // MPV takes over the read end and closes it once playback ends.
+        let url = format!("fdclose://{}", read_end.into_raw_fd());
+        ctx.command(&["loadfile", &url])
+            .map_err(|err| format!("{:?}", err))?;
+        Ok(Output { pipe, ctx })
+    }
+}
+
+/**
+ * MPV options making the leader write its decoded audio into `fifo`.
+ */
+fn pcm_options(fifo: &Path) -> Vec<(String, String)> {
+    vec![
+        ("ao".to_string(), "pcm".to_string()),
+        (
+            "ao-pcm-file".to_string(),
+            fifo.to_string_lossy().to_string(),
+        ),
+        ("ao-pcm-waveheader".to_string(), "no".to_string()),
+        ("audio-format".to_string(), "s16".to_string()),
+        ("audio-samplerate".to_string(), SAMPLE_RATE.to_string()),
+        ("audio-channels".to_string(), "stereo".to_string()),
+    ]
+}
+
+pub fn make_fifo(path: &Path) -> io::Result<()> {
+    let _ = fs::remove_file(path);
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/**
+ * Fills `buf` with whole frames, fewer bytes are only returned at the end of
+ * the file.
+ */
+fn read_chunk<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len - len % BYTES_PER_FRAME)
+}
+
+fn broadcast(clients: &Mutex<Vec<Client>>, message: &Message) {
+    let buf = Arc::new(message.encode());
+    lock(clients).retain(|client| match client.audio.try_send(buf.clone()) {
+        Ok(()) => true,
+        Err(err) => {
+            if let TrySendError::Full(_) = err {
+                eprintln!("W: Dropping multi-room follower: too far behind");
+            }
+            // Ends the connection, the follower notices and reconnects.
+            let _ = client.stream.shutdown(Shutdown::Both);
+            false
+        }
+    });
+}
+
+fn send_audio(rx: Receiver<Arc<Vec<u8>>>, writer: Arc<Mutex<TcpStream>>) {
+    for buf in rx {
+        if let Err(err) = lock(&writer).write_all(&buf) {
+            eprintln!("W: Dropping multi-room follower: {}", err);
+            return;
+        }
+    }
+}
+
+/**
+ * Reads the audio MPV writes into the FIFO and sends it to the followers,
+ * at most `latency` ahead of its presentation time.
+ */
+fn run_leader(fifo: PathBuf, latency: i64, clients: Arc<Mutex<Vec<Client>>>) {
+    let mut timeline = Timeline::new(latency);
+    let mut chunk = vec![0; CHUNK_BYTES];
+    loop {
+        // Blocks until MPV opens its audio output.
+        let mut file = match File::open(&fifo) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("E: Cannot open {}: {}", fifo.display(), err);
+                return;
+            }
+        };
+        loop {
+            let len = match read_chunk(&mut file, &mut chunk) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) => {
+                    eprintln!("W: Reading {} failed: {}", fifo.display(), err);
+                    break;
+                }
+            };
+            let pts = timeline.pts(now_micros(), len / BYTES_PER_FRAME);
+            let wait = pts - latency - now_micros();
+            if wait > 0 {
+                thread::sleep(Duration::from_micros(wait as u64));
+            }
+            let message = Message::Audio {
+                pts,
+                data: chunk[..len].to_vec(),
+            };
+            broadcast(&clients, &message);
+        }
+    }
+}
+
+fn answer_pings(mut reader: TcpStream, writer: Arc<Mutex<TcpStream>>) {
+    while let Ok(Message::Ping { sent }) = Message::read_from(&mut reader) {
+        let pong = Message::Pong {
+            sent,
+            leader: now_micros(),
+        };
+        if lock(&writer).write_all(&pong.encode()).is_err() {
+            break;
+        }
+    }
+}
+
+fn accept(stream: TcpStream, clients: &Mutex<Vec<Client>>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let reader = stream.try_clone()?;
+    let (tx, rx) = sync_channel(FOLLOWER_BACKLOG);
+    lock(clients).push(Client {
+        audio: tx,
+        stream: stream.try_clone()?,
+    });
+    let writer = Arc::new(Mutex::new(stream));
+    let audio_writer = writer.clone();
+    thread::spawn(move || answer_pings(reader, writer));
+    thread::spawn(move || send_audio(rx, audio_writer));
+    Ok(())
+}
+
+fn receive(leader: &str, tx: &Sender<(i64, Vec<u8>)>, clock: &Mutex<Clock>) -> io::Result<()> {
+    let stream = TcpStream::connect(leader)?;
+    stream.set_nodelay(true)?;
+    // A restarted leader may run on another clock.
+    *lock(clock) = Clock::default();
+
+    let mut pinger = stream.try_clone()?;
+    thread::spawn(move || {
+        let ping = || Message::Ping { sent: now_micros() }.encode();
+        while pinger.write_all(&ping()).is_ok() {
+            thread::sleep(PING_INTERVAL);
+        }
+    });
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let result = loop {
+        match Message::read_from(&mut reader) {
+            Ok(Message::Audio { pts, data }) => {
+                if tx.send((pts, data)).is_err() {
+                    break Ok(());
+                }
+            }
+            Ok(Message::Pong { sent, leader }) => lock(clock).add(sent, leader, now_micros()),
+            Ok(Message::Ping { .. }) => (),
+            Err(err) => break Err(err),
+        }
+    };
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+/**
+ * Plays the received chunks at their presentation time on the local clock.
+ * Chunks are written ahead of time by the latency of the output, remaining
+ * drift is corrected through the playback speed.
+ */
+fn play(rx: Receiver<(i64, Vec<u8>)>, clock: Arc<Mutex<Clock>>, cfg: OutputCfg) {
+    let mut output: Option<Output> = None;
+    let mut failed: Option<Instant> = None;
+    let mut sync = Sync::new();
+    let mut synced = Instant::now();
+    for (pts, data) in rx {
+        let offset = match lock(&clock).offset() {
+            Some(offset) => offset,
+            None => continue,
+        };
+        let due = pts - offset + cfg.offset_ms * 1000;
+        let write_at = due - sync.latency;
+        let now = now_micros();
+        if write_at > now {
+            thread::sleep(Duration::from_micros((write_at - now) as u64));
+        } else if now - write_at > MAX_LATE_MICROS {
+            continue;
+        }
+
+        if output.is_none() && failed.map_or(true, |at| at.elapsed() > RECONNECT_INTERVAL) {
+            match Output::open(&cfg) {
+                Ok(opened) => {
+                    output = Some(opened);
+                    sync = Sync::new();
+                    synced = Instant::now();
+                }
+                Err(err) => {
+                    eprintln!("W: Cannot open the multi-room output: {}", err);
+                    failed = Some(Instant::now());
+                }
+            }
+        }
+        if let Some(opened) = &mut output {
+            if let Err(err) = opened.pipe.write_all(&data) {
+                eprintln!("W: Multi-room output failed: {}", err);
+                output = None;
+                continue;
+            }
+            sync.wrote(due, data.len() / BYTES_PER_FRAME);
+
+            if synced.elapsed() >= SYNC_INTERVAL {
+                synced = Instant::now();
+                let position = opened
+                    .ctx
+                    .get_property_node("audio-pts")
+                    .ok()
+                    .and_then(|position| position.as_f64());
+                if let Some(position) = position {
+                    let speed = sync.update(position, now_micros());
+                    let _ = opened.ctx.set_property("speed", &format!("{:.4}", speed));
+                }
+            }
+        }
+    }
+}
+
+fn spawn_follower(leader: String, cfg: OutputCfg) {
+    let (tx, rx) = channel();
+    let clock = Arc::new(Mutex::new(Clock::default()));
+
+    let receive_clock = clock.clone();
+    thread::spawn(move || loop {
+        if let Err(err) = receive(&leader, &tx, &receive_clock) {
+            eprintln!("W: Multi-room leader {}: {}", leader, err);
+        }
+        thread::sleep(RECONNECT_INTERVAL);
+    });
+    thread::spawn(move || play(rx, clock, cfg));
+}
+
+/**
+ * Starts synchronised playback. A leader redirects the audio of `player`
+ * into `fifo`, a follower plays whatever its leader sends and silences
+ * `player` so it cannot play over it.
+ */
+pub fn spawn(player: Arc<Mutex<Player>>, cfg: MultiroomCfg, fifo: PathBuf) -> Result<(), String> {
+    match cfg {
+        MultiroomCfg::Leader {
+            listen,
+            latency_ms,
+            output,
+        } => {
+            let listener =
+                TcpListener::bind(&listen).map_err(|err| format!("{}: {}", listen, err))?;
+            let mut local = listener.local_addr().map_err(|err| err.to_string())?;
+            if local.ip().is_unspecified() {
+                local.set_ip(Ipv4Addr::LOCALHOST.into());
+            }
+            make_fifo(&fifo).map_err(|err| format!("{}: {}", fifo.display(), err))?;
+
+            let clients: Arc<Mutex<Vec<Client>>> = Arc::default();
+            let accept_clients = clients.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if let Err(err) = stream.and_then(|stream| accept(stream, &accept_clients)) {
+                        eprintln!("W: Cannot accept a multi-room follower: {}", err);
+                    }
+                }
+            });
+            let options = pcm_options(&fifo);
+            thread::spawn(move || run_leader(fifo, latency_ms as i64 * 1000, clients));
+
+            lock(&player)
+                .set_output_options(options)
+                .map_err(|err| err.to_string())?;
+            spawn_follower(local.to_string(), output);
+        }
+        MultiroomCfg::Follower { leader, output } => {
+            let mut player = lock(&player);
+            player.stop().map_err(|err| err.to_string())?;
+            player
+                .set_output_options(vec![("ao".to_string(), "null".to_string())])
+                .map_err(|err| err.to_string())?;
+            drop(player);
+            spawn_follower(leader, output);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_messages() {
+        let messages = vec![
+            Message::Audio {
+                pts: 1_600_000_000_000_000,
+                data: vec![1, 2, 3, 4],
+            },
+            Message::Ping { sent: -5 },
+            Message::Pong { sent: 7, leader: 9 },
+        ];
+        let buf: Vec<u8> = messages.iter().flat_map(Message::encode).collect();
+        let mut reader = &buf[..];
+        for message in messages {
+            assert_eq!(Message::read_from(&mut reader).unwrap(), message);
+        }
+        assert!(Message::read_from(&mut reader).is_err());
+        assert!(Message::read_from(&mut &[9u8][..]).is_err());
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut clock = Clock::default();
+        assert_eq!(clock.offset(), None);
+        // Leader 1 s ahead, 10 ms round trip.
+        clock.add(0, 1_005_000, 10_000);
+        // Reply delayed behind queued audio.
+        clock.add(100_000, 1_200_000, 180_000);
+        assert_eq!(clock.offset(), Some(1_000_000));
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut sync = Sync::new();
+        for n in 0..10 {
+            sync.wrote(1_000_000 + n * 20_000, CHUNK_FRAMES);
+        }
+        assert_eq!(sync.due(CHUNK_FRAMES as i64 * 2 + 480), Some(1_050_000));
+
+        // 100 ms buffered, the audible frame is exactly on time.
+        let position = (CHUNK_FRAMES * 5) as f64 / SAMPLE_RATE as f64;
+        assert_eq!(sync.update(position, 1_100_000), 1.0);
+        assert_eq!(sync.latency, 100_000);
+
+        // Heard 20 ms late and early.
+        assert!(sync.update(position, 1_120_000) > 1.0);
+        assert!(sync.update(position, 1_080_000) < 1.0);
+        assert_eq!(
+            sync.update(position, 10_000_000),
+            1.0 + MAX_SPEED_CORRECTION
+        );
+    }
+
+    #[test]
+    fn test_timeline() {
+        let mut timeline = Timeline::new(500_000);
+        assert_eq!(timeline.pts(0, CHUNK_FRAMES), 500_000);
+        assert_eq!(timeline.pts(1_000, CHUNK_FRAMES), 520_000);
+        assert_eq!(timeline.pts(2_000, CHUNK_FRAMES), 540_000);
+        // Resumed after a pause, the old timeline is in the past.
+        assert_eq!(timeline.pts(10_000_000, CHUNK_FRAMES), 10_500_000);
+        assert_eq!(timeline.pts(10_000_000, CHUNK_FRAMES), 10_520_000);
+    }
+
+    #[test]
+    fn test_drop_slow_follower() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut follower = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (tx, _rx) = sync_channel(1);
+        let clients = Mutex::new(vec![Client {
+            audio: tx,
+            stream: listener.accept().unwrap().0,
+        }]);
+
+        let message = Message::Ping { sent: 0 };
+        broadcast(&clients, &message);
+        assert_eq!(lock(&clients).len(), 1);
+        broadcast(&clients, &message);
+        assert!(lock(&clients).is_empty());
+        // The connection is closed rather than left silent.
+        assert_eq!(follower.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
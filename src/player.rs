@@ -1,11 +1,23 @@
 use std::cmp;
-use std::fs::{self, OpenOptions};
-use std::sync::mpsc::{channel, Receiver};
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::mpv_simple::{MpvCtx, MpvEvent, MpvFormat};
+use crate::config;
+use crate::eq::{self, Band, Presets};
+use crate::events::{Listeners, PlayerEvent};
+use crate::health::{self, Health};
+use crate::metrics::Metrics;
+use crate::mpv_simple::{self, MpvCtx, MpvError, MpvEvent, MpvFormat};
+use crate::webhooks::Webhook;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct MetadataUpdate<'a> {
@@ -25,24 +37,174 @@ struct MetadataUpdate<'a> {
     name: Option<&'a str>,
     #[serde(rename = "icy-title")]
     title: Option<&'a str>,
+    #[serde(rename = "title", alias = "TITLE", alias = "Title")]
+    tag_title: Option<&'a str>,
+    #[serde(rename = "artist", alias = "ARTIST", alias = "Artist")]
+    tag_artist: Option<&'a str>,
 }
 
-#[derive(Deserialize, Serialize)]
+impl<'a> MetadataUpdate<'a> {
+    /**
+     * The ICY title of a stream, or the tags of a local file.
+     */
+    fn now_playing(&self) -> Option<String> {
+        match (self.title, self.tag_artist, self.tag_title) {
+            (Some(title), _, _) => Some(title.to_string()),
+            (None, Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+            (None, None, Some(title)) => Some(title.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Errors returned by the player. Each kind maps onto a machine-readable code,
+ * see `PlayerError::code`.
+ */
+#[derive(Debug, Clone)]
+pub enum PlayerError {
+    NotFound(usize),
+    UnknownStream(String),
+    InvalidUrl(String),
+    InvalidGain(f64),
+    InvalidOption(String),
+    Backend(MpvError),
+    Persistence(String),
+    NotPlaying,
+    UnknownPreset(String),
+    InvalidPreset(String),
+    UnknownDevice(String),
+    Restream(String),
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Stream {
     pub name: String,
     pub url: String,
     pub id: usize,
+
+    /**
+     * Whether titles played on this stream are scrobbled, disable it for
+     * talk stations.
+     */
+    #[serde(default = "default_true")]
+    pub scrobble: bool,
+
+    /**
+     * Volume offset in dB applied while this stream plays, to even out the
+     * loudness of different stations.
+     */
+    #[serde(default)]
+    pub gain: f64,
+
+    /**
+     * Equalizer preset used instead of the global one while this stream
+     * plays.
+     */
+    #[serde(default)]
+    pub eq: Option<String>,
+
+    /**
+     * MPV options that only apply while this stream plays, e.g. `user-agent`
+     * or `http-header-fields` for stations that block MPV.
+     */
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
 }
 
-#[derive(Deserialize, Serialize, Default)]
+#[derive(Deserialize, Serialize)]
 pub struct PlayerCfg {
     pub streams: Vec<Stream>,
     pub current: usize,
 
+    #[serde(default = "default_volume")]
+    pub volume: u8,
+
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+
+    #[serde(default)]
+    pub timeshift: Option<TimeshiftCfg>,
+
+    #[serde(default)]
+    pub normalization: NormalizationCfg,
+
+    /**
+     * Equalizer preset applied to streams without a preset of their own.
+     */
+    #[serde(default)]
+    pub eq: Option<String>,
+
+    /**
+     * Name of the MPV audio device, MPV picks one if unset.
+     */
+    #[serde(default)]
+    pub audio_device: Option<String>,
+
+    /**
+     * MPV options set before the context is initialised, e.g. `cache`,
+     * `network-timeout` or `audio-buffer`.
+     */
+    #[serde(default)]
+    pub mpv: BTreeMap<String, String>,
+
+    /**
+     * Seconds to crossfade when switching between playing stations, switches
+     * cut over immediately if unset.
+     */
+    #[serde(default)]
+    pub crossfade: Option<f64>,
+
     #[serde(skip, default)]
     pub last_id: usize,
 }
 
+/**
+ * Keeps receiving the stream while paused so playback can resume where it
+ * was, and allows seeking within the received data. Takes effect when the
+ * MPV context is created.
+ */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TimeshiftCfg {
+    /**
+     * Length of the buffer in seconds.
+     */
+    pub seconds: u64,
+    /**
+     * Keep the buffer in a temporary file instead of in memory.
+     */
+    #[serde(default)]
+    pub on_disk: bool,
+}
+
+/**
+ * Loudness normalisation, `filter` is an MPV audio filter chain as accepted by
+ * the `af` property.
+ */
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct NormalizationCfg {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_normalization_filter")]
+    pub filter: String,
+}
+
+#[derive(Serialize)]
+pub struct TimeshiftStatus {
+    pub enabled: bool,
+    pub state: PlaybackState,
+    pub behind_live: f64,
+    pub buffered: f64,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackState {
+    Playing,
+    Paused,
+    Stopped,
+}
+
 #[derive(Deserialize, Serialize, Default)]
 pub struct Player {
     pub cfg: PlayerCfg,
@@ -51,41 +213,302 @@ pub struct Player {
     cfg_path: String,
 
     #[serde(skip, default)]
-    mpv_ctx: Option<Arc<Mutex<MpvCtx>>>,
+    backend: Option<Backend>,
 
     #[serde(skip, default)]
     now_playing: Arc<Mutex<String>>,
 
     #[serde(skip, default)]
-    event_thread: Option<std::thread::JoinHandle<()>>,
+    state: PlaybackState,
+
+    #[serde(skip, default)]
+    listeners: Listeners,
+
+    #[serde(skip, default)]
+    metrics: Arc<Metrics>,
+
+    #[serde(skip, default)]
+    eq_presets: Presets,
+
+    #[serde(skip, default)]
+    fade: Arc<Fade>,
+
+    #[serde(skip, default)]
+    output: Vec<(String, String)>,
+
+    /**
+     * Local files playing instead of a station.
+     */
+    #[serde(skip, default)]
+    queue: Vec<String>,
+}
+
+/**
+ * State shared with the thread fading between two MPV contexts. A fade stops
+ * as soon as the generation no longer matches the one it was started with.
+ */
+#[derive(Default)]
+struct Fade {
+    generation: AtomicUsize,
+    volume: AtomicUsize,
+}
+
+impl Fade {
+    fn superseded(&self, generation: usize) -> bool {
+        self.generation.load(Ordering::SeqCst) != generation
+    }
+}
+
+/**
+ * An entry of MPV's `audio-device-list`.
+ */
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AudioDevice {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Serialize)]
+pub struct EqStatus<'a> {
+    pub default: Option<&'a str>,
+    pub active: Option<&'a str>,
+    pub bands: &'a [Band],
+}
+
+/**
+ * An MPV context together with the thread reading its events.
+ */
+struct Backend {
+    ctx: Arc<Mutex<MpvCtx>>,
+    alive: Arc<AtomicBool>,
+    current: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    event_tx: Sender<()>,
+    event_thread: Option<JoinHandle<()>>,
 }
 
 unsafe impl Send for Player {}
 unsafe impl Sync for Player {}
 unsafe impl Send for MpvCtx {}
 
+/**
+ * Upper estimate of the data rate of a radio stream (320 kbit/s), used to size
+ * the timeshift buffer.
+ */
+const BYTES_PER_SECOND: u64 = 40_000;
+
+/**
+ * Distance from the newest received data kept when jumping back to live, so
+ * playback does not immediately run dry.
+ */
+const LIVE_MARGIN: f64 = 2.0;
+
+/**
+ * Largest gain in dB, in either direction, accepted for a stream.
+ */
+const MAX_GAIN: f64 = 24.0;
+
+/**
+ * Interval between volume changes while crossfading.
+ */
+const FADE_STEP: Duration = Duration::from_millis(50);
+
+/**
+ * How long a crossfade waits for the new stream to produce audio before it
+ * starts fading regardless.
+ */
+const FADE_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_volume() -> u8 {
+    100
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_normalization_filter() -> String {
+    "lavfi=[loudnorm=I=-16:TP=-1.5:LRA=11]".to_string()
+}
+
+impl Default for PlayerCfg {
+    fn default() -> Self {
+        PlayerCfg {
+            streams: Vec::new(),
+            current: 0,
+            volume: default_volume(),
+            webhooks: Vec::new(),
+            timeshift: None,
+            normalization: NormalizationCfg::default(),
+            eq: None,
+            audio_device: None,
+            mpv: BTreeMap::new(),
+            crossfade: None,
+            last_id: 0,
+        }
+    }
+}
+
+impl Default for NormalizationCfg {
+    fn default() -> Self {
+        NormalizationCfg {
+            enabled: false,
+            filter: default_normalization_filter(),
+        }
+    }
+}
+
+impl Default for PlaybackState {
+    fn default() -> Self {
+        PlaybackState::Stopped
+    }
+}
+
 impl Stream {
     pub fn new(id: usize, name: String, url: String) -> Self {
-        Stream { id, name, url }
+        Stream {
+            id,
+            name,
+            url,
+            scrobble: true,
+            gain: 0.0,
+            eq: None,
+            options: BTreeMap::new(),
+        }
+    }
+}
+
+impl PlayerError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PlayerError::NotFound(_) => "not_found",
+            PlayerError::UnknownStream(_) => "unknown_stream",
+            PlayerError::InvalidUrl(_) => "invalid_url",
+            PlayerError::InvalidGain(_) => "invalid_gain",
+            PlayerError::InvalidOption(_) => "invalid_option",
+            PlayerError::Backend(_) => "backend_failure",
+            PlayerError::Persistence(_) => "persistence_failure",
+            PlayerError::NotPlaying => "not_playing",
+            PlayerError::UnknownPreset(_) => "unknown_preset",
+            PlayerError::InvalidPreset(_) => "invalid_preset",
+            PlayerError::UnknownDevice(_) => "unknown_device",
+            PlayerError::Restream(_) => "restream_failure",
+        }
+    }
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlayerError::NotFound(id) => write!(f, "No stream with ID {}", id),
+            PlayerError::UnknownStream(name) => write!(f, "No stream named {}", name),
+            PlayerError::InvalidUrl(url) => write!(f, "URL invalid or unsupported: {}", url),
+            PlayerError::InvalidGain(gain) => write!(f, "Gain out of range: {} dB", gain),
+            PlayerError::InvalidOption(name) => write!(f, "Invalid MPV option: {}", name),
+            PlayerError::Backend(err) => write!(f, "MPV failure: {:?}", err),
+            PlayerError::Persistence(msg) => write!(f, "Failed to store configuration: {}", msg),
+            PlayerError::NotPlaying => write!(f, "No stream is playing"),
+            PlayerError::UnknownPreset(name) => write!(f, "No equalizer preset {}", name),
+            PlayerError::InvalidPreset(msg) => write!(f, "Invalid equalizer preset: {}", msg),
+            PlayerError::UnknownDevice(name) => write!(f, "No audio device {}", name),
+            PlayerError::Restream(msg) => write!(f, "Re-streaming failed: {}", msg),
+        }
+    }
+}
+
+impl From<MpvError> for PlayerError {
+    fn from(err: MpvError) -> Self {
+        PlayerError::Backend(err)
     }
 }
 
+/**
+ * Locks a mutex, recovering the data if another thread panicked while holding
+ * the lock. None of the state guarded in this program can be left half-updated
+ * by a panic, so continuing is preferable over poisoning every later request.
+ */
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
 /**
  * Event thread
  */
-fn read_events(rx: Receiver<()>, ctx: Arc<Mutex<MpvCtx>>, now_playing: Arc<Mutex<String>>) {
-    loop {
-        let _ = rx.recv();
-        let mut guard = ctx.lock().unwrap();
+fn read_events(
+    rx: Receiver<()>,
+    ctx: Arc<Mutex<MpvCtx>>,
+    now_playing: Arc<Mutex<String>>,
+    listeners: Listeners,
+    metrics: Arc<Metrics>,
+    alive: Arc<AtomicBool>,
+    current: Arc<AtomicBool>,
+) {
+    let mut playback_time = String::new();
+    let mut position_second = None;
+    let mut duration = None;
+    while rx.recv().is_ok() && alive.load(Ordering::SeqCst) {
+        let mut guard = lock(&ctx);
         loop {
             match guard.wait_event(0.0) {
                 Ok(MpvEvent::None) => break,
-                Ok(MpvEvent::PropertyChange { change, .. }) => {
-                    if let Ok(metadata) = serde_json::from_str::<MetadataUpdate>(&change) {
-                        if let Some(title) = metadata.title {
-                            let mut now_playing_guard = now_playing.lock().unwrap();
-                            println!("{}", title);
-                            *now_playing_guard = title.to_string();
+                Ok(MpvEvent::Shutdown) => {
+                    alive.store(false, Ordering::SeqCst);
+                    return;
+                }
+                Ok(MpvEvent::EndFile { error }) => {
+                    if let Some(err) = error {
+                        metrics.stream_error(err);
+                    }
+                    position_second = None;
+                    duration = None;
+                }
+                Ok(MpvEvent::StartFile) => {
+                    position_second = None;
+                    duration = None;
+                    if current.load(Ordering::SeqCst) {
+                        if let Ok(Value::String(path)) = guard.get_property_node("path") {
+                            listeners.emit(PlayerEvent::FileStarted(path));
+                        }
+                    }
+                }
+                // A context that is being faded out no longer speaks for the player.
+                Ok(MpvEvent::PropertyChange { .. }) if !current.load(Ordering::SeqCst) => (),
+                Ok(MpvEvent::PropertyChange { name, change, .. }) => {
+                    if name == "demuxer-cache-duration" {
+                        metrics.set_buffered(change.parse().unwrap_or(0.0));
+                    } else if name == "demuxer-cache-time" {
+                        metrics.set_cache_time(change.parse().unwrap_or(0.0));
+                    } else if name == "playback-time" {
+                        if change != playback_time {
+                            metrics.set_playback_time(change.parse().unwrap_or(0.0));
+                            metrics.audio_progressed();
+                            playback_time = change;
+                        }
+                    } else if name == "duration" {
+                        duration = change.parse().ok();
+                    } else if name == "time-pos" {
+                        let position: f64 = match change.parse() {
+                            Ok(position) => position,
+                            Err(_) => continue,
+                        };
+                        let second = position as u64;
+                        if position_second != Some(second) {
+                            position_second = Some(second);
+                            listeners.emit(PlayerEvent::PositionChanged(position, duration));
+                        }
+                    } else if let Ok(metadata) = serde_json::from_str::<MetadataUpdate>(&change) {
+                        if let Some(title) = metadata.now_playing() {
+                            let mut now_playing_guard = lock(&now_playing);
+                            if *now_playing_guard != title {
+                                println!("{}", title);
+                                *now_playing_guard = title.clone();
+                                drop(now_playing_guard);
+                                metrics.title_changed();
+                                listeners.emit(PlayerEvent::TitleChanged(title.to_string()));
+                            }
                         }
                     }
                 }
@@ -95,17 +518,195 @@ fn read_events(rx: Receiver<()>, ctx: Arc<Mutex<MpvCtx>>, now_playing: Arc<Mutex
     }
 }
 
+/**
+ * Periodically checks whether the MPV context of the player is still usable
+ * and recreates it if it is not, notices the end of the file queue and
+ * reconnects streams that dropped.
+ */
+pub fn spawn_watchdog(player: Arc<Mutex<Player>>, interval: Duration) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let mut guard = lock(&player);
+        if !guard.backend_alive() {
+            eprintln!("W: MPV context is unusable, recreating it");
+            if let Err(err) = guard.restart_backend() {
+                eprintln!("E: {}", err);
+            }
+        }
+        guard.finish_queue();
+        guard.reconnect_stream();
+    })
+}
+
+impl Backend {
+    fn start(
+        now_playing: Arc<Mutex<String>>,
+        listeners: Listeners,
+        metrics: Arc<Metrics>,
+        options: &[(String, String)],
+    ) -> Result<Self, PlayerError> {
+        let mut mpv_ctx = MpvCtx::create()?;
+        for (name, value) in options {
+            if let Err(err) = mpv_ctx.set_option(name, value) {
+                eprintln!("W: Ignoring MPV option {}={}: {:?}", name, value, err);
+            }
+        }
+        mpv_ctx.init()?;
+        mpv_ctx.observe_property(0, "metadata", MpvFormat::String)?;
+        mpv_ctx.observe_property(0, "demuxer-cache-duration", MpvFormat::String)?;
+        mpv_ctx.observe_property(0, "playback-time", MpvFormat::String)?;
+        mpv_ctx.observe_property(0, "demuxer-cache-time", MpvFormat::String)?;
+        mpv_ctx.observe_property(0, "time-pos", MpvFormat::String)?;
+        mpv_ctx.observe_property(0, "duration", MpvFormat::String)?;
+
+        let ctx = Arc::new(Mutex::new(mpv_ctx));
+        let alive = Arc::new(AtomicBool::new(true));
+        let current = Arc::new(AtomicBool::new(true));
+        let (tx, rx) = channel();
+
+        let thread_ctx = ctx.clone();
+        let thread_alive = alive.clone();
+        let thread_current = current.clone();
+        let thread_metrics = metrics.clone();
+        let event_thread = thread::spawn(move || {
+            read_events(
+                rx,
+                thread_ctx,
+                now_playing,
+                listeners,
+                thread_metrics,
+                thread_alive,
+                thread_current,
+            );
+        });
+
+        let callback_tx = tx.clone();
+        let closure = move || {
+            let _ = callback_tx.send(());
+        };
+        lock(&ctx).set_wakeup_callback(closure);
+
+        Ok(Backend {
+            ctx,
+            alive,
+            current,
+            metrics,
+            event_tx: tx,
+            event_thread: Some(event_thread),
+        })
+    }
+
+    fn is_alive(&self) -> bool {
+        self.ctx_alive() && self.event_thread_running()
+    }
+
+    fn ctx_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+
+    fn event_thread_running(&self) -> bool {
+        self.event_thread
+            .as_ref()
+            .map_or(false, |thread| !thread.is_finished())
+    }
+
+    fn command(&self, args: &[&str]) -> Result<(), PlayerError> {
+        let result = lock(&self.ctx).command(args);
+        self.check(result)
+    }
+
+    fn command_named(&self, name: &str, args: &[(&str, &str)]) -> Result<(), PlayerError> {
+        let result = lock(&self.ctx).command_named(name, args);
+        self.check(result)
+    }
+
+    fn get_property_node(&self, name: &str) -> Result<serde_json::Value, PlayerError> {
+        let result = lock(&self.ctx).get_property_node(name);
+        self.check(result)
+    }
+
+    fn set_property(&self, name: &str, value: &str) -> Result<(), PlayerError> {
+        let result = lock(&self.ctx).set_property(name, value);
+        self.check(result)
+    }
+
+    /**
+     * Records failures and marks the context as dead if it became unusable.
+     */
+    fn check<T>(&self, result: Result<T, MpvError>) -> Result<T, PlayerError> {
+        if let Err(err) = result {
+            self.metrics.stream_error(err);
+        }
+        if let Err(MpvError::Uninitialized) | Err(MpvError::NoMem) = result {
+            self.alive.store(false, Ordering::SeqCst);
+        }
+        result.map_err(PlayerError::from)
+    }
+}
+
+/**
+ * Crossfades from `old` to `new` with equal-power curves once `new` plays,
+ * then tears `old` down.
+ */
+fn fade(old: Backend, new: Arc<Mutex<MpvCtx>>, seconds: f64, shared: Arc<Fade>, generation: usize) {
+    let superseded = || shared.superseded(generation);
+    // A superseded fade may have left the old context below full volume.
+    let start_volume = old
+        .get_property_node("volume")
+        .ok()
+        .and_then(|volume| volume.as_f64());
+
+    let deadline = Instant::now() + FADE_START_TIMEOUT;
+    while Instant::now() < deadline && !superseded() {
+        let playing = lock(&new)
+            .get_property_node("playback-time")
+            .map_or(false, |time| time.is_number());
+        if playing {
+            break;
+        }
+        thread::sleep(FADE_STEP);
+    }
+
+    let steps = cmp::max(1, (seconds / FADE_STEP.as_secs_f64()) as u32);
+    for step in 1..=steps {
+        let angle = f64::from(step) / f64::from(steps) * std::f64::consts::FRAC_PI_2;
+        let volume = shared.volume.load(Ordering::SeqCst) as f64;
+        {
+            // Checked under the lock so a cancelled fade cannot override the
+            // volume the player restores.
+            let mut guard = lock(&new);
+            if superseded() {
+                return;
+            }
+            if let Err(err) = guard.set_property("volume", &format!("{:.1}", volume * angle.sin()))
+            {
+                eprintln!("W: Crossfade failed: {:?}", err);
+                return;
+            }
+        }
+        let old_volume = start_volume.unwrap_or(volume) * angle.cos();
+        let _ = old.set_property("volume", &format!("{:.1}", old_volume));
+        thread::sleep(FADE_STEP);
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::SeqCst);
+        let _ = self.event_tx.send(());
+        if let Some(thread) = self.event_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
 impl Player {
-    pub fn from_file(path: &std::path::Path, mut mpv_ctx: MpvCtx) -> Self {
-        mpv_ctx
-            .observe_property(0, "metadata", MpvFormat::String)
-            .expect("Failed to observe metadata property");
-        let mut player = match fs::read_to_string(path) {
-            Ok(txt) => {
-                let mut player = serde_json::from_str::<Player>(&txt)
-                    .expect("Failed to parse configuration file");
+    pub fn from_file(path: &Path) -> Result<Self, PlayerError> {
+        let loaded = config::load::<Player>(path, config::BACKUP_COUNT)
+            .map_err(|err| PlayerError::Persistence(err.to_string()))?;
+        let mut player = match loaded {
+            Some(mut player) => {
                 player.cfg_path = path.to_str().unwrap().to_string();
-                player.mpv_ctx = Some(Arc::new(Mutex::new(mpv_ctx)));
                 player.cfg.last_id = player
                     .cfg
                     .streams
@@ -113,103 +714,961 @@ impl Player {
                     .fold(0, |acc, stream| cmp::max(acc, stream.id));
                 player
             }
-            Err(_) => Player {
+            None => Player {
                 cfg_path: path.to_str().unwrap().to_string(),
-                mpv_ctx: Some(Arc::new(Mutex::new(mpv_ctx))),
                 now_playing: Arc::new(Mutex::new(String::new())),
                 ..Default::default()
             },
         };
 
-        let (tx, rx) = channel();
+        let presets_path = path.with_file_name("eq_presets.json");
+        player.eq_presets = Presets::load(&presets_path).unwrap_or_else(|err| {
+            eprintln!("W: Ignoring equalizer presets: {}", err);
+            Presets::empty(&presets_path)
+        });
 
-        let thread_ctx = player.mpv_ctx.clone();
-        let thread_now_playing = player.now_playing.clone();
+        player.backend = Some(player.start_backend()?);
+        player.set_volume(player.cfg.volume)?;
+        player.apply_audio_device();
 
-        player.event_thread = Some(std::thread::spawn(move || {
-            read_events(rx, thread_ctx.unwrap(), thread_now_playing);
-        }));
+        if player.get_current().is_some() {
+            if let Err(err) = player.play(player.cfg.current) {
+                eprintln!("W: Could not resume the last stream: {}", err);
+            }
+        }
+        Ok(player)
+    }
 
-        let closure = move || {
-            let _ = tx.send(());
+    fn start_backend(&self) -> Result<Backend, PlayerError> {
+        Backend::start(
+            self.now_playing.clone(),
+            self.listeners.clone(),
+            self.metrics.clone(),
+            &self.mpv_options(),
+        )
+    }
+
+    /**
+     * Crossfade duration if switching now should fade, i.e. it is configured
+     * and the current context is actually playing something.
+     */
+    fn crossfade_seconds(&self) -> Option<f64> {
+        let seconds = self.cfg.crossfade.filter(|seconds| *seconds > 0.0)?;
+        // Two contexts cannot share a redirected output.
+        if !self.output.is_empty() {
+            return None;
+        }
+        if self.state != PlaybackState::Playing || !self.backend_alive() {
+            return None;
+        }
+        let idle = self
+            .backend()
+            .and_then(|backend| backend.get_property_node("idle-active"));
+        match idle {
+            Ok(Value::Bool(false)) => Some(seconds),
+            _ => None,
+        }
+    }
+
+    /**
+     * Replaces the backend with a silent one for the next stream and returns
+     * the old backend, which keeps playing until it is faded out.
+     */
+    fn start_fade_in(&mut self) -> Result<Backend, PlayerError> {
+        let backend = self.start_backend()?;
+        backend.set_property("volume", "0")?;
+        let old = self
+            .backend
+            .replace(backend)
+            .ok_or(MpvError::Uninitialized)?;
+        old.current.store(false, Ordering::SeqCst);
+        self.apply_audio_device();
+        Ok(old)
+    }
+
+    /**
+     * Stops a running crossfade, tearing down the old context and restoring
+     * the volume of the current one.
+     */
+    fn cancel_fade(&self) {
+        self.fade.generation.fetch_add(1, Ordering::SeqCst);
+        let volume = self.cfg.volume.to_string();
+        if let Ok(backend) = self.backend() {
+            let _ = backend.set_property("volume", &volume);
+        }
+    }
+
+    /**
+     * Redirects the audio output, e.g. into a pipe, overriding the configured
+     * MPV options. The backend is restarted to apply them.
+     */
+    pub fn set_output_options(
+        &mut self,
+        options: Vec<(String, String)>,
+    ) -> Result<(), PlayerError> {
+        self.output = options;
+        self.restart_backend()
+    }
+
+    pub fn backend_alive(&self) -> bool {
+        self.backend.as_ref().map_or(false, Backend::is_alive)
+    }
+
+    /**
+     * Tears down the current MPV context and starts playing the current stream
+     * on a fresh one.
+     */
+    pub fn restart_backend(&mut self) -> Result<(), PlayerError> {
+        self.fade.generation.fetch_add(1, Ordering::SeqCst);
+        self.backend = None;
+        self.metrics.backend_restarted();
+        self.backend = Some(self.start_backend()?);
+        self.set_volume(self.cfg.volume)?;
+        self.apply_audio_device();
+        if self.state != PlaybackState::Stopped && !self.queue.is_empty() {
+            let queue = std::mem::take(&mut self.queue);
+            self.play_files(queue, false, 0.0)?;
+        } else if self.state != PlaybackState::Stopped && self.get_current().is_some() {
+            self.play(self.cfg.current)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Options the MPV context is created with.
+     */
+    fn mpv_options(&self) -> Vec<(String, String)> {
+        let mut options = Vec::new();
+        if let Some(timeshift) = &self.cfg.timeshift {
+            let bytes = (timeshift.seconds * BYTES_PER_SECOND).to_string();
+            let seconds = timeshift.seconds.to_string();
+            let on_disk = if timeshift.on_disk { "yes" } else { "no" };
+            for (name, value) in &[
+                ("cache", "yes"),
+                ("cache-secs", &seconds),
+                ("demuxer-readahead-secs", &seconds),
+                ("demuxer-max-bytes", &bytes),
+                ("demuxer-max-back-bytes", &bytes),
+                ("demuxer-seekable-cache", "yes"),
+                ("force-seekable", "yes"),
+                ("cache-on-disk", on_disk),
+            ] {
+                options.push((name.to_string(), value.to_string()));
+            }
+        }
+        for (name, value) in &self.cfg.mpv {
+            options.push((name.to_string(), value.to_string()));
+        }
+        options.extend(self.output.iter().cloned());
+        options
+    }
+
+    /**
+     * The `af` filter chain for the current stream: the normalisation filter
+     * if enabled, the equalizer and the gain of the stream.
+     */
+    fn audio_filters(&self) -> String {
+        let mut filters = Vec::new();
+        if self.cfg.normalization.enabled {
+            filters.push(self.cfg.normalization.filter.to_string());
+        }
+        if let Some(filter) = eq::filter(self.eq_bands()) {
+            filters.push(filter);
+        }
+        if let Some(stream) = self.get_current() {
+            if stream.gain != 0.0 {
+                filters.push(format!("lavfi=[volume={}dB]", stream.gain));
+            }
+        }
+        filters.join(",")
+    }
+
+    fn apply_filters(&self) -> Result<(), PlayerError> {
+        self.backend()?.set_property("af", &self.audio_filters())
+    }
+
+    /**
+     * Name of the equalizer preset for the current stream.
+     */
+    fn eq_preset(&self) -> Option<&str> {
+        self.get_current()
+            .and_then(|stream| stream.eq.as_ref())
+            .or(self.cfg.eq.as_ref())
+            .map(String::as_str)
+    }
+
+    fn eq_bands(&self) -> &[Band] {
+        self.eq_preset()
+            .and_then(|name| self.eq_presets.get(name))
+            .unwrap_or(&[])
+    }
+
+    pub fn eq_status(&self) -> EqStatus {
+        EqStatus {
+            default: self.cfg.eq.as_ref().map(String::as_str),
+            active: self.eq_preset(),
+            bands: self.eq_bands(),
+        }
+    }
+
+    pub fn eq_presets(&self) -> &BTreeMap<String, Vec<Band>> {
+        self.eq_presets.all()
+    }
+
+    fn check_preset(&self, preset: &Option<String>) -> Result<(), PlayerError> {
+        match preset {
+            Some(name) if !self.eq_presets.contains(name) => {
+                Err(PlayerError::UnknownPreset(name.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Sets the equalizer preset used by streams without one of their own.
+     */
+    pub fn set_eq(&mut self, preset: Option<String>) -> Result<EqStatus, PlayerError> {
+        self.check_preset(&preset)?;
+        let previous = std::mem::replace(&mut self.cfg.eq, preset);
+        self.commit_cfg(true, |cfg| cfg.eq = previous)?;
+        Ok(self.eq_status())
+    }
+
+    pub fn set_stream_eq(
+        &mut self,
+        id: usize,
+        preset: Option<String>,
+    ) -> Result<&Stream, PlayerError> {
+        self.check_preset(&preset)?;
+        let pos = self
+            .cfg
+            .streams
+            .iter()
+            .position(|stream| stream.id == id)
+            .ok_or(PlayerError::NotFound(id))?;
+        let previous = std::mem::replace(&mut self.cfg.streams[pos].eq, preset);
+        self.commit_cfg(id == self.cfg.current, |cfg| cfg.streams[pos].eq = previous)?;
+        self.listeners.emit(PlayerEvent::PlaylistChanged);
+        Ok(&self.cfg.streams[pos])
+    }
+
+    pub fn set_eq_preset(&mut self, name: String, bands: Vec<Band>) -> Result<(), PlayerError> {
+        eq::validate(&bands).map_err(PlayerError::InvalidPreset)?;
+        let previous = self.eq_presets.replace(&name, Some(bands));
+        if let Err(err) = self.apply_filters() {
+            self.eq_presets.replace(&name, previous);
+            return Err(err);
+        }
+        self.eq_presets
+            .save()
+            .map_err(|err| PlayerError::Persistence(err.to_string()))
+    }
+
+    /**
+     * Deletes a preset, streams still referring to it play without equalizer.
+     */
+    pub fn delete_eq_preset(&mut self, name: &str) -> Result<Vec<Band>, PlayerError> {
+        let removed = self
+            .eq_presets
+            .replace(name, None)
+            .ok_or_else(|| PlayerError::UnknownPreset(name.to_string()))?;
+        if let Err(err) = self.apply_filters() {
+            self.eq_presets.replace(name, Some(removed));
+            return Err(err);
+        }
+        self.eq_presets
+            .save()
+            .map_err(|err| PlayerError::Persistence(err.to_string()))?;
+        Ok(removed)
+    }
+
+    pub fn audio_devices(&self) -> Result<Vec<AudioDevice>, PlayerError> {
+        let list = self.backend()?.get_property_node("audio-device-list")?;
+        serde_json::from_value(list).map_err(|_| PlayerError::Backend(MpvError::PropertyFormat))
+    }
+
+    /**
+     * Selects the configured audio device, or MPV's default if the device is
+     * not present. The configuration is kept so the device is used again once
+     * it is back.
+     */
+    fn apply_audio_device(&self) {
+        let device = match &self.cfg.audio_device {
+            Some(device) => device,
+            None => return,
+        };
+        let present = self
+            .audio_devices()
+            .map(|devices| devices.iter().any(|entry| &entry.name == device));
+        let result = match present {
+            Ok(true) => self
+                .backend()
+                .and_then(|backend| backend.set_property("audio-device", device)),
+            Ok(false) => {
+                eprintln!("W: Audio device {} not found, using the default", device);
+                self.backend()
+                    .and_then(|backend| backend.set_property("audio-device", "auto"))
+            }
+            Err(err) => Err(err),
         };
+        if let Err(err) = result {
+            eprintln!("W: Could not select audio device {}: {}", device, err);
+        }
+    }
 
-        {
-            let mut guard = player.mpv_ctx.as_mut().unwrap().lock().unwrap();
-            guard.set_wakeup_callback(closure);
+    /**
+     * Switches to the audio device `name`, or to MPV's default for `None`.
+     */
+    pub fn set_audio_device(&mut self, name: Option<String>) -> Result<(), PlayerError> {
+        if let Some(name) = &name {
+            if !self
+                .audio_devices()?
+                .iter()
+                .any(|device| &device.name == name)
+            {
+                return Err(PlayerError::UnknownDevice(name.to_string()));
+            }
+        }
+        self.backend()?
+            .set_property("audio-device", name.as_ref().map_or("auto", String::as_str))?;
+        self.cfg.audio_device = name;
+        self.dump_cfg()
+    }
+
+    fn backend(&self) -> Result<&Backend, PlayerError> {
+        self.backend
+            .as_ref()
+            .ok_or(PlayerError::Backend(MpvError::Uninitialized))
+    }
+
+    fn play_stream(&mut self, stream: &Stream) -> Result<(), PlayerError> {
+        let backend = self.backend()?;
+        if stream.options.is_empty() {
+            backend.command(&["loadfile", &stream.url])?;
+        } else {
+            let options: Vec<(&str, &str)> = stream
+                .options
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            backend.command_named(
+                "loadfile",
+                &[
+                    ("url", &stream.url),
+                    ("flags", "replace"),
+                    ("options", &mpv_simple::key_value_list(&options)),
+                ],
+            )?;
         }
+        backend.command(&["set", "pause", "no"])
+    }
 
-        let _ = player.play(player.cfg.current);
-        player
+    pub fn listeners(&self) -> &Listeners {
+        &self.listeners
     }
 
-    fn play_stream(&mut self, stream: &str) {
-        let mut guard = self.mpv_ctx.as_mut().unwrap().lock().unwrap();
-        guard
-            .command(&["loadfile", &stream])
-            .expect("Error opening URL");
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
     }
 
     pub fn get_playlist(&self) -> &[Stream] {
         &self.cfg.streams
     }
 
-    pub fn add(&mut self, name: String, url: String) -> &Stream {
+    pub fn add(&mut self, name: String, url: String) -> Result<&Stream, PlayerError> {
         self.cfg.last_id += 1;
         self.cfg
             .streams
             .push(Stream::new(self.cfg.last_id, name, url));
-        self.dump_cfg();
-        self.cfg.streams.last().unwrap()
+        self.commit_cfg(false, |cfg| {
+            cfg.streams.pop();
+            cfg.last_id -= 1;
+        })?;
+        self.listeners.emit(PlayerEvent::PlaylistChanged);
+        Ok(self.cfg.streams.last().unwrap())
     }
 
-    pub fn play(&mut self, id: usize) -> Result<&Stream, ()> {
+    pub fn play(&mut self, id: usize) -> Result<&Stream, PlayerError> {
         let found = self.cfg.streams.iter().position(|x| x.id == id);
 
         if let Some(pos) = found {
             {
-                let mut guard = self.now_playing.lock().unwrap();
+                let mut guard = lock(&self.now_playing);
                 *guard = String::new();
             }
 
+            let crossfade = self.crossfade_seconds();
+            let previous = match crossfade {
+                Some(_) => {
+                    self.fade.generation.fetch_add(1, Ordering::SeqCst);
+                    match self.start_fade_in() {
+                        Ok(old) => Some(old),
+                        Err(err) => {
+                            eprintln!("W: Switching without crossfade: {}", err);
+                            self.cancel_fade();
+                            None
+                        }
+                    }
+                }
+                None => {
+                    self.cancel_fade();
+                    None
+                }
+            };
+
+            let old_queue = std::mem::take(&mut self.queue);
+            let old_current = self.cfg.current;
             self.cfg.current = self.cfg.streams[pos].id;
-            let url = self.cfg.streams[pos].url.to_string();
-            self.play_stream(&url);
-            self.dump_cfg();
+            let stream = self.cfg.streams[pos].clone();
+            if let Err(err) = self.apply_filters() {
+                eprintln!("W: Could not apply audio filters: {}", err);
+            }
+            if let Err(err) = self.play_stream(&stream) {
+                // The old stream never stopped, the player goes on with it.
+                self.cfg.current = old_current;
+                self.queue = old_queue;
+                match previous {
+                    Some(old) => {
+                        old.current.store(true, Ordering::SeqCst);
+                        self.backend = Some(old);
+                        self.cancel_fade();
+                    }
+                    None => {
+                        if let Err(err) = self.apply_filters() {
+                            eprintln!("W: Could not apply audio filters: {}", err);
+                        }
+                    }
+                }
+                return Err(err);
+            }
+            if let (Some(old), Some(seconds)) = (previous, crossfade) {
+                let new = self.backend()?.ctx.clone();
+                let shared = self.fade.clone();
+                let generation = shared.generation.load(Ordering::SeqCst);
+                thread::spawn(move || fade(old, new, seconds, shared, generation));
+            }
+            self.state = PlaybackState::Playing;
+            self.metrics.set_listening(Some(self.cfg.current));
+            self.metrics.audio_progressed();
+            self.listeners.emit(PlayerEvent::StreamChanged(Some(
+                self.cfg.streams[pos].clone(),
+            )));
+            self.listeners
+                .emit(PlayerEvent::PlaybackChanged(PlaybackState::Playing));
+            // Saved last, the switch happened even if it cannot be remembered.
+            self.dump_cfg()?;
             Ok(&self.cfg.streams[pos])
         } else {
-            Err(())
+            Err(PlayerError::NotFound(id))
+        }
+    }
+
+    /**
+     * Plays local files instead of the current station, or appends them to
+     * the files already playing. Playback of the first file begins `start`
+     * seconds in.
+     */
+    pub fn play_files(
+        &mut self,
+        files: Vec<String>,
+        append: bool,
+        start: f64,
+    ) -> Result<(), PlayerError> {
+        if files.is_empty() {
+            return Ok(());
+        }
+        if append && !self.queue.is_empty() {
+            let backend = self.backend()?;
+            for file in &files {
+                backend.command(&["loadfile", file, "append"])?;
+            }
+            self.queue.extend(files);
+            return Ok(());
+        }
+
+        self.cancel_fade();
+        *lock(&self.now_playing) = String::new();
+        let backend = self.backend()?;
+        for (pos, file) in files.iter().enumerate() {
+            if pos == 0 && start > 0.0 {
+                let start = start.to_string();
+                backend.command_named(
+                    "loadfile",
+                    &[
+                        ("url", file),
+                        ("flags", "replace"),
+                        ("options", &mpv_simple::key_value_list(&[("start", &start)])),
+                    ],
+                )?;
+            } else {
+                let flags = if pos == 0 { "replace" } else { "append" };
+                backend.command(&["loadfile", file, flags])?;
+            }
+        }
+        backend.command(&["set", "pause", "no"])?;
+        self.queue = files;
+        if let Err(err) = self.apply_filters() {
+            eprintln!("W: Could not apply audio filters: {}", err);
         }
+        self.state = PlaybackState::Playing;
+        self.metrics.set_listening(None);
+        self.metrics.audio_progressed();
+        self.listeners.emit(PlayerEvent::StreamChanged(None));
+        self.listeners
+            .emit(PlayerEvent::PlaybackChanged(PlaybackState::Playing));
+        Ok(())
+    }
+
+    /**
+     * Local files queued by `play_files`, empty while a station is playing.
+     */
+    pub fn get_queue(&self) -> &[String] {
+        &self.queue
+    }
+
+    pub fn set_scrobble(&mut self, id: usize, scrobble: bool) -> Result<&Stream, PlayerError> {
+        let pos = self
+            .cfg
+            .streams
+            .iter()
+            .position(|stream| stream.id == id)
+            .ok_or(PlayerError::NotFound(id))?;
+        let previous = std::mem::replace(&mut self.cfg.streams[pos].scrobble, scrobble);
+        self.commit_cfg(false, |cfg| cfg.streams[pos].scrobble = previous)?;
+        self.listeners.emit(PlayerEvent::PlaylistChanged);
+        Ok(&self.cfg.streams[pos])
+    }
+
+    pub fn set_gain(&mut self, id: usize, gain: f64) -> Result<&Stream, PlayerError> {
+        if !gain.is_finite() || gain.abs() > MAX_GAIN {
+            return Err(PlayerError::InvalidGain(gain));
+        }
+        let pos = self
+            .cfg
+            .streams
+            .iter()
+            .position(|stream| stream.id == id)
+            .ok_or(PlayerError::NotFound(id))?;
+        let previous = std::mem::replace(&mut self.cfg.streams[pos].gain, gain);
+        self.commit_cfg(id == self.cfg.current, |cfg| {
+            cfg.streams[pos].gain = previous
+        })?;
+        self.listeners.emit(PlayerEvent::PlaylistChanged);
+        Ok(&self.cfg.streams[pos])
+    }
+
+    /**
+     * Replaces the MPV options of a stream, they take effect the next time it
+     * is played.
+     */
+    pub fn set_stream_options(
+        &mut self,
+        id: usize,
+        options: BTreeMap<String, String>,
+    ) -> Result<&Stream, PlayerError> {
+        let pos = self
+            .cfg
+            .streams
+            .iter()
+            .position(|stream| stream.id == id)
+            .ok_or(PlayerError::NotFound(id))?;
+        if let Some((name, _)) = options
+            .iter()
+            .find(|(name, value)| name.contains('\0') || value.contains('\0'))
+        {
+            return Err(PlayerError::InvalidOption(name.escape_debug().to_string()));
+        }
+        let previous = std::mem::replace(&mut self.cfg.streams[pos].options, options);
+        self.commit_cfg(false, |cfg| cfg.streams[pos].options = previous)?;
+        self.listeners.emit(PlayerEvent::PlaylistChanged);
+        Ok(&self.cfg.streams[pos])
+    }
+
+    /**
+     * Turns loudness normalisation on or off. The setting is kept as is if MPV
+     * rejects the filter chain.
+     */
+    pub fn set_normalization(&mut self, enabled: bool) -> Result<&NormalizationCfg, PlayerError> {
+        let previous = std::mem::replace(&mut self.cfg.normalization.enabled, enabled);
+        self.commit_cfg(true, |cfg| cfg.normalization.enabled = previous)?;
+        Ok(&self.cfg.normalization)
     }
 
     pub fn get_current(&self) -> Option<&Stream> {
+        if !self.queue.is_empty() {
+            return None;
+        }
         self.cfg.streams.iter().find(|x| x.id == self.cfg.current)
     }
 
-    pub fn delete(&mut self, id: usize) -> Option<Stream> {
+    pub fn delete(&mut self, id: usize) -> Result<Stream, PlayerError> {
         match self.cfg.streams.iter().position(|stream| stream.id == id) {
             Some(pos) => {
                 let deleted = self.cfg.streams.remove(pos);
-                self.dump_cfg();
-                Some(deleted)
+                let stream = deleted.clone();
+                self.commit_cfg(false, |cfg| cfg.streams.insert(pos, stream))?;
+                self.listeners.emit(PlayerEvent::PlaylistChanged);
+                Ok(deleted)
+            }
+            None => Err(PlayerError::NotFound(id)),
+        }
+    }
+
+    /**
+     * Replaces the stations with a library kept elsewhere, as zones share the
+     * stations of the default zone. Playback stops if the current station is
+     * gone.
+     */
+    pub fn set_library(&mut self, streams: Vec<Stream>) -> Result<(), PlayerError> {
+        self.cfg.last_id = streams
+            .iter()
+            .fold(0, |acc, stream| cmp::max(acc, stream.id));
+        self.cfg.streams = streams;
+        let playing_files = !self.queue.is_empty();
+        if self.state != PlaybackState::Stopped && !playing_files && self.get_current().is_none() {
+            self.stop()?;
+        }
+        self.dump_cfg()?;
+        self.listeners.emit(PlayerEvent::PlaylistChanged);
+        Ok(())
+    }
+
+    /**
+     * Re-reads the configuration file and merges it into the running player.
+     * The current stream keeps playing if it still exists with the same URL.
+     * An invalid file is rejected and leaves the in-memory state untouched.
+     * Returns whether anything changed.
+     */
+    pub fn reload(&mut self) -> Result<bool, PlayerError> {
+        let loaded = config::load::<Player>(Path::new(&self.cfg_path), 0)
+            .map_err(|err| PlayerError::Persistence(err.to_string()))?;
+        let new_cfg = match loaded {
+            Some(player) => player.cfg,
+            None => return Ok(false),
+        };
+
+        let playing_url = self.get_current().map(|stream| stream.url.to_string());
+        let old_current = self.cfg.current;
+        let old_options = self.mpv_options();
+        let changed = serde_json::to_value(&new_cfg).ok() != serde_json::to_value(&self.cfg).ok();
+        if !changed {
+            return Ok(false);
+        }
+
+        let last_id = new_cfg
+            .streams
+            .iter()
+            .fold(self.cfg.last_id, |acc, stream| cmp::max(acc, stream.id));
+        self.cfg = PlayerCfg { last_id, ..new_cfg };
+        self.listeners.emit(PlayerEvent::PlaylistChanged);
+
+        let still_playing =
+            self.cfg.streams.iter().any(|stream| {
+                stream.id == old_current && Some(&stream.url) == playing_url.as_ref()
+            });
+        if still_playing {
+            if self.cfg.current != old_current {
+                // Keep the file in line with what is actually playing
+                self.cfg.current = old_current;
+                self.dump_cfg()?;
+            }
+            if let Err(err) = self.apply_filters() {
+                eprintln!("W: Could not apply audio filters: {}", err);
+            }
+        } else if self.get_current().is_some() {
+            self.play(self.cfg.current)?;
+        } else if playing_url.is_some() {
+            self.stop()?;
+        }
+
+        if self.mpv_options() != old_options {
+            self.restart_backend()?;
+        }
+        Ok(true)
+    }
+
+    pub fn stop(&mut self) -> Result<(), PlayerError> {
+        self.cancel_fade();
+        *lock(&self.now_playing) = String::new();
+        self.queue.clear();
+        self.backend()?.command(&["stop"])?;
+        self.metrics.set_buffered(0.0);
+        self.set_state(PlaybackState::Stopped);
+        Ok(())
+    }
+
+    pub fn pause(&mut self, pause: bool) -> Result<(), PlayerError> {
+        if self.state == PlaybackState::Stopped {
+            return Ok(());
+        }
+        if pause {
+            self.cancel_fade();
+        }
+        self.backend()?
+            .command(&["set", "pause", if pause { "yes" } else { "no" }])?;
+        self.set_state(if pause {
+            PlaybackState::Paused
+        } else {
+            PlaybackState::Playing
+        });
+        Ok(())
+    }
+
+    /**
+     * Seeks `offset` seconds relative to the playback position, within the
+     * data received so far.
+     */
+    pub fn seek(&mut self, offset: f64) -> Result<(), PlayerError> {
+        if self.state == PlaybackState::Stopped {
+            return Err(PlayerError::NotPlaying);
+        }
+        self.backend()?
+            .command(&["seek", &offset.to_string(), "relative"])
+    }
+
+    /**
+     * Jumps back to the newest data received from the stream.
+     */
+    pub fn go_live(&mut self) -> Result<(), PlayerError> {
+        let behind = self.metrics.behind_live() - LIVE_MARGIN;
+        if behind > 0.0 {
+            self.seek(behind)?;
+        }
+        self.pause(false)
+    }
+
+    pub fn timeshift_status(&self) -> TimeshiftStatus {
+        TimeshiftStatus {
+            enabled: self.cfg.timeshift.is_some(),
+            state: self.state,
+            behind_live: self.metrics.behind_live(),
+            buffered: self.metrics.buffered(),
+        }
+    }
+
+    fn set_state(&mut self, state: PlaybackState) {
+        if self.state != state {
+            if state == PlaybackState::Playing {
+                self.metrics.audio_progressed();
+            }
+            self.state = state;
+            self.metrics.set_listening(match state {
+                PlaybackState::Playing if self.queue.is_empty() => Some(self.cfg.current),
+                _ => None,
+            });
+            self.listeners.emit(PlayerEvent::PlaybackChanged(state));
+        }
+    }
+
+    /**
+     * Writes the stream data as received to `path`, or stops writing it. Files
+     * are not carried over when the backend is restarted.
+     */
+    pub fn set_stream_record(&mut self, path: Option<&Path>) -> Result<(), PlayerError> {
+        let path = match path {
+            Some(path) => path
+                .to_str()
+                .ok_or_else(|| PlayerError::Persistence(format!("Invalid path {:?}", path)))?,
+            None => "",
+        };
+        self.backend()?.command(&["set", "stream-record", path])
+    }
+
+    /**
+     * Stops once MPV went idle after playing the last file of the queue.
+     */
+    fn finish_queue(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        let idle = self
+            .backend()
+            .and_then(|backend| backend.get_property_node("idle-active"));
+        if let Ok(Value::Bool(true)) = idle {
+            self.queue.clear();
+            self.set_state(PlaybackState::Stopped);
+        }
+    }
+
+    /**
+     * Reloads the current station if MPV went idle while it should be playing,
+     * i.e. the stream dropped or failed.
+     */
+    fn reconnect_stream(&mut self) {
+        if self.state != PlaybackState::Playing {
+            return;
+        }
+        // There is no current station while files are queued.
+        let stream = match self.get_current() {
+            Some(stream) => stream.clone(),
+            None => return,
+        };
+        let idle = self
+            .backend()
+            .and_then(|backend| backend.get_property_node("idle-active"));
+        if let Ok(Value::Bool(true)) = idle {
+            eprintln!("W: {} stopped, reconnecting", stream.name);
+            self.metrics.stream_reconnected();
+            if let Err(err) = self.play_stream(&stream) {
+                eprintln!("E: {}", err);
             }
-            None => None,
         }
     }
 
-    fn dump_cfg(&self) {
-        let open_result = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&self.cfg_path);
+    pub fn get_state(&self) -> PlaybackState {
+        self.state
+    }
+
+    /**
+     * Sets the volume in percent, values above 100 are clamped.
+     */
+    pub fn set_volume(&mut self, volume: u8) -> Result<(), PlayerError> {
+        let volume = cmp::min(volume, 100);
+        self.fade.volume.store(volume.into(), Ordering::SeqCst);
+        self.backend()?
+            .command(&["set", "volume", &volume.to_string()])?;
+        if self.cfg.volume != volume {
+            self.cfg.volume = volume;
+            self.dump_cfg()?;
+            self.listeners.emit(PlayerEvent::VolumeChanged(volume));
+        }
+        Ok(())
+    }
+
+    pub fn get_volume(&self) -> u8 {
+        self.cfg.volume
+    }
 
-        if let Ok(f) = open_result {
-            let _ = serde_json::to_writer_pretty(f, &self);
+    /**
+     * Plays the stream `offset` positions away from the current one in the
+     * playlist, wrapping around at both ends.
+     */
+    fn play_relative(&mut self, offset: isize) -> Result<&Stream, PlayerError> {
+        let len = self.cfg.streams.len() as isize;
+        if len == 0 {
+            return Err(PlayerError::NotFound(self.cfg.current));
+        }
+        let pos = self
+            .cfg
+            .streams
+            .iter()
+            .position(|stream| stream.id == self.cfg.current)
+            .map_or(0, |pos| (pos as isize + offset).rem_euclid(len));
+        let id = self.cfg.streams[pos as usize].id;
+        self.play(id)
+    }
+
+    pub fn next(&mut self) -> Result<&Stream, PlayerError> {
+        self.play_relative(1)
+    }
+
+    pub fn previous(&mut self) -> Result<&Stream, PlayerError> {
+        self.play_relative(-1)
+    }
+
+    fn dump_cfg(&self) -> Result<(), PlayerError> {
+        config::save(Path::new(&self.cfg_path), &self, config::BACKUP_COUNT)
+            .map_err(|err| PlayerError::Persistence(err.to_string()))
+    }
+
+    /**
+     * Applies the audio filters if asked to and saves a change made to the
+     * configuration. If either fails, `undo` reverts the change so the player
+     * and the file keep agreeing.
+     */
+    fn commit_cfg<F: FnOnce(&mut PlayerCfg)>(
+        &mut self,
+        filters: bool,
+        undo: F,
+    ) -> Result<(), PlayerError> {
+        let applied = if filters {
+            self.apply_filters()
+        } else {
+            Ok(())
+        };
+        if let Err(err) = applied.and_then(|_| self.dump_cfg()) {
+            undo(&mut self.cfg);
+            if filters {
+                if let Err(err) = self.apply_filters() {
+                    eprintln!("W: Could not apply audio filters: {}", err);
+                }
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /**
+     * Checks the parts of the audio pipeline. Audio only has to be flowing
+     * while the player is supposed to be playing.
+     */
+    pub fn health(&self) -> Health {
+        let backend = self.backend.as_ref();
+        Health {
+            mpv: backend.map_or(false, Backend::ctx_alive),
+            event_thread: backend.map_or(false, Backend::event_thread_running),
+            config_writable: health::writable(Path::new(&self.cfg_path)),
+            audio_flowing: self.state != PlaybackState::Playing
+                || self.metrics.since_progress() < health::STALL_TIMEOUT,
         }
     }
 
     pub fn get_now_playing(&self) -> String {
-        self.now_playing.lock().unwrap().to_string()
+        lock(&self.now_playing).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_crossfade_seconds() {
+        let mut player = Player {
+            state: PlaybackState::Playing,
+            ..Player::default()
+        };
+        assert_eq!(player.crossfade_seconds(), None);
+
+        player.cfg.crossfade = Some(0.0);
+        assert_eq!(player.crossfade_seconds(), None);
+
+        // Without a running context there is nothing to fade out.
+        player.cfg.crossfade = Some(2.0);
+        assert_eq!(player.crossfade_seconds(), None);
+
+        player.output = vec![("ao".to_string(), "pcm".to_string())];
+        assert_eq!(player.crossfade_seconds(), None);
+    }
+
+    #[test]
+    fn test_cancel_fade() {
+        let player = Player::default();
+        let generation = player.fade.generation.load(Ordering::SeqCst);
+        assert!(!player.fade.superseded(generation));
+
+        player.cancel_fade();
+        assert!(player.fade.superseded(generation));
+        let generation = player.fade.generation.load(Ordering::SeqCst);
+        assert!(!player.fade.superseded(generation));
+    }
+
+    #[test]
+    fn test_rollback_unsaved_changes() {
+        let mut player = Player {
+            cfg_path: "/nonexistent/radio.json".to_string(),
+            ..Player::default()
+        };
+        player.cfg.streams = vec![Stream::new(1, "Jazz".to_string(), String::new())];
+        player.cfg.last_id = 1;
+
+        assert!(player.add("Arrow".to_string(), String::new()).is_err());
+        assert!(player.set_scrobble(1, false).is_err());
+        assert!(player.set_stream_options(1, BTreeMap::new()).is_err());
+        assert!(player.delete(1).is_err());
+        assert_eq!(player.cfg.last_id, 1);
+        assert_eq!(player.cfg.streams.len(), 1);
+        assert!(player.cfg.streams[0].scrobble);
     }
 }
@@ -0,0 +1,930 @@
+use std::cmp::{self, Reverse};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::events::PlayerEvent;
+use crate::feed::{self, Feed};
+use crate::player::{lock, PlaybackState, Player, PlayerError};
+
+const TICK: Duration = Duration::from_secs(60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+ * Downloads may take long, but should not stall.
+ */
+const DOWNLOAD_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/**
+ * How often the resume position is written to disk during playback.
+ */
+const SAVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/**
+ * An episode counts as played once playback gets this close to its end, so
+ * outros and trailing silence don't need to be sat through.
+ */
+const PLAYED_MARGIN: f64 = 30.0;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Episode {
+    pub id: usize,
+    pub guid: String,
+    pub title: String,
+    pub url: String,
+    /**
+     * Seconds since the epoch.
+     */
+    #[serde(default)]
+    pub published: Option<u64>,
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub played: bool,
+    /**
+     * Seconds into the episode where playback resumes.
+     */
+    #[serde(default)]
+    pub position: f64,
+    /**
+     * Name of the downloaded file in the directory of the feed.
+     */
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Subscription {
+    #[serde(default)]
+    pub id: usize,
+    pub url: String,
+    #[serde(default)]
+    pub title: String,
+    /**
+     * Keep the newest unplayed episodes downloaded.
+     */
+    #[serde(default)]
+    pub auto_download: bool,
+    /**
+     * Seconds since the epoch of the last refresh.
+     */
+    #[serde(default)]
+    pub refreshed: u64,
+    /**
+     * Why the last refresh failed.
+     */
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub episodes: Vec<Episode>,
+}
+
+/**
+ * A subscription without its episodes.
+ */
+#[derive(Serialize)]
+pub struct SubscriptionInfo {
+    pub id: usize,
+    pub url: String,
+    pub title: String,
+    pub auto_download: bool,
+    pub refreshed: u64,
+    pub error: Option<String>,
+    pub episodes: usize,
+    pub unplayed: usize,
+}
+
+/**
+ * Contents of `podcasts.json` in the data directory.
+ */
+#[derive(Deserialize, Serialize)]
+pub struct PodcastsFile {
+    #[serde(default = "default_refresh_minutes")]
+    pub refresh_minutes: u64,
+    /**
+     * Number of unplayed episodes kept downloaded per feed with
+     * `auto_download`. Other downloads are deleted.
+     */
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    #[serde(default)]
+    pub feeds: Vec<Subscription>,
+
+    #[serde(skip, default)]
+    last_id: usize,
+}
+
+#[derive(Debug)]
+pub enum PodcastError {
+    NotFound(usize),
+    EpisodeNotFound(usize, usize),
+    InvalidUrl(String),
+    Player(PlayerError),
+    Persistence(String),
+}
+
+struct State {
+    file: PodcastsFile,
+    path: PathBuf,
+}
+
+enum Job {
+    Event(PlayerEvent),
+    /**
+     * The player is about to play the episode of the feed from the file or
+     * URL.
+     */
+    Playing(usize, usize, String),
+}
+
+/**
+ * Episodes as seen by the thread keeping the resume positions.
+ */
+#[derive(Default)]
+struct Tracking {
+    /**
+     * Episode whose file MPV is playing, positions are credited to it.
+     */
+    playing: Option<(usize, usize)>,
+    /**
+     * Episode asked for and its file, until MPV starts that file.
+     */
+    requested: Option<((usize, usize), String)>,
+}
+
+/**
+ * Podcast subscriptions, shared by the HTTP handlers, the thread refreshing
+ * the feeds and the thread tracking the playback position.
+ */
+#[derive(Clone)]
+pub struct Podcasts {
+    state: Arc<Mutex<State>>,
+    player: Arc<Mutex<Player>>,
+    dir: PathBuf,
+    downloading: Arc<AtomicBool>,
+    tx: Sender<Job>,
+}
+
+fn default_refresh_minutes() -> u64 {
+    60
+}
+
+fn default_keep() -> usize {
+    3
+}
+
+impl Default for PodcastsFile {
+    fn default() -> Self {
+        PodcastsFile {
+            refresh_minutes: default_refresh_minutes(),
+            keep: default_keep(),
+            feeds: Vec::new(),
+            last_id: 0,
+        }
+    }
+}
+
+impl PodcastError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PodcastError::NotFound(_) => "not_found",
+            PodcastError::EpisodeNotFound(..) => "not_found",
+            PodcastError::InvalidUrl(_) => "invalid_url",
+            PodcastError::Player(err) => err.code(),
+            PodcastError::Persistence(_) => "persistence_failure",
+        }
+    }
+}
+
+impl fmt::Display for PodcastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PodcastError::NotFound(id) => write!(f, "No podcast with ID {}", id),
+            PodcastError::EpisodeNotFound(id, episode) => {
+                write!(f, "No episode {} in podcast {}", episode, id)
+            }
+            PodcastError::InvalidUrl(url) => write!(f, "URL invalid or unsupported: {}", url),
+            PodcastError::Player(err) => write!(f, "{}", err),
+            PodcastError::Persistence(msg) => write!(f, "Failed to store podcasts: {}", msg),
+        }
+    }
+}
+
+impl From<PlayerError> for PodcastError {
+    fn from(err: PlayerError) -> Self {
+        PodcastError::Player(err)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/**
+ * Feeds and episodes are only fetched over HTTP, a feed must not be able to
+ * point the player at local files.
+ */
+fn is_http(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+fn fetch(url: &str) -> Result<Feed, String> {
+    let xml = ureq::AgentBuilder::new()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .get(url)
+        .call()
+        .map_err(|err| err.to_string())?
+        .into_string()
+        .map_err(|err| err.to_string())?;
+    feed::parse(&xml)
+}
+
+fn download(url: &str, path: &Path) -> Result<(), String> {
+    if !is_http(url) {
+        return Err("not an HTTP URL".to_string());
+    }
+    let partial = path.with_extension("part");
+    let response = ureq::AgentBuilder::new()
+        .timeout_connect(FETCH_TIMEOUT)
+        .timeout_read(DOWNLOAD_READ_TIMEOUT)
+        .build()
+        .get(url)
+        .call()
+        .map_err(|err| err.to_string())?;
+    let mut file = File::create(&partial).map_err(|err| err.to_string())?;
+    io::copy(&mut response.into_reader(), &mut file).map_err(|err| err.to_string())?;
+    fs::rename(&partial, path).map_err(|err| err.to_string())
+}
+
+/**
+ * File name for a downloaded episode, keeping the extension of the URL so
+ * MPV can tell the format.
+ */
+fn file_name(episode: &Episode) -> String {
+    let path = episode.url.split(&['?', '#'][..]).next().unwrap_or("");
+    let name = path.rsplit('/').next().unwrap_or("");
+    let extension = match name.rsplit_once('.') {
+        Some((_, ext))
+            if !ext.is_empty()
+                && ext.len() <= 5
+                && ext.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            ext.to_ascii_lowercase()
+        }
+        _ => "mp3".to_string(),
+    };
+    format!("{}.{}", episode.id, extension)
+}
+
+impl Subscription {
+    /**
+     * Takes over the episodes of a freshly fetched feed. Episodes keep their
+     * ID and state across refreshes, those gone from the feed are dropped.
+     */
+    fn merge(&mut self, feed: Feed) {
+        if !feed.title.is_empty() {
+            self.title = feed.title;
+        }
+        let mut last_id = self
+            .episodes
+            .iter()
+            .fold(0, |acc, episode| cmp::max(acc, episode.id));
+        let mut old = std::mem::take(&mut self.episodes);
+        for item in feed.items {
+            if !is_http(&item.url)
+                || self
+                    .episodes
+                    .iter()
+                    .any(|episode| episode.guid == item.guid)
+            {
+                continue;
+            }
+            let episode = match old.iter().position(|episode| episode.guid == item.guid) {
+                Some(pos) => Episode {
+                    title: item.title,
+                    url: item.url,
+                    published: item.published,
+                    duration: item.duration,
+                    ..old.remove(pos)
+                },
+                None => {
+                    last_id += 1;
+                    Episode {
+                        id: last_id,
+                        guid: item.guid,
+                        title: item.title,
+                        url: item.url,
+                        published: item.published,
+                        duration: item.duration,
+                        played: false,
+                        position: 0.0,
+                        file: None,
+                    }
+                }
+            };
+            self.episodes.push(episode);
+        }
+        self.episodes
+            .sort_by_key(|episode| Reverse(episode.published));
+    }
+
+    /**
+     * IDs of the episodes that should be downloaded.
+     */
+    fn wanted(&self, keep: usize) -> BTreeSet<usize> {
+        if !self.auto_download {
+            return BTreeSet::new();
+        }
+        self.episodes
+            .iter()
+            .filter(|episode| !episode.played)
+            .take(keep)
+            .map(|episode| episode.id)
+            .collect()
+    }
+
+    fn info(&self) -> SubscriptionInfo {
+        SubscriptionInfo {
+            id: self.id,
+            url: self.url.to_string(),
+            title: self.title.to_string(),
+            auto_download: self.auto_download,
+            refreshed: self.refreshed,
+            error: self.error.clone(),
+            episodes: self.episodes.len(),
+            unplayed: self
+                .episodes
+                .iter()
+                .filter(|episode| !episode.played)
+                .count(),
+        }
+    }
+}
+
+impl State {
+    fn feed(&mut self, id: usize) -> Result<&mut Subscription, PodcastError> {
+        self.file
+            .feeds
+            .iter_mut()
+            .find(|feed| feed.id == id)
+            .ok_or(PodcastError::NotFound(id))
+    }
+
+    fn episode(&mut self, id: usize, episode: usize) -> Result<&mut Episode, PodcastError> {
+        self.feed(id)?
+            .episodes
+            .iter_mut()
+            .find(|candidate| candidate.id == episode)
+            .ok_or(PodcastError::EpisodeNotFound(id, episode))
+    }
+
+    fn save(&self) -> Result<(), PodcastError> {
+        config::save_unversioned(&self.path, &self.file, config::BACKUP_COUNT)
+            .map_err(|err| PodcastError::Persistence(err.to_string()))
+    }
+
+    /**
+     * Saves changes made in the background, such as resume positions, which
+     * happen too often to rotate the backups for.
+     */
+    fn save_progress(&self) {
+        if let Err(err) = config::save_unversioned(&self.path, &self.file, 0) {
+            eprintln!("W: {}", PodcastError::Persistence(err.to_string()));
+        }
+    }
+
+    /**
+     * Applies a playback position reported by the player to an episode.
+     * Returns whether the episode is now played.
+     */
+    fn update_position(
+        &mut self,
+        (id, episode): (usize, usize),
+        position: f64,
+        duration: Option<f64>,
+    ) -> bool {
+        let episode = match self.episode(id, episode) {
+            Ok(episode) => episode,
+            Err(_) => return true,
+        };
+        let duration = duration.or(episode.duration);
+        if duration.map_or(false, |duration| position >= duration - PLAYED_MARGIN) {
+            episode.played = true;
+            episode.position = 0.0;
+            true
+        } else {
+            episode.position = position;
+            false
+        }
+    }
+}
+
+impl Podcasts {
+    /**
+     * Loads the subscriptions from `path` and starts refreshing them,
+     * downloads are stored below `dir`.
+     */
+    pub fn spawn(
+        player: Arc<Mutex<Player>>,
+        path: PathBuf,
+        dir: PathBuf,
+    ) -> Result<Self, PodcastError> {
+        let mut file = config::load_unversioned::<PodcastsFile>(&path, config::BACKUP_COUNT)
+            .map_err(|err| PodcastError::Persistence(err.to_string()))?
+            .unwrap_or_default();
+        file.last_id = file
+            .feeds
+            .iter()
+            .fold(0, |acc, feed| cmp::max(acc, feed.id));
+
+        let (tx, rx) = channel();
+        let event_tx = tx.clone();
+        lock(&player).listeners().add(move |event| match event {
+            PlayerEvent::PositionChanged(..)
+            | PlayerEvent::FileStarted(_)
+            | PlayerEvent::PlaybackChanged(_) => event_tx.send(Job::Event(event.clone())).is_ok(),
+            _ => true,
+        });
+
+        let podcasts = Podcasts {
+            state: Arc::new(Mutex::new(State { file, path })),
+            player,
+            dir,
+            downloading: Arc::default(),
+            tx,
+        };
+
+        let tracker = podcasts.clone();
+        thread::spawn(move || {
+            let mut tracking = Tracking::default();
+            let mut saved = Instant::now();
+            for job in rx {
+                tracker.track(job, &mut tracking, &mut saved);
+            }
+        });
+
+        let refresher = podcasts.clone();
+        thread::spawn(move || loop {
+            refresher.refresh_due();
+            refresher.download();
+            thread::sleep(TICK);
+        });
+        Ok(podcasts)
+    }
+
+    /**
+     * Keeps the resume position of the episode that is playing. Positions
+     * only count once MPV started the file of the episode, until then they
+     * may still be those of the file it replaces.
+     */
+    fn track(&self, job: Job, tracking: &mut Tracking, saved: &mut Instant) {
+        let mut state = lock(&self.state);
+        match job {
+            Job::Playing(id, episode, source) => {
+                tracking.requested = Some(((id, episode), source));
+                if tracking.playing.take().is_none() {
+                    return;
+                }
+            }
+            Job::Event(PlayerEvent::FileStarted(path)) => {
+                let previous = tracking.playing.take();
+                if matches!(&tracking.requested, Some((_, source)) if *source == path) {
+                    tracking.playing = tracking.requested.take().map(|(episode, _)| episode);
+                }
+                if previous.is_none() {
+                    return;
+                }
+            }
+            Job::Event(PlayerEvent::PositionChanged(position, duration)) => {
+                let current = match tracking.playing {
+                    Some(current) => current,
+                    None => return,
+                };
+                if state.update_position(current, position, duration) {
+                    tracking.playing = None;
+                } else if saved.elapsed() < SAVE_INTERVAL {
+                    return;
+                }
+            }
+            Job::Event(PlayerEvent::PlaybackChanged(PlaybackState::Playing)) => return,
+            Job::Event(_) => {
+                if tracking.playing.is_none() {
+                    return;
+                }
+            }
+        }
+        *saved = Instant::now();
+        state.save_progress();
+    }
+
+    fn feed_dir(&self, id: usize) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    pub fn list(&self) -> Vec<SubscriptionInfo> {
+        lock(&self.state)
+            .file
+            .feeds
+            .iter()
+            .map(Subscription::info)
+            .collect()
+    }
+
+    pub fn get(&self, id: usize) -> Result<Subscription, PodcastError> {
+        lock(&self.state).feed(id).map(|feed| feed.clone())
+    }
+
+    /**
+     * Subscribes to a feed, its episodes are fetched in the background.
+     */
+    pub fn subscribe(
+        &self,
+        url: String,
+        auto_download: bool,
+    ) -> Result<SubscriptionInfo, PodcastError> {
+        if !is_http(&url) {
+            return Err(PodcastError::InvalidUrl(url));
+        }
+
+        let info = {
+            let mut state = lock(&self.state);
+            state.file.last_id += 1;
+            let feed = Subscription {
+                id: state.file.last_id,
+                title: url.to_string(),
+                url,
+                auto_download,
+                refreshed: 0,
+                error: None,
+                episodes: Vec::new(),
+            };
+            let info = feed.info();
+            state.file.feeds.push(feed);
+            state.save()?;
+            info
+        };
+        self.spawn_refresh(info.id);
+        Ok(info)
+    }
+
+    pub fn unsubscribe(&self, id: usize) -> Result<SubscriptionInfo, PodcastError> {
+        let mut state = lock(&self.state);
+        let pos = state
+            .file
+            .feeds
+            .iter()
+            .position(|feed| feed.id == id)
+            .ok_or(PodcastError::NotFound(id))?;
+        let feed = state.file.feeds.remove(pos);
+        state.save()?;
+        let dir = self.feed_dir(id);
+        if dir.exists() {
+            if let Err(err) = fs::remove_dir_all(&dir) {
+                eprintln!("W: Cannot delete {}: {}", dir.display(), err);
+            }
+        }
+        Ok(feed.info())
+    }
+
+    pub fn set_auto_download(
+        &self,
+        id: usize,
+        auto_download: bool,
+    ) -> Result<SubscriptionInfo, PodcastError> {
+        let info = {
+            let mut state = lock(&self.state);
+            let feed = state.feed(id)?;
+            feed.auto_download = auto_download;
+            let info = feed.info();
+            state.save()?;
+            info
+        };
+        self.spawn_download();
+        Ok(info)
+    }
+
+    /**
+     * Marks an episode as played or unplayed, either way it starts from the
+     * beginning the next time.
+     */
+    pub fn set_played(
+        &self,
+        id: usize,
+        episode: usize,
+        played: bool,
+    ) -> Result<Episode, PodcastError> {
+        let mut state = lock(&self.state);
+        let episode = state.episode(id, episode)?;
+        episode.played = played;
+        episode.position = 0.0;
+        let episode = episode.clone();
+        state.save()?;
+        Ok(episode)
+    }
+
+    /**
+     * Plays an episode from its download if there is one, resuming where it
+     * was left off.
+     */
+    pub fn play(&self, id: usize, episode: usize) -> Result<Episode, PodcastError> {
+        let episode = lock(&self.state).episode(id, episode)?.clone();
+        let source = match &episode.file {
+            Some(file) => self.feed_dir(id).join(file).to_string_lossy().to_string(),
+            None if is_http(&episode.url) => episode.url.to_string(),
+            None => return Err(PodcastError::InvalidUrl(episode.url)),
+        };
+        let start = if episode.played {
+            0.0
+        } else {
+            episode.position
+        };
+        // Queued ahead of the start of the file, which the tracker waits for.
+        let _ = self
+            .tx
+            .send(Job::Playing(id, episode.id, source.to_string()));
+        lock(&self.player).play_files(vec![source], false, start)?;
+        Ok(episode)
+    }
+
+    pub fn spawn_refresh(&self, id: usize) {
+        let podcasts = self.clone();
+        thread::spawn(move || {
+            podcasts.refresh(id);
+            podcasts.download();
+        });
+    }
+
+    fn spawn_download(&self) {
+        let podcasts = self.clone();
+        thread::spawn(move || podcasts.download());
+    }
+
+    fn refresh_due(&self) {
+        let due: Vec<usize> = {
+            let state = lock(&self.state);
+            let interval = state.file.refresh_minutes * 60;
+            let time = now();
+            state
+                .file
+                .feeds
+                .iter()
+                .filter(|feed| feed.refreshed + interval <= time)
+                .map(|feed| feed.id)
+                .collect()
+        };
+        for id in due {
+            self.refresh(id);
+        }
+    }
+
+    fn refresh(&self, id: usize) {
+        let url = match lock(&self.state).feed(id) {
+            Ok(feed) => feed.url.to_string(),
+            Err(_) => return,
+        };
+        let result = fetch(&url);
+
+        let mut state = lock(&self.state);
+        let feed = match state.feed(id) {
+            Ok(feed) => feed,
+            Err(_) => return,
+        };
+        match result {
+            Ok(fetched) => {
+                feed.merge(fetched);
+                feed.error = None;
+            }
+            Err(err) => {
+                eprintln!("W: Cannot refresh podcast {}: {}", url, err);
+                feed.error = Some(err);
+            }
+        }
+        feed.refreshed = now();
+        if let Err(err) = state.save() {
+            eprintln!("W: {}", err);
+        }
+    }
+
+    /**
+     * Downloads the wanted episodes of every feed and deletes the files that
+     * are no longer wanted.
+     */
+    fn download(&self) {
+        if self.downloading.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let feeds: Vec<(usize, BTreeSet<usize>)> = {
+            let state = lock(&self.state);
+            state
+                .file
+                .feeds
+                .iter()
+                .map(|feed| (feed.id, feed.wanted(state.file.keep)))
+                .collect()
+        };
+        for (id, wanted) in feeds {
+            self.enforce_retention(id, &wanted);
+            for episode in wanted {
+                self.download_episode(id, episode);
+            }
+        }
+        self.downloading.store(false, Ordering::SeqCst);
+    }
+
+    fn enforce_retention(&self, id: usize, wanted: &BTreeSet<usize>) {
+        let dir = self.feed_dir(id);
+        let mut keep = BTreeSet::new();
+        {
+            let mut state = lock(&self.state);
+            let feed = match state.feed(id) {
+                Ok(feed) => feed,
+                Err(_) => return,
+            };
+            let mut changed = false;
+            for episode in feed.episodes.iter_mut() {
+                if !wanted.contains(&episode.id) {
+                    changed |= episode.file.take().is_some();
+                } else if let Some(file) = &episode.file {
+                    keep.insert(file.to_string());
+                }
+            }
+            if changed {
+                state.save_progress();
+            }
+        }
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !keep.contains(&name) {
+                if let Err(err) = fs::remove_file(entry.path()) {
+                    eprintln!("W: Cannot delete {}: {}", entry.path().display(), err);
+                }
+            }
+        }
+    }
+
+    fn download_episode(&self, id: usize, episode: usize) {
+        let (url, name) = match lock(&self.state).episode(id, episode) {
+            Ok(episode) if episode.file.is_none() => (episode.url.to_string(), file_name(episode)),
+            _ => return,
+        };
+        let dir = self.feed_dir(id);
+        let result = fs::create_dir_all(&dir)
+            .map_err(|err| err.to_string())
+            .and_then(|()| download(&url, &dir.join(&name)));
+        if let Err(err) = result {
+            eprintln!("W: Cannot download {}: {}", url, err);
+            return;
+        }
+
+        let mut state = lock(&self.state);
+        if let Ok(episode) = state.episode(id, episode) {
+            episode.file = Some(name);
+        }
+        state.save_progress();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::testutil::feed_fixture;
+
+    fn subscription() -> Subscription {
+        Subscription {
+            id: 1,
+            url: "https://example.com/podcast.rss".to_string(),
+            title: String::new(),
+            auto_download: true,
+            refreshed: 0,
+            error: None,
+            episodes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut feed = subscription();
+        feed.merge(feed_fixture("podcast.rss"));
+        assert_eq!(feed.title, "Radio & Co");
+        assert_eq!(feed.episodes.len(), 2);
+        assert_eq!(feed.episodes[0].id, 1);
+        assert_eq!(feed.episodes[1].id, 2);
+
+        feed.episodes[1].played = true;
+        feed.episodes[0].position = 12.5;
+        feed.episodes[0].file = Some("1.mp3".to_string());
+        feed.merge(feed_fixture("podcast.rss"));
+        assert_eq!(feed.episodes.len(), 2);
+        assert!(feed.episodes[1].played);
+        assert_eq!(feed.episodes[0].position, 12.5);
+        assert_eq!(feed.episodes[0].file.as_deref(), Some("1.mp3"));
+
+        // The Atom episode is new, the RSS episodes are gone from the feed.
+        feed.merge(feed_fixture("podcast.atom"));
+        assert_eq!(feed.episodes.len(), 1);
+        assert_eq!(feed.episodes[0].id, 3);
+
+        let mut local = feed_fixture("podcast.atom");
+        local.items[0].url = "file:///etc/shadow".to_string();
+        feed.merge(local);
+        assert!(feed.episodes.is_empty());
+    }
+
+    #[test]
+    fn test_wanted() {
+        let mut feed = subscription();
+        feed.merge(feed_fixture("podcast.rss"));
+        assert_eq!(feed.wanted(1).into_iter().collect::<Vec<_>>(), vec![1]);
+        feed.episodes[0].played = true;
+        assert_eq!(feed.wanted(1).into_iter().collect::<Vec<_>>(), vec![2]);
+        feed.auto_download = false;
+        assert!(feed.wanted(1).is_empty());
+    }
+
+    #[test]
+    fn test_update_position() {
+        let mut feed = subscription();
+        feed.merge(feed_fixture("podcast.rss"));
+        let mut state = State {
+            file: PodcastsFile {
+                feeds: vec![feed],
+                ..Default::default()
+            },
+            path: PathBuf::new(),
+        };
+
+        assert!(!state.update_position((1, 2), 600.0, None));
+        assert_eq!(state.episode(1, 2).unwrap().position, 600.0);
+        assert!(state.update_position((1, 2), 1780.0, None));
+        assert!(state.episode(1, 2).unwrap().played);
+        assert_eq!(state.episode(1, 2).unwrap().position, 0.0);
+        assert!(!state.update_position((1, 1), 1780.0, Some(4000.0)));
+        assert!(state.update_position((1, 9), 1.0, None));
+    }
+
+    #[test]
+    fn test_track() {
+        let mut feed = subscription();
+        feed.merge(feed_fixture("podcast.rss"));
+        let podcasts = Podcasts {
+            state: Arc::new(Mutex::new(State {
+                file: PodcastsFile {
+                    feeds: vec![feed],
+                    ..Default::default()
+                },
+                path: PathBuf::from("/nonexistent/podcasts.json"),
+            })),
+            player: Arc::default(),
+            dir: PathBuf::new(),
+            downloading: Arc::default(),
+            tx: channel().0,
+        };
+        let mut tracking = Tracking::default();
+        let mut saved = Instant::now();
+        let mut track = |job| podcasts.track(job, &mut tracking, &mut saved);
+
+        // The file being replaced still reports its position.
+        track(Job::Playing(1, 2, "/2.mp3".to_string()));
+        track(Job::Event(PlayerEvent::PositionChanged(900.0, None)));
+        track(Job::Event(PlayerEvent::FileStarted("/1.mp3".to_string())));
+        track(Job::Event(PlayerEvent::PositionChanged(901.0, None)));
+        assert_eq!(lock(&podcasts.state).episode(1, 2).unwrap().position, 0.0);
+
+        track(Job::Event(PlayerEvent::FileStarted("/2.mp3".to_string())));
+        track(Job::Event(PlayerEvent::PositionChanged(30.0, None)));
+        assert_eq!(lock(&podcasts.state).episode(1, 2).unwrap().position, 30.0);
+    }
+
+    #[test]
+    fn test_file_name() {
+        let mut feed = subscription();
+        feed.merge(feed_fixture("podcast.atom"));
+        let mut episode = feed.episodes[0].clone();
+        assert_eq!(file_name(&episode), "1.ogg");
+        episode.url = "https://example.com/listen?id=5".to_string();
+        assert_eq!(file_name(&episode), "1.mp3");
+        episode.url = "https://example.com/a.b/Show.M4A?token=1".to_string();
+        assert_eq!(file_name(&episode), "1.m4a");
+    }
+}
@@ -0,0 +1,426 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::PlayerEvent;
+use crate::player::{lock, PlaybackState, Player, PlayerError};
+
+pub const DEFAULT_TEMPLATE: &str = "{timestamp} {station}";
+
+/**
+ * Matroska can hold whatever codec a station sends, it is used when the
+ * template does not name an extension.
+ */
+const DEFAULT_EXTENSION: &str = "mka";
+
+/**
+ * Body of `POST /record`. The template may contain `{station}`, `{timestamp}`
+ * and `{title}`.
+ */
+#[derive(Deserialize, Default)]
+pub struct RecordRequest {
+    /**
+     * Seconds after which the recording stops by itself.
+     */
+    pub duration: Option<u64>,
+    pub template: Option<String>,
+    /**
+     * Start a new file whenever the ICY title changes.
+     */
+    #[serde(default)]
+    pub split: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RecordStatus {
+    pub file: String,
+    pub files: Vec<String>,
+    pub started: u64,
+    pub until: Option<u64>,
+    pub split: bool,
+}
+
+#[derive(Serialize)]
+pub struct RecordingFile {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+}
+
+struct Active {
+    template: String,
+    split: bool,
+    until: Option<Instant>,
+    status: RecordStatus,
+}
+
+enum Job {
+    Event(PlayerEvent),
+    Start(RecordRequest, Sender<Result<RecordStatus, PlayerError>>),
+    Stop(Sender<Result<Option<RecordStatus>, PlayerError>>),
+    Status(Sender<Option<RecordStatus>>),
+}
+
+/**
+ * Handle to the worker that owns the recording. The worker follows station and
+ * title changes and switches files, the HTTP handlers talk to it through
+ * `Job`s so they never wait for the player lock while holding it.
+ */
+#[derive(Clone)]
+pub struct Recorder {
+    tx: Sender<Job>,
+    dir: PathBuf,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/**
+ * Formats seconds since the epoch as a sortable UTC `YYYYMMDD-HHMMSS`.
+ */
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/**
+ * Replaces characters that can't or shouldn't appear in a file name.
+ */
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c == '/' || c == '\\' || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .trim()
+        .to_string()
+}
+
+/**
+ * Expands `template` into a file name. If the name would be empty, e.g. for
+ * `{title}` while no title is known, it falls back to the station and time.
+ */
+pub fn file_name(template: &str, station: &str, title: &str, timestamp: u64) -> String {
+    let expand = |template: &str| {
+        sanitize(
+            &template
+                .replace("{station}", station)
+                .replace("{title}", title)
+                .replace("{timestamp}", &format_timestamp(timestamp)),
+        )
+    };
+
+    let (stem, extension) = match Path::new(template).extension() {
+        Some(extension) => {
+            let extension = extension.to_string_lossy();
+            let stem = &template[..template.len() - extension.len() - 1];
+            (stem, extension.to_string())
+        }
+        None => (template, DEFAULT_EXTENSION.to_string()),
+    };
+
+    let mut name = expand(stem);
+    if name.is_empty() {
+        name = expand(DEFAULT_TEMPLATE);
+    }
+    format!("{}.{}", name, extension)
+}
+
+/**
+ * Resolves the name of a recording to its path, rejecting names that would
+ * point outside of the recordings directory.
+ */
+pub fn recording_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+        None
+    } else {
+        Some(dir.join(name))
+    }
+}
+
+pub fn list(dir: &Path) -> io::Result<Vec<RecordingFile>> {
+    let mut files = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(files),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        files.push(RecordingFile {
+            name: entry.file_name().to_string_lossy().to_string(),
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |duration| duration.as_secs()),
+        });
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+/**
+ * Picks a path for a new file that does not overwrite an earlier one, titles
+ * can repeat within the same second.
+ */
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let stem = Path::new(name)
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().to_string());
+    let extension = Path::new(name)
+        .extension()
+        .map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()));
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/**
+ * Starts writing to a new file named after the current station and title.
+ */
+fn open_file(
+    player: &Arc<Mutex<Player>>,
+    dir: &Path,
+    template: &str,
+) -> Result<String, PlayerError> {
+    let mut player = lock(player);
+    if player.get_state() == PlaybackState::Stopped {
+        return Err(PlayerError::NotPlaying);
+    }
+    let station = match player.get_current() {
+        Some(stream) => stream.name.to_string(),
+        None => return Err(PlayerError::NotPlaying),
+    };
+    let name = file_name(template, &station, &player.get_now_playing(), now());
+
+    fs::create_dir_all(dir).map_err(|err| PlayerError::Persistence(err.to_string()))?;
+    let path = unique_path(dir, &name);
+    player.set_stream_record(Some(&path))?;
+    Ok(path
+        .file_name()
+        .map_or(name, |name| name.to_string_lossy().to_string()))
+}
+
+fn close_file(player: &Arc<Mutex<Player>>) -> Result<(), PlayerError> {
+    lock(player).set_stream_record(None)
+}
+
+fn next_file(player: &Arc<Mutex<Player>>, dir: &Path, active: &mut Option<Active>) {
+    let result = match active {
+        Some(recording) => open_file(player, dir, &recording.template),
+        None => return,
+    };
+    match (result, active.as_mut()) {
+        (Ok(file), Some(recording)) => {
+            recording.status.files.push(file.to_string());
+            recording.status.file = file;
+        }
+        (Err(err), _) => {
+            eprintln!("W: Recording stopped: {}", err);
+            *active = None;
+        }
+        (Ok(_), None) => (),
+    }
+}
+
+fn run(player: Arc<Mutex<Player>>, dir: PathBuf, rx: Receiver<Job>) {
+    let mut active: Option<Active> = None;
+
+    loop {
+        let job = match active.as_ref().and_then(|recording| recording.until) {
+            Some(until) => rx.recv_timeout(until.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match job {
+            Ok(Job::Start(request, reply)) => {
+                let RecordRequest {
+                    duration,
+                    template,
+                    split,
+                } = request;
+                let template = template.unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+                let result = open_file(&player, &dir, &template).map(|file| {
+                    let started = now();
+                    let recording = Active {
+                        template,
+                        split,
+                        until: duration.map(|secs| Instant::now() + Duration::from_secs(secs)),
+                        status: RecordStatus {
+                            files: vec![file.to_string()],
+                            file,
+                            started,
+                            until: duration.map(|secs| started + secs),
+                            split,
+                        },
+                    };
+                    let status = recording.status.clone();
+                    active = Some(recording);
+                    status
+                });
+                let _ = reply.send(result);
+            }
+            Ok(Job::Stop(reply)) => {
+                let result = match active.take() {
+                    Some(recording) => close_file(&player).map(|_| Some(recording.status)),
+                    None => Ok(None),
+                };
+                let _ = reply.send(result);
+            }
+            Ok(Job::Status(reply)) => {
+                let _ = reply.send(active.as_ref().map(|recording| recording.status.clone()));
+            }
+            Ok(Job::Event(PlayerEvent::TitleChanged(_))) => {
+                if active.as_ref().map_or(false, |recording| recording.split) {
+                    next_file(&player, &dir, &mut active);
+                }
+            }
+            Ok(Job::Event(PlayerEvent::StreamChanged(Some(_)))) => {
+                next_file(&player, &dir, &mut active);
+            }
+            Ok(Job::Event(PlayerEvent::PlaybackChanged(PlaybackState::Stopped)))
+            | Err(RecvTimeoutError::Timeout) => {
+                if active.take().is_some() {
+                    if let Err(err) = close_file(&player) {
+                        eprintln!("W: Failed to stop recording: {}", err);
+                    }
+                }
+            }
+            Ok(Job::Event(_)) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+impl Recorder {
+    /**
+     * Starts the recording worker, files are written to `dir`.
+     */
+    pub fn spawn(player: Arc<Mutex<Player>>, dir: PathBuf) -> Self {
+        let (tx, rx) = channel();
+
+        let event_tx = tx.clone();
+        lock(&player)
+            .listeners()
+            .add(move |event| event_tx.send(Job::Event(event.clone())).is_ok());
+
+        let thread_dir = dir.clone();
+        thread::spawn(move || run(player, thread_dir, rx));
+        Recorder { tx, dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn worker_gone() -> PlayerError {
+        PlayerError::Persistence("The recording worker is not running".to_string())
+    }
+
+    pub fn start(&self, request: RecordRequest) -> Result<RecordStatus, PlayerError> {
+        let (reply, rx) = channel();
+        self.tx
+            .send(Job::Start(request, reply))
+            .map_err(|_| Self::worker_gone())?;
+        rx.recv().map_err(|_| Self::worker_gone())?
+    }
+
+    pub fn stop(&self) -> Result<Option<RecordStatus>, PlayerError> {
+        let (reply, rx) = channel();
+        self.tx
+            .send(Job::Stop(reply))
+            .map_err(|_| Self::worker_gone())?;
+        rx.recv().map_err(|_| Self::worker_gone())?
+    }
+
+    pub fn status(&self) -> Option<RecordStatus> {
+        let (reply, rx) = channel();
+        self.tx.send(Job::Status(reply)).ok()?;
+        rx.recv().ok()?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "19700101-000000");
+        assert_eq!(format_timestamp(951_782_400), "20000229-000000");
+        assert_eq!(format_timestamp(1_792_417_815), "20261019-135015");
+    }
+
+    #[test]
+    fn test_file_name() {
+        assert_eq!(
+            file_name("{timestamp} {station}", "AC/DC Radio", "", 0),
+            "19700101-000000 AC_DC Radio.mka"
+        );
+        assert_eq!(
+            file_name("{station} - {title}.mp3", "Jazz", "Miles - So What", 0),
+            "Jazz - Miles - So What.mp3"
+        );
+        assert_eq!(file_name("{title}", "", "../etc", 0), "_etc.mka");
+        assert_eq!(
+            file_name("{title}", "Jazz", "", 0),
+            "19700101-000000 Jazz.mka"
+        );
+        assert_eq!(file_name("{title}.mp3", "", "", 0), "19700101-000000.mp3");
+    }
+
+    #[test]
+    fn test_recording_path() {
+        let dir = Path::new("/data/recordings");
+        assert_eq!(recording_path(dir, "show.mka"), Some(dir.join("show.mka")));
+        assert_eq!(recording_path(dir, "../radio.json"), None);
+        assert_eq!(recording_path(dir, ".hidden"), None);
+    }
+}
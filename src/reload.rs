@@ -0,0 +1,144 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use libc::{c_int, c_void};
+
+use crate::player::{lock, Player};
+
+static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+const POLL_INTERVAL_MS: c_int = 500;
+
+extern "C" fn on_sighup(_: c_int) {
+    SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/**
+ * Minimal inotify watch on the directory containing the configuration file.
+ * The directory is watched instead of the file itself because the file is
+ * replaced by a rename whenever it is saved.
+ */
+struct Watch {
+    fd: c_int,
+    file_name: Vec<u8>,
+}
+
+impl Watch {
+    fn new(path: &Path) -> Option<Watch> {
+        let dir = path.parent()?;
+        let file_name = path.file_name()?.as_bytes().to_vec();
+        let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return None;
+        }
+
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            unsafe { libc::close(fd) };
+            return None;
+        }
+
+        Some(Watch { fd, file_name })
+    }
+
+    /**
+     * Waits for at most `POLL_INTERVAL_MS` and returns whether the watched file
+     * was written in the meantime.
+     */
+    fn wait(&self) -> bool {
+        let mut pfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pfd, 1, POLL_INTERVAL_MS) } <= 0 {
+            return false;
+        }
+
+        let mut buf = [0u8; 4096];
+        let mut changed = false;
+        loop {
+            let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+            if len <= 0 {
+                break;
+            }
+
+            let mut offset = 0;
+            while offset + std::mem::size_of::<libc::inotify_event>() <= len as usize {
+                let event = unsafe {
+                    std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name_start = offset + std::mem::size_of::<libc::inotify_event>();
+                let name = &buf[name_start..name_start + event.len as usize];
+                let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+                if name == &self.file_name[..] {
+                    changed = true;
+                }
+                offset = name_start + event.len as usize;
+            }
+        }
+        changed
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn reload(player: &Mutex<Player>, reason: &str) {
+    match lock(player).reload() {
+        Ok(true) => eprintln!("W: Reloaded configuration ({})", reason),
+        Ok(false) => (),
+        Err(err) => eprintln!("W: Not reloading configuration: {}", err),
+    }
+}
+
+/**
+ * Reloads the configuration of `player` on SIGHUP and, if `watch` is set,
+ * whenever the file at `path` changes on disk.
+ */
+pub fn spawn(player: Arc<Mutex<Player>>, path: PathBuf, watch: bool) -> JoinHandle<()> {
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            on_sighup as extern "C" fn(c_int) as libc::sighandler_t,
+        )
+    };
+
+    let watch = if watch {
+        let inotify = Watch::new(&path);
+        if inotify.is_none() {
+            eprintln!(
+                "W: Cannot watch {}, only reloading on SIGHUP",
+                path.display()
+            );
+        }
+        inotify
+    } else {
+        None
+    };
+
+    thread::spawn(move || loop {
+        let changed = match watch {
+            Some(ref inotify) => inotify.wait(),
+            None => {
+                thread::sleep(std::time::Duration::from_millis(POLL_INTERVAL_MS as u64));
+                false
+            }
+        };
+
+        if SIGHUP_RECEIVED.swap(false, Ordering::SeqCst) {
+            reload(&player, "SIGHUP");
+        } else if changed {
+            reload(&player, "file changed");
+        }
+    })
+}
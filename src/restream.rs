@@ -0,0 +1,546 @@
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::mpsc as stream;
+use futures::sync::oneshot;
+use futures::Future;
+use serde::Deserialize;
+
+use crate::events::PlayerEvent;
+use crate::mpv_simple::{MpvCtx, MpvEvent};
+use crate::multiroom;
+use crate::player::{lock, PlaybackState, Player, PlayerError};
+
+/**
+ * Bytes of audio between two ICY metadata blocks.
+ */
+pub const METAINT: usize = 16_000;
+
+const CHUNK_BYTES: usize = 4096;
+
+/**
+ * Chunks queued for a client before it is considered too slow and dropped.
+ */
+const CLIENT_BUFFER: usize = 64;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL_MS: libc::c_int = 1000;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Copy,
+    Mp3,
+    Aac,
+    Opus,
+    Vorbis,
+}
+
+/**
+ * Contents of `restream.json`. Without it the station is passed through as
+ * it is received.
+ */
+#[derive(Deserialize, Clone)]
+pub struct RestreamCfg {
+    #[serde(default)]
+    pub codec: Codec,
+    #[serde(default = "default_bitrate")]
+    pub bitrate_kbps: u32,
+}
+
+/**
+ * The audio sent to one `/listen` client.
+ */
+pub type ClientStream = stream::Receiver<Vec<u8>>;
+
+/**
+ * Inserts ICY metadata blocks into the audio of a client every `METAINT`
+ * bytes. A title is only sent again once it changed.
+ */
+struct Icy {
+    until_meta: usize,
+    sent: Option<String>,
+}
+
+type Reply = oneshot::Sender<Result<(String, ClientStream), PlayerError>>;
+
+struct Client {
+    tx: stream::Sender<Vec<u8>>,
+    icy: Option<Icy>,
+}
+
+enum Job {
+    Connect(bool, Reply),
+    Event(PlayerEvent),
+    Started(usize, String),
+    Data(usize, Vec<u8>),
+    Ended(usize, String),
+}
+
+struct Source {
+    generation: usize,
+    stop: Arc<AtomicBool>,
+    content_type: Option<String>,
+}
+
+struct Worker {
+    player: Arc<Mutex<Player>>,
+    cfg: RestreamCfg,
+    fifo: PathBuf,
+    tx: Sender<Job>,
+    clients: Vec<Client>,
+    pending: Vec<(bool, Reply)>,
+    source: Option<Source>,
+    generation: usize,
+    title: String,
+}
+
+/**
+ * Handle to the worker serving the current station to `/listen` clients. The
+ * station is only fetched while someone is listening.
+ */
+#[derive(Clone)]
+pub struct Restream {
+    tx: Sender<Job>,
+}
+
+fn default_bitrate() -> u32 {
+    128
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Copy
+    }
+}
+
+impl Default for RestreamCfg {
+    fn default() -> Self {
+        RestreamCfg {
+            codec: Codec::default(),
+            bitrate_kbps: default_bitrate(),
+        }
+    }
+}
+
+impl Codec {
+    /**
+     * MPV muxer, encoder and content type, `None` when passing through.
+     */
+    fn encoder(self) -> Option<(&'static str, &'static str, &'static str)> {
+        match self {
+            Codec::Copy => None,
+            Codec::Mp3 => Some(("mp3", "libmp3lame", "audio/mpeg")),
+            Codec::Aac => Some(("adts", "aac", "audio/aac")),
+            Codec::Opus => Some(("ogg", "libopus", "audio/ogg")),
+            Codec::Vorbis => Some(("ogg", "libvorbis", "audio/ogg")),
+        }
+    }
+}
+
+pub fn load_config(path: &Path) -> Result<RestreamCfg, String> {
+    match std::fs::read_to_string(path) {
+        Ok(txt) => serde_json::from_str(&txt).map_err(|err| format!("{}: {}", path.display(), err)),
+        Err(_) => Ok(RestreamCfg::default()),
+    }
+}
+
+/**
+ * A metadata block: its length in units of 16 bytes followed by the padded
+ * text.
+ */
+fn icy_block(title: &str) -> Vec<u8> {
+    let text = format!("StreamTitle='{}';", title.replace('\'', "\u{2019}"));
+    let blocks = cmp::min((text.len() + 15) / 16, 255);
+    let mut block = vec![blocks as u8];
+    block.extend(text.bytes().take(blocks * 16));
+    block.resize(blocks * 16 + 1, 0);
+    block
+}
+
+impl Icy {
+    fn new() -> Self {
+        Icy {
+            until_meta: METAINT,
+            sent: None,
+        }
+    }
+
+    fn wrap(&mut self, mut data: &[u8], title: &str) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 1);
+        while !data.is_empty() {
+            let len = cmp::min(self.until_meta, data.len());
+            out.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            self.until_meta -= len;
+            if self.until_meta == 0 {
+                if self.sent.as_deref() == Some(title) {
+                    out.push(0);
+                } else {
+                    out.extend(icy_block(title));
+                    self.sent = Some(title.to_string());
+                }
+                self.until_meta = METAINT;
+            }
+        }
+        out
+    }
+}
+
+/**
+ * Passes the station through as received.
+ */
+fn relay(url: &str, generation: usize, stop: &AtomicBool, tx: &Sender<Job>) -> Result<(), String> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .build();
+    let response = agent.get(url).call().map_err(|err| err.to_string())?;
+    let _ = tx.send(Job::Started(
+        generation,
+        response.content_type().to_string(),
+    ));
+
+    let mut reader = response.into_reader();
+    let mut buf = vec![0; CHUNK_BYTES];
+    while !stop.load(Ordering::SeqCst) {
+        match reader.read(&mut buf) {
+            Ok(0) => return Err("The station closed the connection".to_string()),
+            Ok(len) => {
+                if tx.send(Job::Data(generation, buf[..len].to_vec())).is_err() {
+                    break;
+                }
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Re-encodes the station with a dedicated MPV context writing into `fifo`.
+ */
+fn encode(
+    url: &str,
+    cfg: &RestreamCfg,
+    fifo: &Path,
+    generation: usize,
+    stop: &AtomicBool,
+    tx: &Sender<Job>,
+) -> Result<(), String> {
+    let (format, encoder, content_type) = match cfg.codec.encoder() {
+        Some(encoder) => encoder,
+        None => return Err("No encoder configured".to_string()),
+    };
+    multiroom::make_fifo(fifo).map_err(|err| format!("{}: {}", fifo.display(), err))?;
+    // Opened for writing as well, so neither opening nor reading waits for
+    // MPV and a failing stream cannot block this thread.
+    let mut file: File = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(fifo)
+        .map_err(|err| err.to_string())?;
+
+    let bitrate = format!("b={}", cfg.bitrate_kbps * 1000);
+    let output = fifo.to_string_lossy();
+    let options = [
+        ("vid", "no"),
+        ("o", &output),
+        ("of", format),
+        ("oac", encoder),
+        ("oacopts", &bitrate),
+    ];
+    let mut ctx = MpvCtx::create().map_err(|err| format!("{:?}", err))?;
+    for (name, value) in options.iter() {
+        ctx.set_option(name, value)
+            .map_err(|err| format!("{}={}: {:?}", name, value, err))?;
+    }
+    ctx.init().map_err(|err| format!("{:?}", err))?;
+    ctx.command(&["loadfile", url])
+        .map_err(|err| format!("{:?}", err))?;
+    let _ = tx.send(Job::Started(generation, content_type.to_string()));
+
+    let mut buf = vec![0; CHUNK_BYTES];
+    while !stop.load(Ordering::SeqCst) {
+        loop {
+            match ctx.wait_event(0.0) {
+                Ok(MpvEvent::None) | Err(_) => break,
+                Ok(MpvEvent::EndFile { .. }) | Ok(MpvEvent::Shutdown) => {
+                    return Err("The station stopped sending".to_string())
+                }
+                _ => (),
+            }
+        }
+
+        let mut pfd = libc::pollfd {
+            fd: file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pfd, 1, POLL_INTERVAL_MS) } <= 0 {
+            continue;
+        }
+        match file.read(&mut buf) {
+            Ok(len) => {
+                if tx.send(Job::Data(generation, buf[..len].to_vec())).is_err() {
+                    break;
+                }
+            }
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+    Ok(())
+}
+
+impl Worker {
+    fn current_url(&self) -> Option<String> {
+        let player = lock(&self.player);
+        if player.get_state() == PlaybackState::Stopped {
+            return None;
+        }
+        player.get_current().map(|stream| stream.url.to_string())
+    }
+
+    fn start_source(&mut self) -> bool {
+        let url = match self.current_url() {
+            Some(url) => url,
+            None => return false,
+        };
+        self.title = lock(&self.player).get_now_playing();
+        self.generation += 1;
+        let generation = self.generation;
+        let stop = Arc::new(AtomicBool::new(false));
+        self.source = Some(Source {
+            generation,
+            stop: stop.clone(),
+            content_type: None,
+        });
+
+        let cfg = self.cfg.clone();
+        let fifo = self.fifo.clone();
+        let tx = self.tx.clone();
+        thread::spawn(move || {
+            let result = if cfg.codec == Codec::Copy {
+                relay(&url, generation, &stop, &tx)
+            } else {
+                encode(&url, &cfg, &fifo, generation, &stop, &tx)
+            };
+            if let Err(err) = result {
+                let _ = tx.send(Job::Ended(generation, err));
+            }
+        });
+        true
+    }
+
+    fn stop_source(&mut self) {
+        if let Some(source) = self.source.take() {
+            source.stop.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /**
+     * Ends every client stream and answers waiting clients with `err`.
+     */
+    fn disconnect(&mut self, err: PlayerError) {
+        self.stop_source();
+        self.clients.clear();
+        for (_, reply) in self.pending.drain(..) {
+            let _ = reply.send(Err(err.clone()));
+        }
+    }
+
+    fn attach(&mut self, icy: bool, content_type: &str) -> (String, ClientStream) {
+        let (tx, rx) = stream::channel(CLIENT_BUFFER);
+        self.clients.push(Client {
+            tx,
+            icy: if icy { Some(Icy::new()) } else { None },
+        });
+        (content_type.to_string(), rx)
+    }
+
+    fn connect(&mut self, icy: bool, reply: Reply) {
+        if self.source.is_none() && !self.start_source() {
+            let _ = reply.send(Err(PlayerError::NotPlaying));
+            return;
+        }
+        let content_type = self
+            .source
+            .as_ref()
+            .and_then(|source| source.content_type.clone());
+        match content_type {
+            Some(content_type) => {
+                let client = self.attach(icy, &content_type);
+                let _ = reply.send(Ok(client));
+            }
+            None => self.pending.push((icy, reply)),
+        }
+    }
+
+    fn is_current(&self, generation: usize) -> bool {
+        self.source
+            .as_ref()
+            .map_or(false, |source| source.generation == generation)
+    }
+
+    fn started(&mut self, content_type: String) {
+        if let Some(source) = &mut self.source {
+            source.content_type = Some(content_type.clone());
+        }
+        let pending: Vec<_> = self.pending.drain(..).collect();
+        for (icy, reply) in pending {
+            let client = self.attach(icy, &content_type);
+            let _ = reply.send(Ok(client));
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        let title = &self.title;
+        self.clients.retain_mut(|client| {
+            let chunk = match &mut client.icy {
+                Some(icy) => icy.wrap(data, title),
+                None => data.to_vec(),
+            };
+            client.tx.try_send(chunk).is_ok()
+        });
+        if self.clients.is_empty() && self.pending.is_empty() {
+            self.stop_source();
+        }
+    }
+
+    fn handle(&mut self, job: Job) {
+        match job {
+            Job::Connect(icy, reply) => self.connect(icy, reply),
+            Job::Event(PlayerEvent::TitleChanged(title)) => self.title = title,
+            Job::Event(PlayerEvent::StreamChanged(_)) => {
+                // Clients keep their connection and continue with the new
+                // station, even if its format differs when passing through.
+                if self.source.is_some() {
+                    self.stop_source();
+                    if !self.start_source() {
+                        self.disconnect(PlayerError::NotPlaying);
+                    }
+                }
+            }
+            Job::Event(PlayerEvent::PlaybackChanged(PlaybackState::Stopped)) => {
+                self.disconnect(PlayerError::NotPlaying)
+            }
+            Job::Event(_) => (),
+            Job::Started(generation, content_type) => {
+                if self.is_current(generation) {
+                    self.started(content_type);
+                }
+            }
+            Job::Data(generation, data) => {
+                if self.is_current(generation) {
+                    self.send(&data);
+                }
+            }
+            Job::Ended(generation, err) => {
+                if self.is_current(generation) {
+                    eprintln!("W: Re-streaming failed: {}", err);
+                    self.disconnect(PlayerError::Restream(err));
+                }
+            }
+        }
+    }
+}
+
+impl Restream {
+    /**
+     * Starts the worker, encoded audio passes through `fifo`.
+     */
+    pub fn spawn(player: Arc<Mutex<Player>>, cfg: RestreamCfg, fifo: PathBuf) -> Self {
+        let (tx, rx): (Sender<Job>, Receiver<Job>) = channel();
+
+        let event_tx = tx.clone();
+        lock(&player)
+            .listeners()
+            .add(move |event| event_tx.send(Job::Event(event.clone())).is_ok());
+
+        let mut worker = Worker {
+            player,
+            cfg,
+            fifo,
+            tx: tx.clone(),
+            clients: Vec::new(),
+            pending: Vec::new(),
+            source: None,
+            generation: 0,
+            title: String::new(),
+        };
+        thread::spawn(move || {
+            for job in rx {
+                worker.handle(job);
+            }
+        });
+        Restream { tx }
+    }
+
+    /**
+     * Adds a client, resolving to the content type and the audio to send it
+     * once the station is connected. With `icy` the audio carries ICY
+     * metadata every `METAINT` bytes.
+     */
+    pub fn connect(
+        &self,
+        icy: bool,
+    ) -> impl Future<Item = (String, ClientStream), Error = PlayerError> {
+        let (reply, rx) = oneshot::channel();
+        // A worker that is gone drops the reply, which cancels `rx`.
+        let _ = self.tx.send(Job::Connect(icy, reply));
+        rx.then(|result| match result {
+            Ok(result) => result,
+            Err(_) => Err(PlayerError::Restream(
+                "The re-streaming worker is not running".to_string(),
+            )),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_icy_block() {
+        let block = icy_block("Artist - Song");
+        assert_eq!(block.len(), 1 + 2 * 16);
+        assert_eq!(block[0], 2);
+        assert!(block[1..].starts_with(b"StreamTitle='Artist - Song';"));
+        assert_eq!(block[32], 0);
+    }
+
+    #[test]
+    fn test_icy_wrap() {
+        let mut icy = Icy::new();
+        let data = vec![1; METAINT - 10];
+        assert_eq!(icy.wrap(&data, "A"), data);
+
+        let out = icy.wrap(&[2; 20], "A");
+        assert_eq!(out[..10], [2; 10]);
+        assert_eq!(out[10], 1);
+        assert!(out[11..].starts_with(b"StreamTitle='A';"));
+        assert_eq!(out[27..], [2; 10]);
+
+        // Unchanged titles are sent as empty blocks.
+        let out = icy.wrap(&vec![3; METAINT], "A");
+        assert_eq!(out.len(), METAINT + 1);
+        assert_eq!(out[METAINT - 10], 0);
+    }
+}
@@ -0,0 +1,616 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::mpv_simple::{MpvCtx, MpvError, MpvEvent};
+use crate::player::{lock, Player};
+use crate::record;
+
+const DAY: u64 = 24 * 60 * 60;
+const WEEK: u64 = 7 * DAY;
+const TICK: Duration = Duration::from_secs(1);
+
+/**
+ * Delay before a capture whose stream ended early is started again.
+ */
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/**
+ * Files modified this recently may still be written to and are never removed
+ * by the retention policy.
+ */
+const IN_USE_SECONDS: u64 = 60;
+
+/**
+ * Recurrences are computed in UTC.
+ */
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Recurrence {
+    Once,
+    Daily,
+    Weekly,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ScheduledRecording {
+    #[serde(default)]
+    pub id: usize,
+    pub station: usize,
+    /**
+     * Seconds since the epoch of the first occurrence.
+     */
+    pub start: u64,
+    /**
+     * Length of each occurrence in seconds.
+     */
+    pub duration: u64,
+    #[serde(default)]
+    pub recurrence: Recurrence,
+    /**
+     * File name template, see `record::RecordRequest`.
+     */
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+/**
+ * Contents of `schedule.json` in the data directory.
+ */
+#[derive(Deserialize, Serialize, Default)]
+pub struct Schedule {
+    pub recordings: Vec<ScheduledRecording>,
+    /**
+     * Disk space in megabytes the recordings directory may use, the oldest
+     * recordings are deleted once it is exceeded.
+     */
+    #[serde(default)]
+    pub quota_mb: Option<u64>,
+
+    #[serde(skip, default)]
+    last_id: usize,
+}
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    NotFound(usize),
+    UnknownStation(usize),
+    Invalid(String),
+    /**
+     * The recording overlaps the scheduled recording with this ID, there is
+     * only one capture context.
+     */
+    Conflict(usize),
+    Persistence(String),
+}
+
+struct State {
+    schedule: Schedule,
+    path: PathBuf,
+}
+
+/**
+ * Handle to the scheduled recordings, shared by the HTTP handlers and the
+ * thread running them.
+ */
+#[derive(Clone)]
+pub struct Scheduler {
+    state: Arc<Mutex<State>>,
+    player: Arc<Mutex<Player>>,
+}
+
+/**
+ * A scheduled recording in progress on its own output-less MPV context.
+ */
+struct Capture {
+    id: usize,
+    end: u64,
+    path: PathBuf,
+    ctx: MpvCtx,
+}
+
+impl Default for Recurrence {
+    fn default() -> Self {
+        Recurrence::Once
+    }
+}
+
+impl Recurrence {
+    fn period(self) -> u64 {
+        match self {
+            Recurrence::Once => 0,
+            Recurrence::Daily => DAY,
+            Recurrence::Weekly => WEEK,
+        }
+    }
+}
+
+impl ScheduleError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ScheduleError::NotFound(_) => "not_found",
+            ScheduleError::UnknownStation(_) => "unknown_station",
+            ScheduleError::Invalid(_) => "invalid_schedule",
+            ScheduleError::Conflict(_) => "schedule_conflict",
+            ScheduleError::Persistence(_) => "persistence_failure",
+        }
+    }
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScheduleError::NotFound(id) => write!(f, "No scheduled recording with ID {}", id),
+            ScheduleError::UnknownStation(id) => write!(f, "No stream with ID {}", id),
+            ScheduleError::Invalid(msg) => write!(f, "Invalid schedule: {}", msg),
+            ScheduleError::Conflict(id) => {
+                write!(f, "Overlaps with scheduled recording {}", id)
+            }
+            ScheduleError::Persistence(msg) => write!(f, "Failed to store schedule: {}", msg),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+impl ScheduledRecording {
+    /**
+     * Start of the occurrence that is running at `time`, if any.
+     */
+    pub fn occurrence_at(&self, time: u64) -> Option<u64> {
+        if time < self.start {
+            return None;
+        }
+        let period = self.recurrence.period();
+        let start = if period == 0 {
+            self.start
+        } else {
+            self.start + (time - self.start) / period * period
+        };
+        if time < start + self.duration {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /**
+     * Starts of the occurrences that are running at some point between `from`
+     * and `to`.
+     */
+    fn occurrences(&self, from: u64, to: u64) -> Vec<u64> {
+        let period = self.recurrence.period();
+        if period == 0 {
+            return if self.start < to && self.start + self.duration > from {
+                vec![self.start]
+            } else {
+                Vec::new()
+            };
+        }
+        let first = from.saturating_sub(self.start + self.duration) / period;
+        (first..)
+            .map(|n| self.start + n * period)
+            .take_while(|start| *start < to)
+            .filter(|start| start + self.duration > from)
+            .collect()
+    }
+
+    /**
+     * Whether any occurrences of both recordings overlap. Recurrences repeat
+     * at least weekly, so one week after both have started covers every
+     * combination.
+     */
+    pub fn overlaps(&self, other: &ScheduledRecording) -> bool {
+        let longest = cmp::max(self.duration, other.duration);
+        let from = cmp::max(self.start, other.start).saturating_sub(longest);
+        let to = cmp::max(self.start, other.start) + WEEK + longest;
+        let theirs = other.occurrences(from, to);
+        self.occurrences(from, to).iter().any(|start| {
+            theirs.iter().any(|other_start| {
+                *start < other_start + other.duration && *other_start < start + self.duration
+            })
+        })
+    }
+
+    fn validate(&self) -> Result<(), ScheduleError> {
+        let period = self.recurrence.period();
+        if self.duration == 0 {
+            Err(ScheduleError::Invalid(
+                "duration must be positive".to_string(),
+            ))
+        } else if period != 0 && self.duration > period {
+            Err(ScheduleError::Invalid(
+                "duration exceeds the recurrence interval".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/**
+ * Deletes the oldest recordings in `dir` until it uses at most `quota` bytes.
+ * Returns the names of the deleted files.
+ */
+pub fn enforce_quota(dir: &Path, quota: u64, now: u64) -> Vec<String> {
+    let mut files = match record::list(dir) {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("W: Cannot list recordings: {}", err);
+            return Vec::new();
+        }
+    };
+    files.sort_by_key(|file| file.modified);
+
+    let mut used: u64 = files.iter().map(|file| file.size).sum();
+    let mut deleted = Vec::new();
+    for file in files {
+        if used <= quota {
+            break;
+        }
+        if file.modified + IN_USE_SECONDS > now {
+            continue;
+        }
+        match fs::remove_file(dir.join(&file.name)) {
+            Ok(()) => {
+                used -= file.size;
+                deleted.push(file.name);
+            }
+            Err(err) => eprintln!("W: Cannot delete recording {}: {}", file.name, err),
+        }
+    }
+    deleted
+}
+
+impl Capture {
+    fn start(
+        id: usize,
+        end: u64,
+        url: &str,
+        options: &BTreeMap<String, String>,
+        path: PathBuf,
+    ) -> Result<Capture, MpvError> {
+        let mut ctx = MpvCtx::create()?;
+        // The context only plays this stream, so its options can be global.
+        for (name, value) in options {
+            if let Err(err) = ctx.set_option(name, value) {
+                eprintln!("W: Ignoring MPV option {}={}: {:?}", name, value, err);
+            }
+        }
+        ctx.set_option("ao", "null")?;
+        ctx.set_option("vid", "no")?;
+        ctx.set_option("stream-record", &path.to_string_lossy())?;
+        ctx.init()?;
+        ctx.command(&["loadfile", url])?;
+        Ok(Capture { id, end, path, ctx })
+    }
+
+    /**
+     * Drains the event queue and returns whether the stream is still being
+     * received.
+     */
+    fn poll(&mut self) -> bool {
+        let mut running = true;
+        loop {
+            match self.ctx.wait_event(0.0) {
+                Ok(MpvEvent::None) => return running,
+                Ok(MpvEvent::Shutdown) | Ok(MpvEvent::EndFile { .. }) => running = false,
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Scheduler {
+    /**
+     * Loads the schedule from `path`, a missing file is an empty schedule.
+     */
+    pub fn load(player: Arc<Mutex<Player>>, path: PathBuf) -> Result<Self, ScheduleError> {
+        let mut schedule = config::load_unversioned::<Schedule>(&path, config::BACKUP_COUNT)
+            .map_err(|err| ScheduleError::Persistence(err.to_string()))?
+            .unwrap_or_default();
+        schedule.last_id = schedule
+            .recordings
+            .iter()
+            .fold(0, |acc, recording| cmp::max(acc, recording.id));
+        Ok(Scheduler {
+            state: Arc::new(Mutex::new(State { schedule, path })),
+            player,
+        })
+    }
+
+    fn save(state: &State) -> Result<(), ScheduleError> {
+        config::save_unversioned(&state.path, &state.schedule, config::BACKUP_COUNT)
+            .map_err(|err| ScheduleError::Persistence(err.to_string()))
+    }
+
+    fn check(&self, state: &State, recording: &ScheduledRecording) -> Result<(), ScheduleError> {
+        recording.validate()?;
+        let conflict = state
+            .schedule
+            .recordings
+            .iter()
+            .find(|other| other.id != recording.id && other.overlaps(recording));
+        match conflict {
+            Some(other) => Err(ScheduleError::Conflict(other.id)),
+            None => Ok(()),
+        }
+    }
+
+    fn check_station(&self, id: usize) -> Result<(), ScheduleError> {
+        let player = lock(&self.player);
+        if player.get_playlist().iter().any(|stream| stream.id == id) {
+            Ok(())
+        } else {
+            Err(ScheduleError::UnknownStation(id))
+        }
+    }
+
+    pub fn list(&self) -> Vec<ScheduledRecording> {
+        lock(&self.state).schedule.recordings.clone()
+    }
+
+    pub fn quota_mb(&self) -> Option<u64> {
+        lock(&self.state).schedule.quota_mb
+    }
+
+    pub fn add(
+        &self,
+        mut recording: ScheduledRecording,
+    ) -> Result<ScheduledRecording, ScheduleError> {
+        self.check_station(recording.station)?;
+        let mut state = lock(&self.state);
+        recording.id = state.schedule.last_id + 1;
+        self.check(&state, &recording)?;
+
+        state.schedule.last_id = recording.id;
+        state.schedule.recordings.push(recording.clone());
+        Self::save(&state)?;
+        Ok(recording)
+    }
+
+    pub fn update(
+        &self,
+        id: usize,
+        mut recording: ScheduledRecording,
+    ) -> Result<ScheduledRecording, ScheduleError> {
+        self.check_station(recording.station)?;
+        let mut state = lock(&self.state);
+        let pos = state
+            .schedule
+            .recordings
+            .iter()
+            .position(|recording| recording.id == id)
+            .ok_or(ScheduleError::NotFound(id))?;
+        recording.id = id;
+        self.check(&state, &recording)?;
+
+        state.schedule.recordings[pos] = recording.clone();
+        Self::save(&state)?;
+        Ok(recording)
+    }
+
+    pub fn delete(&self, id: usize) -> Result<ScheduledRecording, ScheduleError> {
+        let mut state = lock(&self.state);
+        let pos = state
+            .schedule
+            .recordings
+            .iter()
+            .position(|recording| recording.id == id)
+            .ok_or(ScheduleError::NotFound(id))?;
+        let deleted = state.schedule.recordings.remove(pos);
+        Self::save(&state)?;
+        Ok(deleted)
+    }
+
+    pub fn set_quota_mb(&self, quota_mb: Option<u64>) -> Result<(), ScheduleError> {
+        let mut state = lock(&self.state);
+        state.schedule.quota_mb = quota_mb;
+        Self::save(&state)
+    }
+
+    /**
+     * Scheduled recording running at `time` together with the start of its
+     * current occurrence.
+     */
+    fn due(&self, time: u64) -> Option<(ScheduledRecording, u64)> {
+        lock(&self.state)
+            .schedule
+            .recordings
+            .iter()
+            .find_map(|recording| {
+                recording
+                    .occurrence_at(time)
+                    .map(|start| (recording.clone(), start))
+            })
+    }
+
+    fn start_capture(
+        &self,
+        recording: &ScheduledRecording,
+        occurrence: u64,
+        dir: &Path,
+    ) -> Result<Capture, String> {
+        let (name, url, options) = {
+            let player = lock(&self.player);
+            let stream = player
+                .get_playlist()
+                .iter()
+                .find(|stream| stream.id == recording.station)
+                .ok_or_else(|| ScheduleError::UnknownStation(recording.station).to_string())?;
+            (
+                stream.name.to_string(),
+                stream.url.to_string(),
+                stream.options.clone(),
+            )
+        };
+        let template = recording
+            .template
+            .as_ref()
+            .map_or(record::DEFAULT_TEMPLATE, String::as_str);
+        let file_name = record::file_name(template, &name, "", now());
+
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+        let path = record::unique_path(dir, &file_name);
+        Capture::start(
+            recording.id,
+            occurrence + recording.duration,
+            &url,
+            &options,
+            path,
+        )
+        .map_err(|err| format!("MPV failure: {:?}", err))
+    }
+
+    fn enforce_quota(&self, dir: &Path) {
+        if let Some(quota_mb) = self.quota_mb() {
+            for name in enforce_quota(dir, quota_mb * 1024 * 1024, now()) {
+                eprintln!("W: Deleted recording {} to stay within the quota", name);
+            }
+        }
+    }
+
+    fn run(self, dir: PathBuf) {
+        let mut capture: Option<Capture> = None;
+        let mut attempts: HashMap<usize, (u64, Instant)> = HashMap::new();
+
+        self.enforce_quota(&dir);
+        loop {
+            thread::sleep(TICK);
+            let time = now();
+
+            if let Some(mut running) = capture.take() {
+                let scheduled = self.due(time).map_or(false, |(recording, start)| {
+                    recording.id == running.id && start + recording.duration == running.end
+                });
+                if !running.poll() {
+                    eprintln!("W: Scheduled recording {} ended early", running.id);
+                } else if scheduled && time < running.end {
+                    capture = Some(running);
+                    continue;
+                }
+                eprintln!("W: Finished recording {}", running.path.display());
+                drop(running);
+                self.enforce_quota(&dir);
+            }
+
+            let (recording, occurrence) = match self.due(time) {
+                Some(due) => due,
+                None => continue,
+            };
+            let retry = match attempts.get(&recording.id) {
+                Some((start, attempt)) if *start == occurrence => {
+                    attempt.elapsed() >= RETRY_INTERVAL
+                }
+                _ => true,
+            };
+            if !retry {
+                continue;
+            }
+
+            attempts.insert(recording.id, (occurrence, Instant::now()));
+            match self.start_capture(&recording, occurrence, &dir) {
+                Ok(started) => {
+                    eprintln!("W: Recording to {}", started.path.display());
+                    capture = Some(started);
+                }
+                Err(err) => eprintln!("W: Scheduled recording {} failed: {}", recording.id, err),
+            }
+        }
+    }
+
+    /**
+     * Runs the scheduled recordings, files are stored in `dir`.
+     */
+    pub fn spawn(&self, dir: PathBuf) -> JoinHandle<()> {
+        let scheduler = self.clone();
+        thread::spawn(move || scheduler.run(dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::testutil::test_dir;
+
+    fn recording(
+        id: usize,
+        start: u64,
+        duration: u64,
+        recurrence: Recurrence,
+    ) -> ScheduledRecording {
+        ScheduledRecording {
+            id,
+            station: 1,
+            start,
+            duration,
+            recurrence,
+            template: None,
+        }
+    }
+
+    #[test]
+    fn test_occurrence_at() {
+        let daily = recording(1, 1000, 600, Recurrence::Daily);
+        assert_eq!(daily.occurrence_at(999), None);
+        assert_eq!(daily.occurrence_at(1000), Some(1000));
+        assert_eq!(daily.occurrence_at(1600), None);
+        assert_eq!(daily.occurrence_at(DAY * 3 + 1200), Some(DAY * 3 + 1000));
+
+        let once = recording(2, 1000, 600, Recurrence::Once);
+        assert_eq!(once.occurrence_at(DAY + 1000), None);
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let weekly = recording(1, 10 * DAY, 3600, Recurrence::Weekly);
+        let daily = recording(2, 3 * DAY + 1800, 600, Recurrence::Daily);
+        let next_week = recording(3, 24 * DAY + 600, 600, Recurrence::Once);
+        let later = recording(4, 17 * DAY + 3600, 600, Recurrence::Once);
+
+        assert!(weekly.overlaps(&daily));
+        assert!(daily.overlaps(&weekly));
+        assert!(weekly.overlaps(&next_week));
+        assert!(!weekly.overlaps(&later));
+        assert!(!next_week.overlaps(&later));
+    }
+
+    #[test]
+    fn test_enforce_quota() {
+        let dir = test_dir("quota");
+        fs::write(dir.join("a.mka"), vec![0; 100]).unwrap();
+        fs::write(dir.join("b.mka"), vec![0; 100]).unwrap();
+
+        let modified = |name: &str| {
+            fs::metadata(dir.join(name))
+                .unwrap()
+                .modified()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        assert!(enforce_quota(&dir, 150, modified("b.mka")).is_empty());
+
+        let later = modified("b.mka") + IN_USE_SECONDS;
+        let deleted = enforce_quota(&dir, 150, later);
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(record::list(&dir).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
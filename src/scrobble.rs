@@ -0,0 +1,512 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config;
+use crate::events::PlayerEvent;
+use crate::player::{lock, PlaybackState, Player};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+const LASTFM_BATCH: usize = 50;
+const LISTENBRAINZ_BATCH: usize = 100;
+
+#[derive(Deserialize, Debug)]
+pub struct ListenBrainzCfg {
+    #[serde(default = "default_listenbrainz_url")]
+    pub url: String,
+    pub token: String,
+}
+
+/**
+ * Settings for Last.fm or a service implementing its scrobble API, such as
+ * Libre.fm.
+ */
+#[derive(Deserialize, Debug)]
+pub struct LastFmCfg {
+    #[serde(default = "default_lastfm_url")]
+    pub url: String,
+    pub api_key: String,
+    pub api_secret: String,
+    pub session_key: String,
+}
+
+/**
+ * Scrobble settings, read from `scrobble.json` in the data directory.
+ */
+#[derive(Deserialize, Debug)]
+pub struct ScrobbleCfg {
+    pub listenbrainz: Option<ListenBrainzCfg>,
+    pub lastfm: Option<LastFmCfg>,
+    /**
+     * Seconds a track must have been playing before it is submitted.
+     */
+    #[serde(default = "default_min_seconds")]
+    pub min_seconds: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Listen {
+    pub artist: String,
+    pub track: String,
+    pub listened_at: u64,
+}
+
+/**
+ * Listens that could not be submitted yet, per service.
+ */
+#[derive(Deserialize, Serialize, Default)]
+struct Queue {
+    listens: BTreeMap<String, Vec<Listen>>,
+}
+
+struct Playing {
+    listen: Listen,
+    started: Instant,
+}
+
+trait Service: Send {
+    fn name(&self) -> &'static str;
+    fn now_playing(&self, agent: &ureq::Agent, listen: &Listen) -> Result<(), String>;
+    fn submit(&self, agent: &ureq::Agent, listens: &[Listen]) -> Result<(), String>;
+}
+
+fn default_listenbrainz_url() -> String {
+    "https://api.listenbrainz.org".to_string()
+}
+
+fn default_lastfm_url() -> String {
+    "https://ws.audioscrobbler.com/2.0/".to_string()
+}
+
+fn default_min_seconds() -> u64 {
+    60
+}
+
+pub fn load_config(path: &Path) -> Result<Option<ScrobbleCfg>, String> {
+    match fs::read_to_string(path) {
+        Ok(txt) => serde_json::from_str(&txt)
+            .map(Some)
+            .map_err(|err| format!("{}: {}", path.display(), err)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/**
+ * Splits an ICY title of the form `Artist - Title`. Titles without an artist
+ * cannot be scrobbled.
+ */
+pub fn parse_title(title: &str) -> Option<(String, String)> {
+    let mut parts = title.splitn(2, " - ");
+    let artist = parts.next()?.trim();
+    let track = parts.next()?.trim();
+    if artist.is_empty() || track.is_empty() {
+        None
+    } else {
+        Some((artist.to_string(), track.to_string()))
+    }
+}
+
+fn error_string(err: ureq::Error) -> String {
+    match err {
+        ureq::Error::Status(code, response) => format!(
+            "HTTP {}: {}",
+            code,
+            response.into_string().unwrap_or_default()
+        ),
+        ureq::Error::Transport(transport) => transport.to_string(),
+    }
+}
+
+struct ListenBrainz(ListenBrainzCfg);
+
+impl ListenBrainz {
+    fn send(&self, agent: &ureq::Agent, body: Value) -> Result<(), String> {
+        agent
+            .post(&format!(
+                "{}/1/submit-listens",
+                self.0.url.trim_end_matches('/')
+            ))
+            .set("Authorization", &format!("Token {}", self.0.token))
+            .send_json(body)
+            .map(|_| ())
+            .map_err(error_string)
+    }
+}
+
+fn track_metadata(listen: &Listen) -> Value {
+    json!({
+        "artist_name": listen.artist,
+        "track_name": listen.track,
+        "additional_info": { "submission_client": "radio" },
+    })
+}
+
+impl Service for ListenBrainz {
+    fn name(&self) -> &'static str {
+        "listenbrainz"
+    }
+
+    fn now_playing(&self, agent: &ureq::Agent, listen: &Listen) -> Result<(), String> {
+        self.send(
+            agent,
+            json!({
+                "listen_type": "playing_now",
+                "payload": [{ "track_metadata": track_metadata(listen) }],
+            }),
+        )
+    }
+
+    fn submit(&self, agent: &ureq::Agent, listens: &[Listen]) -> Result<(), String> {
+        for chunk in listens.chunks(LISTENBRAINZ_BATCH) {
+            let payload: Vec<Value> = chunk
+                .iter()
+                .map(|listen| {
+                    json!({
+                        "listened_at": listen.listened_at,
+                        "track_metadata": track_metadata(listen),
+                    })
+                })
+                .collect();
+            let listen_type = if payload.len() == 1 {
+                "single"
+            } else {
+                "import"
+            };
+            self.send(
+                agent,
+                json!({ "listen_type": listen_type, "payload": payload }),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct LastFm(LastFmCfg);
+
+/**
+ * Signs a Last.fm API call: the MD5 of all parameters sorted by name and
+ * concatenated as `<name><value>`, followed by the shared secret.
+ */
+fn lastfm_signature(params: &BTreeMap<String, String>, secret: &str) -> String {
+    let mut txt = String::new();
+    for (key, value) in params {
+        if key != "format" && key != "callback" {
+            txt.push_str(key);
+            txt.push_str(value);
+        }
+    }
+    txt.push_str(secret);
+    format!("{:x}", md5::compute(txt))
+}
+
+impl LastFm {
+    fn call(
+        &self,
+        agent: &ureq::Agent,
+        mut params: BTreeMap<String, String>,
+    ) -> Result<(), String> {
+        params.insert("api_key".to_string(), self.0.api_key.to_string());
+        params.insert("sk".to_string(), self.0.session_key.to_string());
+        let signature = lastfm_signature(&params, &self.0.api_secret);
+        params.insert("api_sig".to_string(), signature);
+        params.insert("format".to_string(), "json".to_string());
+
+        let form: Vec<(&str, &str)> = params
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let response: Value = agent
+            .post(&self.0.url)
+            .send_form(&form)
+            .map_err(error_string)?
+            .into_json()
+            .map_err(|err| err.to_string())?;
+
+        match response.get("error") {
+            Some(code) => Err(format!(
+                "error {}: {}",
+                code,
+                response["message"].as_str().unwrap_or("")
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Service for LastFm {
+    fn name(&self) -> &'static str {
+        "lastfm"
+    }
+
+    fn now_playing(&self, agent: &ureq::Agent, listen: &Listen) -> Result<(), String> {
+        let mut params = BTreeMap::new();
+        params.insert("method".to_string(), "track.updateNowPlaying".to_string());
+        params.insert("artist".to_string(), listen.artist.to_string());
+        params.insert("track".to_string(), listen.track.to_string());
+        self.call(agent, params)
+    }
+
+    fn submit(&self, agent: &ureq::Agent, listens: &[Listen]) -> Result<(), String> {
+        for chunk in listens.chunks(LASTFM_BATCH) {
+            let mut params = BTreeMap::new();
+            params.insert("method".to_string(), "track.scrobble".to_string());
+            for (i, listen) in chunk.iter().enumerate() {
+                params.insert(format!("artist[{}]", i), listen.artist.to_string());
+                params.insert(format!("track[{}]", i), listen.track.to_string());
+                params.insert(format!("timestamp[{}]", i), listen.listened_at.to_string());
+            }
+            self.call(agent, params)?;
+        }
+        Ok(())
+    }
+}
+
+struct Scrobbler {
+    services: Vec<Box<dyn Service>>,
+    min_seconds: u64,
+    agent: ureq::Agent,
+    queue_path: PathBuf,
+    queue: Queue,
+    playing: Option<Playing>,
+}
+
+impl Scrobbler {
+    fn new(cfg: ScrobbleCfg, queue_path: PathBuf) -> Self {
+        let mut services: Vec<Box<dyn Service>> = Vec::new();
+        if let Some(listenbrainz) = cfg.listenbrainz {
+            services.push(Box::new(ListenBrainz(listenbrainz)));
+        }
+        if let Some(lastfm) = cfg.lastfm {
+            services.push(Box::new(LastFm(lastfm)));
+        }
+
+        let queue = match config::load_unversioned::<Queue>(&queue_path, 0) {
+            Ok(queue) => queue.unwrap_or_default(),
+            Err(err) => {
+                eprintln!("W: Discarding scrobble queue: {}", err);
+                Queue::default()
+            }
+        };
+
+        Scrobbler {
+            services,
+            min_seconds: cfg.min_seconds,
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            queue_path,
+            queue,
+            playing: None,
+        }
+    }
+
+    fn save_queue(&self) {
+        if let Err(err) = config::save_unversioned(&self.queue_path, &self.queue, 0) {
+            eprintln!("W: Failed to store scrobble queue: {}", err);
+        }
+    }
+
+    /**
+     * Submits all queued listens, listens are kept for every service that
+     * fails so they are retried later.
+     */
+    fn flush(&mut self) {
+        let mut changed = false;
+        for service in &self.services {
+            let pending = match self.queue.listens.get(service.name()) {
+                Some(pending) if !pending.is_empty() => pending,
+                _ => continue,
+            };
+            match service.submit(&self.agent, pending) {
+                Ok(()) => {
+                    self.queue.listens.remove(service.name());
+                    changed = true;
+                }
+                Err(err) => eprintln!("W: Scrobbling to {} failed: {}", service.name(), err),
+            }
+        }
+        if changed {
+            self.save_queue();
+        }
+    }
+
+    /**
+     * Ends the current track, queueing it if it played long enough.
+     */
+    fn finish(&mut self) {
+        if let Some(playing) = self.playing.take() {
+            if playing.started.elapsed().as_secs() < self.min_seconds {
+                return;
+            }
+            for service in &self.services {
+                self.queue
+                    .listens
+                    .entry(service.name().to_string())
+                    .or_insert_with(Vec::new)
+                    .push(playing.listen.clone());
+            }
+            self.save_queue();
+            self.flush();
+        }
+    }
+
+    fn start(&mut self, artist: String, track: String) {
+        let listen = Listen {
+            artist,
+            track,
+            listened_at: now(),
+        };
+        for service in &self.services {
+            if let Err(err) = service.now_playing(&self.agent, &listen) {
+                eprintln!("W: Now playing on {} failed: {}", service.name(), err);
+            }
+        }
+        self.playing = Some(Playing {
+            listen,
+            started: Instant::now(),
+        });
+    }
+}
+
+fn run(player: Arc<Mutex<Player>>, mut scrobbler: Scrobbler, rx: Receiver<PlayerEvent>) {
+    scrobbler.flush();
+
+    loop {
+        match rx.recv_timeout(RETRY_INTERVAL) {
+            Ok(PlayerEvent::TitleChanged(title)) => {
+                scrobbler.finish();
+                let (enabled, playing) = {
+                    let player = lock(&player);
+                    (
+                        player.get_current().map_or(false, |stream| stream.scrobble),
+                        player.get_state() == PlaybackState::Playing,
+                    )
+                };
+                if let (true, true, Some((artist, track))) = (enabled, playing, parse_title(&title))
+                {
+                    scrobbler.start(artist, track);
+                }
+            }
+            Ok(PlayerEvent::StreamChanged(_)) => scrobbler.finish(),
+            Ok(PlayerEvent::PlaybackChanged(state)) if state != PlaybackState::Playing => {
+                scrobbler.finish()
+            }
+            Ok(_) => (),
+            Err(RecvTimeoutError::Timeout) => scrobbler.flush(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/**
+ * Starts scrobbling the titles announced by the streams. Listens that cannot
+ * be submitted are stored in `queue_path` and retried periodically.
+ */
+pub fn spawn(player: Arc<Mutex<Player>>, cfg: ScrobbleCfg, queue_path: PathBuf) -> JoinHandle<()> {
+    let scrobbler = Scrobbler::new(cfg, queue_path);
+
+    let (tx, rx) = channel();
+    lock(&player)
+        .listeners()
+        .add(move |event| tx.send(event.clone()).is_ok());
+
+    thread::spawn(move || run(player, scrobbler, rx))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::testutil::{http_stand_in, test_dir};
+
+    #[test]
+    fn test_parse_title() {
+        assert_eq!(
+            parse_title("Boards of Canada - Roygbiv"),
+            Some(("Boards of Canada".to_string(), "Roygbiv".to_string()))
+        );
+        assert_eq!(
+            parse_title("A - B - C"),
+            Some(("A".to_string(), "B - C".to_string()))
+        );
+        assert_eq!(parse_title("Het nieuws"), None);
+        assert_eq!(parse_title(" - Untitled"), None);
+    }
+
+    #[test]
+    fn test_lastfm_signature() {
+        let mut params = BTreeMap::new();
+        params.insert("method".to_string(), "track.scrobble".to_string());
+        params.insert("api_key".to_string(), "key".to_string());
+        params.insert("format".to_string(), "json".to_string());
+        // md5("api_keykeymethodtrack.scrobblesecret")
+        assert_eq!(
+            lastfm_signature(&params, "secret"),
+            format!("{:x}", md5::compute("api_keykeymethodtrack.scrobblesecret"))
+        );
+    }
+
+    fn scrobbler(url: String, name: &str) -> Scrobbler {
+        let dir = test_dir(&format!("scrobble-{}", name));
+        let cfg = ScrobbleCfg {
+            listenbrainz: Some(ListenBrainzCfg {
+                url,
+                token: "token".to_string(),
+            }),
+            lastfm: None,
+            min_seconds: 0,
+        };
+        Scrobbler::new(cfg, dir.join("scrobble_queue.json"))
+    }
+
+    #[test]
+    fn test_listenbrainz_submission() {
+        let (url, server) = http_stand_in(&["200 OK"; 2]);
+        let mut scrobbler = scrobbler(url, "ok");
+        scrobbler.start("Artist".to_string(), "Track".to_string());
+        scrobbler.finish();
+
+        let bodies: Vec<Value> = server
+            .join()
+            .unwrap()
+            .iter()
+            .map(|body| serde_json::from_slice(body).unwrap())
+            .collect();
+        assert_eq!(bodies[0]["listen_type"], "playing_now");
+        assert_eq!(bodies[1]["listen_type"], "single");
+        assert_eq!(
+            bodies[1]["payload"][0]["track_metadata"]["track_name"],
+            "Track"
+        );
+        assert!(scrobbler.queue.listens.is_empty());
+    }
+
+    #[test]
+    fn test_offline_queue() {
+        let (url, server) = http_stand_in(&["503 Service Unavailable"; 2]);
+        let mut scrobbler = scrobbler(url, "offline");
+        scrobbler.start("Artist".to_string(), "Track".to_string());
+        scrobbler.finish();
+        server.join().unwrap();
+
+        let queue_path = scrobbler.queue_path.clone();
+        let stored = config::load_unversioned::<Queue>(&queue_path, 0)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.listens["listenbrainz"].len(), 1);
+        assert_eq!(stored.listens["listenbrainz"][0].track, "Track");
+    }
+}
@@ -0,0 +1,70 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread::{self, JoinHandle};
+
+use crate::feed::{self, Feed};
+
+/**
+ * Empty directory below the system temporary directory, unique to the test
+ * `name` and this process.
+ */
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("radio-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/**
+ * Parses a feed from the `fixtures` directory.
+ */
+pub fn feed_fixture(name: &str) -> Feed {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(name);
+    feed::parse_file(&path).unwrap()
+}
+
+/**
+ * Local stand-in for an HTTP server which answers one request after the
+ * other with the `statuses` and returns the request bodies.
+ */
+pub fn http_stand_in(statuses: &[&str]) -> (String, JoinHandle<Vec<Vec<u8>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let statuses: Vec<String> = statuses.iter().map(|status| status.to_string()).collect();
+    let server = thread::spawn(move || {
+        let mut bodies = Vec::new();
+        for status in statuses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                let lower = line.to_lowercase();
+                if lower.starts_with("content-length:") {
+                    length = lower[15..].trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            bodies.push(body);
+
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}",
+                status
+            );
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        }
+        bodies
+    });
+    (url, server)
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::events::PlayerEvent;
+use crate::player::{lock, Player, Stream};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEvent {
+    Title,
+    Station,
+    Test,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Webhook {
+    pub url: String,
+    /**
+     * Events this webhook is called for, all events if empty.
+     */
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+impl Webhook {
+    fn wants(&self, event: WebhookEvent) -> bool {
+        event == WebhookEvent::Test || self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+enum Job {
+    Event(PlayerEvent),
+    Test,
+}
+
+/**
+ * Handle to the worker delivering webhooks. Deliveries happen on a worker
+ * thread per endpoint so neither the MPV event thread nor an HTTP worker ever
+ * waits for a slow webhook receiver.
+ */
+#[derive(Clone)]
+pub struct Webhooks {
+    tx: Sender<Job>,
+}
+
+fn payload(event: WebhookEvent, station: Option<&Stream>, title: &str) -> Value {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    json!({
+        "event": event,
+        "station": station,
+        "title": title,
+        "timestamp": timestamp,
+    })
+}
+
+fn deliver(agent: &ureq::Agent, url: &str, payload: &Value) {
+    for attempt in 1..=ATTEMPTS {
+        match agent.post(url).send_json(payload) {
+            Ok(_) => return,
+            Err(err) => {
+                eprintln!(
+                    "W: Webhook {} failed (attempt {}/{}): {}",
+                    url, attempt, ATTEMPTS, err
+                );
+                if attempt < ATTEMPTS {
+                    thread::sleep(RETRY_DELAY * attempt);
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Starts a worker delivering to a single endpoint, so retries against a
+ * receiver that is down do not hold up the other webhooks.
+ */
+fn spawn_endpoint(agent: ureq::Agent, url: String) -> Sender<Value> {
+    let (tx, rx) = channel::<Value>();
+    thread::spawn(move || {
+        for payload in rx {
+            deliver(&agent, &url, &payload);
+        }
+    });
+    tx
+}
+
+fn run(player: Arc<Mutex<Player>>, rx: Receiver<Job>) {
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    let mut endpoints: HashMap<String, Sender<Value>> = HashMap::new();
+
+    // The payload describes the state at the time of the event, which may
+    // have changed again by the time the worker gets to it.
+    let (mut station, mut title) = {
+        let player = lock(&player);
+        (player.get_current().cloned(), player.get_now_playing())
+    };
+
+    for job in rx {
+        let event = match job {
+            Job::Event(PlayerEvent::TitleChanged(changed)) => {
+                title = changed;
+                WebhookEvent::Title
+            }
+            Job::Event(PlayerEvent::StreamChanged(changed)) => {
+                station = changed;
+                title = String::new();
+                WebhookEvent::Station
+            }
+            Job::Event(_) => continue,
+            Job::Test => WebhookEvent::Test,
+        };
+
+        let webhooks = lock(&player).cfg.webhooks.clone();
+        endpoints.retain(|url, _| webhooks.iter().any(|hook| &hook.url == url));
+
+        let payload = payload(event, station.as_ref(), &title);
+        for hook in webhooks.into_iter().filter(|hook| hook.wants(event)) {
+            let tx = endpoints
+                .entry(hook.url.clone())
+                .or_insert_with(|| spawn_endpoint(agent.clone(), hook.url));
+            let _ = tx.send(payload.clone());
+        }
+    }
+}
+
+impl Webhooks {
+    /**
+     * Starts the delivery worker, webhooks are read from the player
+     * configuration whenever an event is delivered.
+     */
+    pub fn spawn(player: Arc<Mutex<Player>>) -> Self {
+        let (tx, rx) = channel();
+
+        let event_tx = tx.clone();
+        lock(&player)
+            .listeners()
+            .add(move |event| event_tx.send(Job::Event(event.clone())).is_ok());
+
+        thread::spawn(move || run(player, rx));
+        Webhooks { tx }
+    }
+
+    /**
+     * Sends a test event to every configured webhook.
+     */
+    pub fn send_test(&self) -> bool {
+        self.tx.send(Job::Test).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::testutil::http_stand_in;
+
+    #[test]
+    fn test_filters() {
+        let hook = Webhook {
+            url: String::new(),
+            events: vec![WebhookEvent::Title],
+        };
+        assert!(hook.wants(WebhookEvent::Title));
+        assert!(!hook.wants(WebhookEvent::Station));
+        assert!(hook.wants(WebhookEvent::Test));
+
+        let hook = Webhook {
+            url: String::new(),
+            events: Vec::new(),
+        };
+        assert!(hook.wants(WebhookEvent::Station));
+    }
+
+    /**
+     * Local stand-in receiver that fails the first request and accepts the
+     * second one.
+     */
+    #[test]
+    fn test_deliver_retries() {
+        let (url, server) = http_stand_in(&["500 Internal Server Error", "200 OK"]);
+        let url = format!("{}/hook", url);
+
+        let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+        let stream = Stream::new(3, "Arrow".to_string(), "http://example.org".to_string());
+        let sent = payload(WebhookEvent::Station, Some(&stream), "Song");
+        deliver(&agent, &url, &sent);
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 2);
+        let received: Value = serde_json::from_slice(&bodies[1]).unwrap();
+        assert_eq!(received["event"], "station");
+        assert_eq!(received["station"]["id"], 3);
+        assert_eq!(received["title"], "Song");
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::events::PlayerEvent;
+use crate::player::{self, lock, PlaybackState, Player, Stream};
+
+/**
+ * Name under which the player configured in `radio.json` is addressed.
+ */
+pub const DEFAULT_ZONE: &str = "default";
+
+/**
+ * A zone declared in `zones.json`, its playback state is kept in
+ * `zones/<name>.json`.
+ */
+#[derive(Deserialize, Serialize, Default)]
+struct ZoneCfg {
+    #[serde(default)]
+    audio_device: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct ZonesFile {
+    #[serde(default)]
+    zones: BTreeMap<String, ZoneCfg>,
+}
+
+#[derive(Serialize)]
+pub struct ZoneInfo {
+    pub name: String,
+    pub state: PlaybackState,
+    pub current: Option<Stream>,
+    pub now_playing: String,
+    pub volume: u8,
+}
+
+/**
+ * Independent players in one process, each with its own MPV context, audio
+ * device, current stream and volume. All of them play from the station
+ * library of the default zone.
+ */
+#[derive(Clone)]
+pub struct Zones {
+    default: Arc<Mutex<Player>>,
+    zones: Arc<BTreeMap<String, Arc<Mutex<Player>>>>,
+}
+
+/**
+ * Zone names end up in file names and URLs.
+ */
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != DEFAULT_ZONE
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+impl Zones {
+    /**
+     * Starts a player for every zone in `zones.json`, a missing file means
+     * there is only the default zone.
+     */
+    pub fn load(default: Arc<Mutex<Player>>, cfg_path: &Path) -> Result<Self, String> {
+        let file = config::load_unversioned::<ZonesFile>(
+            &cfg_path.join("zones.json"),
+            config::BACKUP_COUNT,
+        )
+        .map_err(|err| err.to_string())?
+        .unwrap_or_default();
+        let streams = lock(&default).get_playlist().to_vec();
+        let dir = cfg_path.join("zones");
+
+        let mut zones = BTreeMap::new();
+        for (name, zone) in file.zones {
+            if !valid_name(&name) {
+                return Err(format!("Invalid zone name {}", name));
+            }
+            fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+            let mut player = Player::from_file(&dir.join(format!("{}.json", name)))
+                .map_err(|err| format!("Zone {}: {}", name, err))?;
+            player
+                .set_library(streams.clone())
+                .map_err(|err| format!("Zone {}: {}", name, err))?;
+            if zone.audio_device.is_some() && player.cfg.audio_device != zone.audio_device {
+                if let Err(err) = player.set_audio_device(zone.audio_device) {
+                    eprintln!("W: Zone {}: {}", name, err);
+                }
+            }
+
+            let player = Arc::new(Mutex::new(player));
+            player::spawn_watchdog(player.clone(), Duration::from_secs(5));
+            zones.insert(name, player);
+        }
+
+        Ok(Zones {
+            default,
+            zones: Arc::new(zones),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Mutex<Player>>> {
+        if name == DEFAULT_ZONE {
+            Some(&self.default)
+        } else {
+            self.zones.get(name)
+        }
+    }
+
+    pub fn info(&self) -> Vec<ZoneInfo> {
+        std::iter::once((DEFAULT_ZONE, &self.default))
+            .chain(
+                self.zones
+                    .iter()
+                    .map(|(name, player)| (name.as_str(), player)),
+            )
+            .map(|(name, player)| {
+                let guard = lock(player);
+                ZoneInfo {
+                    name: name.to_string(),
+                    state: guard.get_state(),
+                    current: guard.get_current().cloned(),
+                    now_playing: guard.get_now_playing(),
+                    volume: guard.get_volume(),
+                }
+            })
+            .collect()
+    }
+
+    /**
+     * Copies the station library of the default zone into the other zones
+     * whenever it changes.
+     */
+    pub fn spawn_sync(&self) {
+        if self.zones.is_empty() {
+            return;
+        }
+
+        let (tx, rx) = channel();
+        lock(&self.default)
+            .listeners()
+            .add(move |event| match event {
+                PlayerEvent::PlaylistChanged => tx.send(()).is_ok(),
+                _ => true,
+            });
+
+        let zones = self.clone();
+        thread::spawn(move || {
+            while rx.recv().is_ok() {
+                let streams = lock(&zones.default).get_playlist().to_vec();
+                for (name, player) in zones.zones.iter() {
+                    if let Err(err) = lock(player).set_library(streams.clone()) {
+                        eprintln!("W: Zone {}: {}", name, err);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("kitchen"));
+        assert!(valid_name("living_room-2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name(DEFAULT_ZONE));
+        assert!(!valid_name("../radio"));
+        assert!(!valid_name("living room"));
+    }
+}
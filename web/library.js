@@ -0,0 +1,133 @@
+(function() {
+	"use strict";
+
+	const form = document.getElementById("form");
+	const inputQuery = document.getElementById("query");
+	const directory = document.getElementById("directory");
+	const trackList = document.getElementById("track_list");
+
+	function trackName(track) {
+		if (track.title === null) {
+			return track.path.split("/").pop();
+		}
+		if (track.artist === null) {
+			return track.title;
+		}
+		return track.artist + " - " + track.title;
+	}
+
+	function play(path) {
+		api.submit(
+			"PUT",
+			"/library/play",
+			{ path: path, append: false },
+			function(xhr, statusCode, response) {
+				if (statusCode === 200) {
+					window.location = "index.html";
+				}
+			}
+		);
+	}
+
+	function createItem(text, onclick) {
+		const li = document.createElement("li");
+		const a = document.createElement("a");
+		const span = document.createElement("span");
+
+		span.innerText = text;
+		span.className = "title";
+
+		a.appendChild(span);
+		li.appendChild(a);
+
+		a.href = "#";
+		a.onclick = function(ev) {
+			ev.preventDefault();
+			onclick();
+			return false;
+		};
+
+		trackList.appendChild(li);
+	}
+
+	function clear() {
+		while (trackList.firstChild) {
+			trackList.removeChild(trackList.firstChild);
+		}
+	}
+
+	function showTracks(tracks) {
+		for (let i = 0; i < tracks.length; ++i) {
+			createItem(trackName(tracks[i]), function() {
+				play(tracks[i].path);
+			});
+		}
+	}
+
+	function browse(path) {
+		api.request(
+			"GET",
+			"/library?path=" + encodeURIComponent(path),
+			function(xhr, statusCode, payload) {
+				if (statusCode !== 200) {
+					return;
+				}
+				const json = JSON.parse(payload);
+				clear();
+				directory.innerText = path === "" ? "Mappen" : path.split("/").pop();
+
+				if (path !== "") {
+					const parent = path.split("/").slice(0, -1).join("/");
+					createItem("..", function() {
+						browse(parent);
+					});
+					createItem("▶ Speel alles", function() {
+						play(path);
+					});
+				}
+				for (let i = 0; i < json.dirs.length; ++i) {
+					createItem(json.dirs[i].split("/").pop() + "/", function() {
+						browse(json.dirs[i]);
+					});
+				}
+				showTracks(json.tracks);
+			}
+		);
+	}
+
+	function formSubmitted(ev) {
+		ev.preventDefault();
+
+		const query = inputQuery.value;
+		if (query === "") {
+			browse("");
+			return false;
+		}
+
+		api.request(
+			"GET",
+			"/library/search?q=" + encodeURIComponent(query),
+			function(xhr, statusCode, payload) {
+				if (statusCode === 200) {
+					clear();
+					directory.innerText = query;
+					showTracks(JSON.parse(payload));
+				}
+			}
+		);
+
+		return false;
+	}
+
+	document.addEventListener(
+		"DOMContentLoaded",
+		function() {
+			form.addEventListener(
+				"submit",
+				formSubmitted
+			);
+			browse("");
+		}
+	);
+
+})();
@@ -0,0 +1,121 @@
+(function() {
+	"use strict";
+
+	const form = document.getElementById("form");
+	const inputUrl = document.getElementById("url");
+	const heading = document.getElementById("podcast");
+	const podcastList = document.getElementById("podcast_list");
+
+	function createItem(text, onclick) {
+		const li = document.createElement("li");
+		const a = document.createElement("a");
+		const span = document.createElement("span");
+
+		span.innerText = text;
+		span.className = "title";
+
+		a.appendChild(span);
+		li.appendChild(a);
+
+		a.href = "#";
+		a.onclick = function(ev) {
+			ev.preventDefault();
+			onclick();
+			return false;
+		};
+
+		podcastList.appendChild(li);
+	}
+
+	function clear() {
+		while (podcastList.firstChild) {
+			podcastList.removeChild(podcastList.firstChild);
+		}
+	}
+
+	function playEpisode(podcast, episode) {
+		api.request(
+			"PUT",
+			"/podcasts/" + podcast.id + "/episodes/" + episode.id,
+			function(xhr, statusCode, payload) {
+				if (statusCode === 200) {
+					window.location = "index.html";
+				}
+			}
+		);
+	}
+
+	function showPodcast(id) {
+		api.request(
+			"GET",
+			"/podcasts/" + id,
+			function(xhr, statusCode, payload) {
+				if (statusCode !== 200) {
+					return;
+				}
+				const podcast = JSON.parse(payload);
+				clear();
+				heading.innerText = podcast.title;
+
+				createItem("..", showPodcasts);
+				for (let i = 0; i < podcast.episodes.length; ++i) {
+					const episode = podcast.episodes[i];
+					const mark = episode.played ? "✓ " : (episode.position > 0 ? "▶ " : "");
+					createItem(mark + episode.title, function() {
+						playEpisode(podcast, episode);
+					});
+				}
+			}
+		);
+	}
+
+	function showPodcasts() {
+		api.request(
+			"GET",
+			"/podcasts",
+			function(xhr, statusCode, payload) {
+				if (statusCode !== 200) {
+					return;
+				}
+				const podcasts = JSON.parse(payload);
+				clear();
+				heading.innerText = "Abonnementen";
+
+				for (let i = 0; i < podcasts.length; ++i) {
+					const podcast = podcasts[i];
+					createItem(podcast.title + " (" + podcast.unplayed + ")", function() {
+						showPodcast(podcast.id);
+					});
+				}
+			}
+		);
+	}
+
+	function dataSubmitted(xhr, statusCode, response) {
+		if (statusCode === 202) {
+			inputUrl.value = "";
+			showPodcasts();
+		}
+	}
+
+	function formSubmitted(ev) {
+		ev.preventDefault();
+
+		const dataObj = { url: inputUrl.value, auto_download: false };
+		api.submit("POST", "/podcasts", dataObj, dataSubmitted);
+
+		return false;
+	}
+
+	document.addEventListener(
+		"DOMContentLoaded",
+		function() {
+			form.addEventListener(
+				"submit",
+				formSubmitted
+			);
+			showPodcasts();
+		}
+	);
+
+})();